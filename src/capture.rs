@@ -0,0 +1,490 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Byte-level capture of a session
+//!
+//! [`TracePort`] wraps a serial port and records every byte read and
+//! written, with the time since the capture started and the state the
+//! state machine was in. The capture is written either as text, one hex
+//! and ASCII line per 16 bytes, or in a compact binary format that
+//! [`read_trace`] loads back and [`ReplayPort`] plays back.
+//!
+//! The binary format is the magic `FLTRACE1` followed by one record per
+//! read, write or timeout:
+//!
+//! | Field | Size |
+//! |-------|------|
+//! | microseconds since the capture started | u64, little endian |
+//! | kind: 0 read, 1 write, 2 timeout | u8 |
+//! | length of the state name | u8 |
+//! | state name | UTF-8 |
+//! | length of the data | u32, little endian |
+//! | data | bytes |
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::serial::SerialPort;
+
+/// First bytes of a binary capture
+const MAGIC: &[u8; 8] = b"FLTRACE1";
+
+/// Bytes shown on each line of a text capture
+const LINE_BYTES: usize = 16;
+
+// ============================================================================
+// Records
+// ============================================================================
+
+/// How a capture is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// Hex and ASCII lines for reading
+    #[default]
+    Text,
+    /// Records for loading back with [`read_trace`]
+    Binary,
+}
+
+/// What happened on the line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    /// Bytes read from the port
+    Read,
+    /// Bytes written to the port
+    Write,
+    /// A read that timed out with nothing received
+    Timeout,
+}
+
+/// One read, write or timeout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Time since the capture started
+    pub at: Duration,
+    /// State of the state machine, e.g. `SendChecksum`
+    pub state: String,
+    pub kind: TraceKind,
+    /// The bytes read or written; empty for a timeout
+    pub data: Vec<u8>,
+}
+
+impl TraceRecord {
+    /// Render as text, one line per 16 bytes
+    pub fn to_text(&self) -> String {
+        let secs = self.at.as_secs_f64();
+        let direction = match self.kind {
+            TraceKind::Write => "TX",
+            TraceKind::Read | TraceKind::Timeout => "RX",
+        };
+        if self.kind == TraceKind::Timeout {
+            return format!("{:>12.6}  {:<18}  {}  (timeout)\n", secs, self.state, direction);
+        }
+
+        let mut out = String::new();
+        for chunk in self.data.chunks(LINE_BYTES) {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            out.push_str(&format!(
+                "{:>12.6}  {:<18}  {}  {:<width$}  |{}|\n",
+                secs,
+                self.state,
+                direction,
+                hex.join(" "),
+                ascii,
+                width = LINE_BYTES * 3 - 1
+            ));
+        }
+        out
+    }
+
+    /// Encode as a binary record
+    pub fn to_binary(&self) -> Vec<u8> {
+        let state = &self.state.as_bytes()[..self.state.len().min(u8::MAX as usize)];
+        let mut out = Vec::with_capacity(14 + state.len() + self.data.len());
+        out.extend_from_slice(&(self.at.as_micros() as u64).to_le_bytes());
+        out.push(match self.kind {
+            TraceKind::Read => 0,
+            TraceKind::Write => 1,
+            TraceKind::Timeout => 2,
+        });
+        out.push(state.len() as u8);
+        out.extend_from_slice(state);
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+        out
+    }
+}
+
+/// Load a binary capture
+pub fn read_trace(mut reader: impl Read) -> std::io::Result<Vec<TraceRecord>> {
+    let invalid = |message: &str| std::io::Error::new(ErrorKind::InvalidData, message.to_string());
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a binary filink trace"));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut micros = [0u8; 8];
+        match reader.read_exact(&mut micros) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        }
+
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header)?;
+        let kind = match header[0] {
+            0 => TraceKind::Read,
+            1 => TraceKind::Write,
+            2 => TraceKind::Timeout,
+            _ => return Err(invalid("unknown trace record kind")),
+        };
+
+        let mut state = vec![0u8; header[1] as usize];
+        reader.read_exact(&mut state)?;
+        let state = String::from_utf8(state).map_err(|_| invalid("state name is not UTF-8"))?;
+
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut data)?;
+
+        records.push(TraceRecord { at: Duration::from_micros(u64::from_le_bytes(micros)), state, kind, data });
+    }
+}
+
+// ============================================================================
+// Tracing Port
+// ============================================================================
+
+/// Serial port wrapper that records all traffic to a writer
+///
+/// Each record is written as soon as it happens, so the capture is complete
+/// up to the moment a session fails or the process exits.
+pub struct TracePort<P> {
+    inner: P,
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    started: Instant,
+    state: &'static str,
+}
+
+impl<P: SerialPort> TracePort<P> {
+    /// Start a capture. A binary capture begins with its magic bytes.
+    pub fn new(inner: P, out: impl Write + Send + 'static, format: TraceFormat) -> std::io::Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC)?;
+        }
+        Ok(TracePort { inner, out, format, started: Instant::now(), state: "" })
+    }
+
+    fn record(&mut self, kind: TraceKind, data: &[u8]) -> std::io::Result<()> {
+        let record = TraceRecord {
+            at: self.started.elapsed(),
+            state: self.state.to_string(),
+            kind,
+            data: data.to_vec(),
+        };
+        match self.format {
+            TraceFormat::Text => self.out.write_all(record.to_text().as_bytes())?,
+            TraceFormat::Binary => self.out.write_all(&record.to_binary())?,
+        }
+        self.out.flush()
+    }
+}
+
+impl<P: SerialPort> SerialPort for TracePort<P> {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.record(TraceKind::Write, buf)?;
+        self.inner.write_all(buf)
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+        match self.inner.read_timeout(buf, timeout) {
+            Ok(0) => {
+                self.record(TraceKind::Timeout, &[])?;
+                Ok(0)
+            }
+            Ok(n) => {
+                self.record(TraceKind::Read, &buf[..n])?;
+                Ok(n)
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                self.record(TraceKind::Timeout, &[])?;
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn enter_state(&mut self, state: &'static str) {
+        self.state = state;
+        self.inner.enter_state(state);
+    }
+}
+
+// ============================================================================
+// Replay Port
+// ============================================================================
+
+/// Serial port that plays back a binary capture, for regression tests
+///
+/// Reads return the bytes the other side sent in the recording, and a
+/// recorded timeout makes the read time out. Every write must be the next
+/// thing this side wrote in the recording; anything else fails with
+/// `InvalidData`, naming the recorded state.
+///
+/// Clones share the playback position, so a test can hand one to the
+/// session and call [`ReplayPort::finish`] on the other once it is over.
+#[derive(Clone)]
+pub struct ReplayPort(Arc<Mutex<Replay>>);
+
+struct Replay {
+    records: Vec<TraceRecord>,
+    /// Next record to play
+    next: usize,
+    /// Bytes of the next record already played
+    offset: usize,
+}
+
+impl ReplayPort {
+    pub fn new(mut records: Vec<TraceRecord>) -> Self {
+        records.retain(|r| r.kind == TraceKind::Timeout || !r.data.is_empty());
+        ReplayPort(Arc::new(Mutex::new(Replay { records, next: 0, offset: 0 })))
+    }
+
+    /// Load a binary capture from a file
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(ReplayPort::new(read_trace(std::io::BufReader::new(file))?))
+    }
+
+    /// Check that the whole recording was played
+    ///
+    /// Fails with `InvalidData` if the session ended before the recording
+    /// did, naming the first record left over.
+    pub fn finish(self) -> std::io::Result<()> {
+        let replay = self.lock();
+        if replay.next < replay.records.len() {
+            let message = format!(
+                "replay unfinished: {} of {} records unplayed, starting with {}",
+                replay.records.len() - replay.next,
+                replay.records.len(),
+                replay.expected()
+            );
+            return Err(std::io::Error::new(ErrorKind::InvalidData, message));
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Replay> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Replay {
+    fn advance(&mut self, played: usize) {
+        self.offset += played;
+        if self.offset >= self.records[self.next].data.len() {
+            self.next += 1;
+            self.offset = 0;
+        }
+    }
+
+    /// What the recording holds next, for error messages
+    fn expected(&self) -> String {
+        match self.records.get(self.next) {
+            None => "the end of the recording".to_string(),
+            Some(record) => {
+                let data = &record.data[self.offset..];
+                match record.kind {
+                    TraceKind::Write => format!("a write of {:02X?} in {}", data, record.state),
+                    TraceKind::Read => format!("a read of {:02X?} in {}", data, record.state),
+                    TraceKind::Timeout => format!("a timeout in {}", record.state),
+                }
+            }
+        }
+    }
+}
+
+fn diverged(message: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message)
+}
+
+impl SerialPort for ReplayPort {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let mut replay = self.lock();
+        for &byte in buf {
+            let matches = replay.records.get(replay.next).is_some_and(|record| {
+                record.kind == TraceKind::Write && record.data[replay.offset] == byte
+            });
+            if !matches {
+                return Err(diverged(format!("replay diverged: wrote {:02X}, but the recording has {}", byte, replay.expected())));
+            }
+            replay.advance(1);
+        }
+        Ok(())
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], _timeout: Duration) -> std::io::Result<usize> {
+        let mut replay = self.lock();
+        let Some(record) = replay.records.get(replay.next) else {
+            return Err(diverged("replay diverged: read past the end of the recording".to_string()));
+        };
+        match record.kind {
+            TraceKind::Read => {
+                let data = &record.data[replay.offset..];
+                let n = buf.len().min(data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                replay.advance(n);
+                Ok(n)
+            }
+            TraceKind::Timeout => {
+                replay.next += 1;
+                Err(std::io::Error::new(ErrorKind::TimedOut, "Recorded timeout"))
+            }
+            TraceKind::Write => {
+                Err(diverged(format!("replay diverged: read, but the recording has {}", replay.expected())))
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::Script;
+
+    /// Writer whose contents can be inspected after the port is dropped
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(format: TraceFormat) -> Vec<u8> {
+        let mut script = Script::default();
+        script.sent(b"\x05").reply(b"OK").quiet();
+
+        let out = Shared::default();
+        let mut port = TracePort::new(script.mock(), out.clone(), format).unwrap();
+        port.enter_state("InitialHandshake");
+        port.write_all(b"\x05").unwrap();
+        port.enter_state("WaitGood");
+        let mut buf = [0u8; 8];
+        assert_eq!(port.read_timeout(&mut buf, Duration::from_millis(10)).unwrap(), 2);
+        assert!(port.read_timeout(&mut buf, Duration::from_millis(10)).is_err());
+        drop(port);
+
+        out.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_trace_text() {
+        let text = String::from_utf8(capture(TraceFormat::Text)).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("  InitialHandshake    TX  05                                               |.|"));
+        assert!(lines[1].ends_with("  WaitGood            RX  4F 4B                                            |OK|"));
+        assert!(lines[2].ends_with("  WaitGood            RX  (timeout)"));
+    }
+
+    #[test]
+    fn test_trace_text_wraps() {
+        let record = TraceRecord { at: Duration::from_micros(1_500_000), state: "TransmitBlock".into(), kind: TraceKind::Write, data: vec![b'A'; 20] };
+        let text = record.to_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("    1.500000  TransmitBlock       TX  41 41"));
+        assert!(lines[1].ends_with("|AAAA|"));
+    }
+
+    #[test]
+    fn test_trace_binary_round_trip() {
+        let records = read_trace(&capture(TraceFormat::Binary)[..]).unwrap();
+        let summary: Vec<(&str, TraceKind, &[u8])> =
+            records.iter().map(|r| (r.state.as_str(), r.kind, &r.data[..])).collect();
+        assert_eq!(summary, vec![
+            ("InitialHandshake", TraceKind::Write, &b"\x05"[..]),
+            ("WaitGood", TraceKind::Read, &b"OK"[..]),
+            ("WaitGood", TraceKind::Timeout, &b""[..]),
+        ]);
+        assert!(records.windows(2).all(|w| w[0].at <= w[1].at));
+
+        assert!(read_trace(&b"NOTATRACE"[..]).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let records = read_trace(&capture(TraceFormat::Binary)[..]).unwrap();
+
+        // Played back in the same order, a read may take a recorded read
+        // in pieces
+        let mut port = ReplayPort::new(records.clone());
+        port.write_all(b"\x05").unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(port.read_timeout(&mut buf, Duration::ZERO).unwrap(), 1);
+        assert_eq!(&buf, b"O");
+        assert_eq!(port.read_timeout(&mut buf, Duration::ZERO).unwrap(), 1);
+        assert_eq!(&buf, b"K");
+        let err = port.read_timeout(&mut buf, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        port.finish().unwrap();
+
+        // A different write is caught
+        let mut port = ReplayPort::new(records.clone());
+        let err = port.write_all(b"\x04").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "replay diverged: wrote 04, but the recording has a write of [05] in InitialHandshake");
+
+        // So is reading when this side should be writing
+        let mut port = ReplayPort::new(records);
+        let err = port.read_timeout(&mut buf, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_replay_unfinished() {
+        let records = read_trace(&capture(TraceFormat::Binary)[..]).unwrap();
+        let port = ReplayPort::new(records);
+        let mut session = port.clone();
+        session.write_all(b"\x05").unwrap();
+        drop(session);
+        let err = port.finish().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "replay unfinished: 2 of 3 records unplayed, starting with a read of [4F, 4B] in WaitGood");
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Time as seen by the state machines
+//!
+//! The state machines read the time and pause between bytes through a
+//! [`Clock`], so tests can run them on a [`FakeClock`] that only moves
+//! when told to. Waiting for a reply is up to the serial port: a test port
+//! advances the fake clock by the timeout it was asked to wait.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of time for a session
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock that stands still until advanced. Sleeping advances it at once.
+///
+/// Clones share the same time, so a test can keep one to inspect while
+/// the session runs on another.
+#[derive(Debug, Clone)]
+pub struct FakeClock {
+    start: Instant,
+    time: Arc<Mutex<FakeTime>>,
+}
+
+#[derive(Debug, Default)]
+struct FakeTime {
+    elapsed: Duration,
+    slept: Duration,
+}
+
+impl FakeClock {
+    pub fn new() -> Self {
+        FakeClock { start: Instant::now(), time: Arc::default() }
+    }
+
+    /// Move the clock forward, as if `duration` had passed
+    pub fn advance(&self, duration: Duration) {
+        self.lock().elapsed += duration;
+    }
+
+    /// Time passed since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    /// Part of `elapsed` spent in `sleep`
+    pub fn slept(&self) -> Duration {
+        self.lock().slept
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FakeTime> {
+        self.time.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        FakeClock::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut time = self.lock();
+        time.elapsed += duration;
+        time.slept += duration;
+    }
+}
+
+/// The clock a state machine runs on, shared with its builder
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: impl Clock + 'static) -> Self {
+        SharedClock(Arc::new(clock))
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration)
+    }
+
+    /// Time passed since `since`
+    pub fn since(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedClock")
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_clock() {
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let shared = SharedClock::new(clock.clone());
+
+        clock.advance(Duration::from_secs(5));
+        shared.sleep(Duration::from_millis(250));
+
+        assert_eq!(shared.since(start), Duration::from_millis(5250));
+        assert_eq!(clock.elapsed(), Duration::from_millis(5250));
+        assert_eq!(clock.slept(), Duration::from_millis(250));
+    }
+}
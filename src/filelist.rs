@@ -0,0 +1,324 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Expansion and validation of the file arguments given to `send`
+//!
+//! Each argument may be a plain path, a glob pattern, a directory (only
+//! with `--recursive`) or `@LISTFILE`, naming a text file with one argument
+//! per line. Every resulting file is checked before the session starts so a
+//! bad entry cannot abort a batch halfway through.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use glob::Pattern;
+
+// ============================================================================
+// Options
+// ============================================================================
+
+/// Controls how `send` arguments are expanded into a list of files
+#[derive(Default)]
+pub struct FileListOptions {
+    /// Descend into directories given on the command line
+    pub recursive: bool,
+    /// Only keep files whose name matches one of these patterns (if any)
+    pub include: Vec<String>,
+    /// Drop files whose name matches any of these patterns
+    pub exclude: Vec<String>,
+}
+
+// ============================================================================
+// Collection
+// ============================================================================
+
+/// Expand `args` into a validated, de-duplicated list of readable files.
+///
+/// All problems are collected and returned together, so the user can fix
+/// every bad entry in one go.
+pub fn collect_files(args: &[String], options: &FileListOptions) -> Result<Vec<PathBuf>, Vec<String>> {
+    let mut errors = Vec::new();
+
+    let include = compile_patterns(&options.include, &mut errors);
+    let exclude = compile_patterns(&options.exclude, &mut errors);
+
+    let mut candidates = Vec::new();
+    for arg in args {
+        if let Some(list) = arg.strip_prefix('@') {
+            match std::fs::read_to_string(list) {
+                Ok(contents) => {
+                    for line in contents.lines() {
+                        let line = line.trim();
+                        if line.is_empty() || line.starts_with('#') {
+                            continue;
+                        }
+                        expand_arg(line, options.recursive, &mut candidates, &mut errors);
+                    }
+                }
+                Err(e) => errors.push(format!("{}: cannot read list file: {}", list, e)),
+            }
+        } else {
+            expand_arg(arg, options.recursive, &mut candidates, &mut errors);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for path in candidates {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if !include.is_empty() && !include.iter().any(|p| p.matches(name)) {
+            continue;
+        }
+        if exclude.iter().any(|p| p.matches(name)) {
+            continue;
+        }
+
+        if let Err(e) = File::open(&path) {
+            errors.push(format!("{}: cannot open: {}", path.display(), e));
+            continue;
+        }
+
+        let key = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if seen.insert(key) {
+            files.push(path);
+        }
+    }
+
+    if errors.is_empty() && files.is_empty() {
+        errors.push("No files to send".to_string());
+    }
+
+    if errors.is_empty() { Ok(files) } else { Err(errors) }
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+fn compile_patterns(patterns: &[String], errors: &mut Vec<String>) -> Vec<Pattern> {
+    patterns
+        .iter()
+        .filter_map(|p| match Pattern::new(p) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                errors.push(format!("{}: invalid pattern: {}", p, e));
+                None
+            }
+        })
+        .collect()
+}
+
+fn is_glob(arg: &str) -> bool {
+    arg.contains(['*', '?', '['])
+}
+
+fn expand_arg(arg: &str, recursive: bool, out: &mut Vec<PathBuf>, errors: &mut Vec<String>) {
+    let path = Path::new(arg);
+
+    // A literal path wins over a glob interpretation, so files with '[' or
+    // '?' in their names can still be sent
+    if path.exists() || !is_glob(arg) {
+        add_path(path, arg, recursive, out, errors);
+        return;
+    }
+
+    match glob::glob(arg) {
+        Ok(paths) => {
+            let mut matched = false;
+            for entry in paths {
+                match entry {
+                    Ok(p) => {
+                        matched = true;
+                        add_path(&p, &p.display().to_string(), recursive, out, errors);
+                    }
+                    Err(e) => errors.push(format!("{}: {}", e.path().display(), e.error())),
+                }
+            }
+            if !matched {
+                errors.push(format!("{}: no files match pattern", arg));
+            }
+        }
+        Err(e) => errors.push(format!("{}: invalid pattern: {}", arg, e)),
+    }
+}
+
+fn add_path(path: &Path, display: &str, recursive: bool, out: &mut Vec<PathBuf>, errors: &mut Vec<String>) {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_file() => out.push(path.to_path_buf()),
+        Ok(meta) if meta.is_dir() => {
+            if recursive {
+                walk_dir(path, out, errors);
+            } else {
+                errors.push(format!("{}: is a directory (use --recursive)", display));
+            }
+        }
+        Ok(_) => errors.push(format!("{}: not a regular file", display)),
+        Err(e) => errors.push(format!("{}: {}", display, e)),
+    }
+}
+
+fn walk_dir(dir: &Path, out: &mut Vec<PathBuf>, errors: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(format!("{}: {}", dir.display(), e));
+            return;
+        }
+    };
+
+    // Sort so the transfer order doesn't depend on the filesystem
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    paths.sort();
+
+    // Links to directories aren't followed, so a link to an ancestor can't
+    // send the walk round in circles. Links to files are sent.
+    for path in paths {
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => walk_dir(&path, out, errors),
+            Ok(meta) if meta.is_file() => out.push(path),
+            Ok(meta) if meta.file_type().is_symlink() && path.is_file() => out.push(path),
+            Ok(_) => {}
+            Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        std::fs::remove_dir_all(&dir).ok();
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, file.as_bytes()).unwrap();
+        }
+        dir
+    }
+
+    fn arg(path: &Path) -> String {
+        path.display().to_string()
+    }
+
+    #[test]
+    fn test_collect_plain_and_glob() {
+        let dir = setup("filelist_glob", &["a.txt", "b.txt", "c.com"]);
+
+        let args = vec![arg(&dir.join("c.com")), arg(&dir.join("*.txt"))];
+        let files = collect_files(&args, &FileListOptions::default()).unwrap();
+        assert_eq!(files, vec![dir.join("c.com"), dir.join("a.txt"), dir.join("b.txt")]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_collect_directory_requires_recursive() {
+        let dir = setup("filelist_dir", &["a.txt", "sub/b.txt", "sub/c.bak"]);
+
+        let args = vec![arg(&dir)];
+        let errors = collect_files(&args, &FileListOptions::default()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("--recursive"));
+
+        let options = FileListOptions {
+            recursive: true,
+            exclude: vec!["*.bak".to_string()],
+            ..Default::default()
+        };
+        let files = collect_files(&args, &options).unwrap();
+        assert_eq!(files, vec![dir.join("a.txt"), dir.join("sub").join("b.txt")]);
+
+        let options = FileListOptions {
+            recursive: true,
+            include: vec!["*.bak".to_string()],
+            ..Default::default()
+        };
+        let files = collect_files(&args, &options).unwrap();
+        assert_eq!(files, vec![dir.join("sub").join("c.bak")]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_directory_symlinks() {
+        let dir = setup("filelist_links", &["a.txt", "sub/b.txt"]);
+        let outside = setup("filelist_links_target", &["c.txt"]);
+        std::os::unix::fs::symlink(&dir, dir.join("sub").join("loop")).unwrap();
+        std::os::unix::fs::symlink(outside.join("c.txt"), dir.join("sub").join("link.txt")).unwrap();
+
+        let options = FileListOptions { recursive: true, ..Default::default() };
+        let files = collect_files(&[arg(&dir)], &options).unwrap();
+        assert_eq!(files, vec![dir.join("a.txt"), dir.join("sub").join("b.txt"), dir.join("sub").join("link.txt")]);
+
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::remove_dir_all(&outside).ok();
+    }
+
+    #[test]
+    fn test_collect_list_file() {
+        let dir = setup("filelist_list", &["a.txt", "b.txt"]);
+        let list = dir.join("files.lst");
+        let contents = format!("# comment\n\n{}\n{}\n", arg(&dir.join("b.txt")), arg(&dir.join("a.txt")));
+        std::fs::write(&list, contents).unwrap();
+
+        let args = vec![format!("@{}", arg(&list))];
+        let files = collect_files(&args, &FileListOptions::default()).unwrap();
+        assert_eq!(files, vec![dir.join("b.txt"), dir.join("a.txt")]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_collect_reports_all_errors() {
+        let dir = setup("filelist_errors", &["a.txt"]);
+
+        let args = vec![
+            arg(&dir.join("missing.txt")),
+            arg(&dir.join("a.txt")),
+            arg(&dir.join("*.none")),
+            format!("@{}", arg(&dir.join("missing.lst"))),
+        ];
+        let errors = collect_files(&args, &FileListOptions::default()).unwrap_err();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_collect_deduplicates() {
+        let dir = setup("filelist_dedup", &["a.txt"]);
+
+        let args = vec![arg(&dir.join("a.txt")), arg(&dir.join("*.txt"))];
+        let files = collect_files(&args, &FileListOptions::default()).unwrap();
+        assert_eq!(files, vec![dir.join("a.txt")]);
+
+        let options = FileListOptions {
+            exclude: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let errors = collect_files(&args, &options).unwrap_err();
+        assert_eq!(errors, vec!["No files to send".to_string()]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! JSON Lines output for scripts
+//!
+//! With `--output json` every session event is written to stdout as a JSON
+//! object on a line of its own, with an `event` field naming it, and
+//! human-readable text goes to stderr.
+
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use serde_json::{json, Value};
+use filink::{Event, FileReport, Observer, Pacing, SessionReport};
+
+// ============================================================================
+// Observer
+// ============================================================================
+
+/// Session observer that writes each event as a line of JSON
+pub struct JsonLines;
+
+impl Observer for JsonLines {
+    fn on_event(&mut self, event: &Event) {
+        if let Some(value) = event_json(event) {
+            emit(&value);
+        }
+    }
+}
+
+/// Write one object to stdout, flushed so a wrapper sees it at once
+pub fn emit(value: &Value) {
+    let mut out = std::io::stdout().lock();
+    // Nothing sensible can be done if the reader went away
+    let _ = writeln!(out, "{}", value);
+    let _ = out.flush();
+}
+
+/// The last line written when a session fails
+pub fn error_json(message: &str, state: Option<&str>, exit_code: i32) -> Value {
+    json!({ "event": "error", "message": message, "state": state, "exit_code": exit_code })
+}
+
+// ============================================================================
+// Event Mapping
+// ============================================================================
+
+fn path_json(path: &Path) -> Value {
+    Value::from(path.display().to_string())
+}
+
+/// Seconds, to the millisecond
+fn seconds_json(duration: Duration) -> Value {
+    Value::from(duration.as_millis() as f64 / 1000.0)
+}
+
+fn file_json(file: &FileReport) -> Value {
+    json!({
+        "name": file.name,
+        "path": path_json(&file.path),
+        "bytes": file.bytes,
+        "blocks": file.blocks,
+        "retries": file.retries,
+        "elapsed": seconds_json(file.elapsed),
+        "cps": file.cps().round() as u64,
+        "outcome": file.outcome.to_string(),
+    })
+}
+
+/// The whole session, as in the summary event and `--report` files
+pub fn report_json(report: &SessionReport) -> Value {
+    json!({
+        "files": report.files.iter().map(file_json).collect::<Vec<_>>(),
+        "rejected": report.rejected().map(|f| path_json(&f.path)).collect::<Vec<_>>(),
+        "bytes": report.bytes(),
+        "elapsed": seconds_json(report.elapsed),
+        "pacing": report.pacing.as_ref().map(pacing_json),
+    })
+}
+
+/// Delays in milliseconds, as `--byte-delay` takes them
+fn pacing_json(pacing: &Pacing) -> Value {
+    json!({
+        "byte_delay": pacing.byte_delay.as_millis() as u64,
+        "block_delay": pacing.block_delay.as_millis() as u64,
+    })
+}
+
+/// Events added to the library later than this program have no JSON form
+fn event_json(event: &Event) -> Option<Value> {
+    let value = match event {
+        Event::HandshakeAttempt { attempt } => json!({ "event": "handshake", "attempt": attempt }),
+        Event::HandshakeCompleted => json!({ "event": "connected" }),
+        Event::FileStarted { path, name, size } => {
+            json!({ "event": "file_start", "name": name, "path": path_json(path), "size": size })
+        }
+        Event::BlockSent { index, bytes } | Event::BlockReceived { index, bytes } => {
+            json!({ "event": "block", "index": index, "bytes": bytes })
+        }
+        Event::ChecksumRetry { index, attempt } => json!({ "event": "retry", "block": index, "attempt": attempt }),
+        Event::PacingChanged(pacing) => {
+            let mut value = pacing_json(pacing);
+            value["event"] = Value::from("pacing");
+            value
+        }
+        Event::FileCompleted(file) => {
+            let mut value = file_json(file);
+            value["event"] = Value::from("file_end");
+            value
+        }
+        Event::FileRejected { name, path, reason } => json!({
+            "event": "file_rejected",
+            "name": name,
+            "path": path.as_deref().map(path_json),
+            "reason": reason,
+        }),
+        Event::NameConflict { name, note } => json!({ "event": "name_conflict", "name": name, "note": note }),
+        Event::FileAbandoned { path, kept } => json!({
+            "event": "file_abandoned",
+            "path": path_json(path),
+            "kept": kept.as_deref().map(path_json),
+        }),
+        Event::SessionEnded(report) => {
+            let mut value = report_json(report);
+            value["event"] = Value::from("summary");
+            value
+        }
+        Event::Trace(message) => json!({ "event": "trace", "message": message }),
+        _ => return None,
+    };
+    Some(value)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use filink::Outcome;
+
+    #[test]
+    fn test_event_json() {
+        let file = FileReport::new(PathBuf::from("out/notes.txt"), "NOTES.TXT".into(), Outcome::Completed)
+            .with_bytes(300)
+            .with_blocks(3)
+            .with_retries(1)
+            .with_elapsed(Duration::from_millis(1500));
+        let rejected = FileReport::rejected(PathBuf::from("big.dat"), "BIG.DAT".into());
+
+        assert_eq!(
+            event_json(&Event::FileStarted { path: file.path.clone(), name: file.name.clone(), size: None }).unwrap().to_string(),
+            r#"{"event":"file_start","name":"NOTES.TXT","path":"out/notes.txt","size":null}"#
+        );
+        assert_eq!(
+            event_json(&Event::FileCompleted(file.clone())).unwrap().to_string(),
+            concat!(
+                r#"{"blocks":3,"bytes":300,"cps":200,"elapsed":1.5,"event":"file_end","name":"NOTES.TXT","#,
+                r#""outcome":"completed","path":"out/notes.txt","retries":1}"#
+            )
+        );
+
+        let pacing = Pacing { byte_delay: Duration::from_millis(3), block_delay: Duration::ZERO };
+        assert_eq!(
+            event_json(&Event::PacingChanged(pacing)).unwrap().to_string(),
+            r#"{"block_delay":0,"byte_delay":3,"event":"pacing"}"#
+        );
+
+        let mut report = SessionReport::default();
+        report.files = vec![file, rejected];
+        report.elapsed = Duration::from_secs(4);
+        let summary = event_json(&Event::SessionEnded(report)).unwrap();
+        assert_eq!(summary["event"], "summary");
+        assert_eq!(summary["bytes"], 300);
+        assert_eq!(summary["elapsed"], 4.0);
+        assert_eq!(summary["files"][0]["name"], "NOTES.TXT");
+        assert_eq!(summary["files"][1]["outcome"], "rejected");
+        assert_eq!(summary["rejected"][0], "big.dat");
+        assert_eq!(summary["pacing"], Value::Null);
+
+        assert_eq!(
+            error_json("Receiver not responding", Some("EndFilename"), 3).to_string(),
+            r#"{"event":"error","exit_code":3,"message":"Receiver not responding","state":"EndFilename"}"#
+        );
+    }
+}
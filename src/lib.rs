@@ -0,0 +1,48 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! FILINK file transfer protocol over serial lines
+//!
+//! A session is set up with [`Sender::builder`] or [`Receiver::builder`]
+//! on anything implementing [`serial::SerialPort`], and runs until the
+//! sender ends it. Both return a [`SessionReport`] listing the files that
+//! were transferred and the ones the receiver refused. Progress is
+//! reported to any [`Observer`] subscribed with the builder's `observer`.
+
+mod protocol;
+mod sender;
+mod receiver;
+pub mod serial;
+pub mod capture;
+pub mod noise;
+pub mod clock;
+mod output;
+mod text;
+mod retry;
+mod pacing;
+mod timeouts;
+mod session;
+mod observer;
+
+pub use sender::{assign_filenames, display_filename, Sender, SenderBuilder, SenderError};
+pub use receiver::{ConflictPolicy, Receiver, ReceiverBuilder, ReceiverError};
+pub use output::{TrimMode, TrimRules};
+pub use text::{ModeRules, TransferMode};
+pub use retry::RetryPolicy;
+pub use pacing::{AdaptivePacing, Pacing};
+pub use timeouts::Timeouts;
+pub use session::{FileReport, Outcome, SessionReport};
+pub use observer::{Event, Observer};
@@ -14,16 +14,44 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-// Filink protocol implementation
-mod protocol;
-mod sender;
-mod receiver;
-mod serial;
+// Command line interface to the filink library
+mod filelist;
+mod json;
+mod progress;
+mod report;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use serialport::{DataBits, Parity, StopBits};
-use std::path::PathBuf;
-use serial::RealSerialPort;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use filink::capture::{TraceFormat, TracePort};
+use filink::noise::{NoiseConfig, NoisyPort};
+use filink::serial::{RealSerialPort, SerialPort};
+use filink::{AdaptivePacing, ConflictPolicy, ModeRules, Receiver, ReceiverError, RetryPolicy, Sender, SenderError, Timeouts};
+use filink::{SessionReport, TransferMode, TrimMode, TrimRules};
+use filelist::FileListOptions;
+use json::JsonLines;
+use progress::{Progress, Stream};
+use report::ReportFormat;
+
+/// Exit status for bad arguments, missing files or a port that can't be opened
+const EXIT_USAGE: i32 = 1;
+/// Exit status when the serial port fails during the session
+const EXIT_IO: i32 = 2;
+/// Exit status when the other side stops responding
+const EXIT_TIMEOUT: i32 = 3;
+/// Exit status when the other side breaks the protocol
+const EXIT_PROTOCOL: i32 = 4;
+/// Exit status when the receiver refuses a file
+const EXIT_REJECTED: i32 = 5;
+/// Exit status when a local file can't be read or written
+const EXIT_LOCAL_FILE: i32 = 6;
+/// Exit status when a block keeps failing its checksum
+const EXIT_RETRIES: i32 = 7;
+/// Exit status when the session is interrupted with Ctrl-C
+const EXIT_CANCELLED: i32 = 130;
 
 #[derive(Parser)]
 #[command(name = "filink")]
@@ -54,29 +82,147 @@ struct Cli {
     #[arg(long, default_value = "0", value_name = "MS")]
     byte_delay: u8,
 
+    /// Tune the byte delay while sending, starting from --byte-delay. Optional
+    /// settings, e.g. '--adaptive-pacing=step=1,block=10,max=100,recover=8'
+    #[arg(long, value_name = "SPEC", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+    adaptive_pacing: Option<String>,
+
+    /// Give up after this many handshake attempts (default: keep trying)
+    #[arg(long, value_name = "N")]
+    handshake_attempts: Option<u32>,
+
+    /// Give up if the handshake hasn't completed after this many seconds
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+
+    /// Times one filename or block may be retried before giving up (default: keep trying)
+    #[arg(long, value_name = "N")]
+    max_retries: Option<u32>,
+
+    /// Times to resend the last control byte after a timeout before giving up
+    #[arg(long, default_value = "0", value_name = "N")]
+    timeout_retries: u32,
+
+    /// Wrong bytes tolerated while waiting for one reply before giving up
+    #[arg(long, default_value = "32", value_name = "N")]
+    max_stray: u32,
+
     /// Enable debug output
     #[arg(long)]
     debug: bool,
 
+    /// Output format: text, or json for one JSON object per event on stdout
+    #[arg(long, default_value = "text", value_name = "FORMAT")]
+    output: String,
+
+    /// Write the per-file summary to FILE as JSON or CSV, chosen by its extension
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Record every byte read and written, with timestamps and protocol state, to FILE
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Format of the --trace file: text (hex and ASCII) or binary
+    #[arg(long, default_value = "text", value_name = "FORMAT")]
+    trace_format: String,
+
+    /// How long to wait for the other side: 'auto' to suit the line settings, or
+    /// overrides in seconds, e.g. 'handshake=10,control=3,byte=1,checksum=20'
+    #[arg(long, default_value = "auto", value_name = "SPEC")]
+    timeouts: String,
+
+    /// Inject line faults, e.g. 'flip=0.001,drop=0.001,dup=0,latency=5,drain=960,buffer=16,seed=1'
+    #[arg(long, value_name = "SPEC")]
+    simulate_noise: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Send a file using the filink protocol
+    /// Send files using the filink protocol
     Send {
-        /// File to send
-        file: PathBuf,
+        /// Files, directories or glob patterns to send (@FILE reads paths from a list file)
+        #[arg(required = true, value_name = "PATH")]
+        files: Vec<String>,
+
+        /// Send the contents of directories, including subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Only send files whose name matches this glob (may be repeated)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Skip files whose name matches this glob (may be repeated)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Times to offer a file the receiver refuses before skipping it
+        #[arg(long, default_value = "3", value_name = "N")]
+        reject_attempts: u32,
+
+        #[command(flatten)]
+        modes: ModeArgs,
     },
     /// Receive files using the filink protocol
     Receive {
         /// Directory to save received files
         #[arg(short, long, default_value = ".")]
         output_dir: PathBuf,
+
+        /// What to do when a file already exists (overwrite, skip, rename, or backup)
+        #[arg(long, default_value = "rename", value_name = "MODE")]
+        on_conflict: String,
+
+        /// Keep the .part file of a transfer that did not finish
+        #[arg(long)]
+        keep_partial: bool,
+
+        /// Force each received file to disk before it is renamed into place
+        #[arg(long)]
+        fsync: bool,
+
+        /// How to strip the ^Z padding from the end of files (auto, text, or binary)
+        #[arg(long, default_value = "auto", value_name = "MODE")]
+        trim: String,
+
+        /// Trim mode for one extension, e.g. 'doc=binary' (may be repeated)
+        #[arg(long, value_name = "EXT=MODE")]
+        trim_ext: Vec<String>,
+
+        #[command(flatten)]
+        modes: ModeArgs,
     },
 }
 
+/// What is written to stdout
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Messages and progress for a person
+    Text,
+    /// JSON Lines events for scripts, with the messages on stderr
+    Json,
+}
+
+/// Transfer mode selection shared by `send` and `receive`
+#[derive(Args)]
+struct ModeArgs {
+    /// Transfer mode for files not matched by --text or --binary (binary or text)
+    #[arg(long, default_value = "binary", value_name = "MODE")]
+    mode: String,
+
+    /// Transfer files whose name matches this glob as text (may be repeated)
+    #[arg(long, value_name = "GLOB")]
+    text: Vec<String>,
+
+    /// Transfer files whose name matches this glob byte-exact (may be repeated)
+    #[arg(long, value_name = "GLOB")]
+    binary: Vec<String>,
+}
+
 fn parse_data_bits(bits: u8) -> Result<DataBits, String> {
     match bits {
         5 => Ok(DataBits::Five),
@@ -104,6 +250,204 @@ fn parse_stop_bits(bits: u8) -> Result<StopBits, String> {
     }
 }
 
+fn parse_output_format(format: &str) -> Result<OutputFormat, String> {
+    match format.to_lowercase().as_str() {
+        "text" => Ok(OutputFormat::Text),
+        "json" => Ok(OutputFormat::Json),
+        _ => Err(format!("Invalid output format: {}. Must be 'text' or 'json'", format)),
+    }
+}
+
+fn parse_report_format(path: &Path) -> Result<ReportFormat, String> {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match ext.to_lowercase().as_str() {
+        "json" => Ok(ReportFormat::Json),
+        "csv" => Ok(ReportFormat::Csv),
+        _ => Err(format!("Invalid report file: {}. Must end in '.json' or '.csv'", path.display())),
+    }
+}
+
+fn parse_trace_format(format: &str) -> Result<TraceFormat, String> {
+    match format.to_lowercase().as_str() {
+        "text" => Ok(TraceFormat::Text),
+        "binary" => Ok(TraceFormat::Binary),
+        _ => Err(format!("Invalid trace format: {}. Must be 'text' or 'binary'", format)),
+    }
+}
+
+fn parse_noise(spec: &str) -> Result<NoiseConfig, String> {
+    let mut config = NoiseConfig { seed: noise_seed(), ..Default::default() };
+    for setting in spec.split(',').filter(|s| !s.is_empty()) {
+        let invalid = || format!("Invalid noise setting: {}", setting);
+        let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+        let chance = || match value.parse::<f64>() {
+            Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+            _ => Err(format!("Invalid noise setting: {}. Must be a chance from 0 to 1", setting)),
+        };
+        match key {
+            "flip" => config.bit_flip = chance()?,
+            "drop" => config.drop = chance()?,
+            "dup" => config.duplicate = chance()?,
+            "latency" => config.latency = Duration::from_millis(value.parse().map_err(|_| invalid())?),
+            "drain" => config.drain_rate = Some(value.parse().map_err(|_| invalid())?),
+            "buffer" => config.buffer = value.parse().map_err(|_| invalid())?,
+            "seed" => config.seed = value.parse().map_err(|_| invalid())?,
+            _ => {
+                return Err(format!(
+                    "Invalid noise setting: {}. Must be one of flip, drop, dup, latency, drain, buffer or seed",
+                    setting
+                ));
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// A different seed for each run unless one is given
+fn noise_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn parse_pacing(spec: &str) -> Result<AdaptivePacing, String> {
+    let mut pacing = AdaptivePacing::default();
+    for setting in spec.split(',').filter(|s| !s.is_empty()) {
+        let invalid = || format!("Invalid pacing setting: {}", setting);
+        let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+        let value: u32 = value.parse().map_err(|_| invalid())?;
+        match key {
+            "step" => pacing.byte_step = Duration::from_millis(value.into()),
+            "block" => pacing.block_step = Duration::from_millis(value.into()),
+            "max" => pacing.max_steps = value,
+            "recover" => pacing.recover_after = value,
+            _ => {
+                return Err(format!(
+                    "Invalid pacing setting: {}. Must be one of step, block, max or recover",
+                    setting
+                ));
+            }
+        }
+    }
+    Ok(pacing)
+}
+
+/// Apply the overrides in `spec` to the timeouts derived from the line
+///
+/// An automatic checksum timeout is raised to cover overridden byte and
+/// control timeouts; one given explicitly has to cover them already.
+fn parse_timeouts(spec: &str, auto: Timeouts) -> Result<Timeouts, String> {
+    let mut timeouts = auto;
+    let mut checksum_given = false;
+    for setting in spec.split(',').filter(|s| !s.is_empty() && *s != "auto") {
+        let (key, value) = setting.split_once('=').ok_or_else(|| format!("Invalid timeout: {}", setting))?;
+        let value = match value.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok()) {
+            Some(value) if !value.is_zero() => value,
+            _ => return Err(format!("Invalid timeout: {}. Must be a number of seconds", setting)),
+        };
+        match key {
+            "handshake" => timeouts.handshake = value,
+            "control" => timeouts.control = value,
+            "byte" => timeouts.data_byte = value,
+            "checksum" => {
+                timeouts.checksum = value;
+                checksum_given = true;
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid timeout: {}. Must be auto or one of handshake, control, byte or checksum",
+                    setting
+                ));
+            }
+        }
+    }
+    if !checksum_given {
+        timeouts.checksum = timeouts.checksum.max(timeouts.min_checksum());
+    } else if timeouts.checksum < timeouts.min_checksum() {
+        return Err(format!(
+            "Invalid timeouts: checksum={} is shorter than byte plus control ({}s)",
+            timeouts.checksum.as_secs_f64(),
+            timeouts.min_checksum().as_secs_f64()
+        ));
+    }
+    Ok(timeouts)
+}
+
+fn parse_on_conflict(mode: &str) -> Result<ConflictPolicy, String> {
+    match mode.to_lowercase().as_str() {
+        "overwrite" => Ok(ConflictPolicy::Overwrite),
+        "skip" => Ok(ConflictPolicy::Skip),
+        "rename" => Ok(ConflictPolicy::Rename),
+        "backup" => Ok(ConflictPolicy::Backup),
+        _ => Err(format!("Invalid conflict mode: {}. Must be 'overwrite', 'skip', 'rename', or 'backup'", mode)),
+    }
+}
+
+fn parse_trim_mode(mode: &str) -> Result<TrimMode, String> {
+    match mode.to_lowercase().as_str() {
+        "auto" => Ok(TrimMode::Auto),
+        "text" => Ok(TrimMode::Text),
+        "binary" => Ok(TrimMode::Binary),
+        _ => Err(format!("Invalid trim mode: {}. Must be 'auto', 'text', or 'binary'", mode)),
+    }
+}
+
+fn parse_trim_rule(rule: &str) -> Result<(String, TrimMode), String> {
+    match rule.split_once('=') {
+        Some((ext, mode)) => {
+            let ext = ext.trim_start_matches('.').to_lowercase();
+            Ok((ext, parse_trim_mode(mode)?))
+        }
+        None => Err(format!("Invalid trim rule: {}. Must be EXT=MODE, e.g. 'doc=text'", rule)),
+    }
+}
+
+fn parse_transfer_mode(mode: &str) -> Result<TransferMode, String> {
+    match mode.to_lowercase().as_str() {
+        "binary" => Ok(TransferMode::Binary),
+        "text" => Ok(TransferMode::Text),
+        _ => Err(format!("Invalid transfer mode: {}. Must be 'binary' or 'text'", mode)),
+    }
+}
+
+fn parse_patterns(patterns: &[String]) -> Result<Vec<glob::Pattern>, String> {
+    patterns
+        .iter()
+        .map(|p| glob::Pattern::new(p).map_err(|e| format!("Invalid pattern: {}: {}", p, e)))
+        .collect()
+}
+
+fn mode_rules(args: &ModeArgs) -> Result<ModeRules, String> {
+    Ok(ModeRules {
+        default: parse_transfer_mode(&args.mode)?,
+        text: parse_patterns(&args.text)?,
+        binary: parse_patterns(&args.binary)?,
+    })
+}
+
+fn retry_policy(cli: &Cli) -> RetryPolicy {
+    let mut policy = RetryPolicy {
+        handshake_attempts: cli.handshake_attempts,
+        connect_timeout: cli.connect_timeout.map(Duration::from_secs),
+        max_retries: cli.max_retries,
+        timeout_retries: cli.timeout_retries,
+        max_stray: cli.max_stray,
+        ..Default::default()
+    };
+    if let Commands::Send { reject_attempts, .. } = &cli.command {
+        policy.reject_attempts = *reject_attempts;
+    }
+    policy
+}
+
+fn trim_rules(trim: &str, trim_ext: &[String]) -> Result<TrimRules, String> {
+    Ok(TrimRules {
+        default: parse_trim_mode(trim)?,
+        rules: trim_ext.iter().map(|r| parse_trim_rule(r)).collect::<Result<_, _>>()?,
+    })
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -111,7 +455,7 @@ fn main() {
         Ok(db) => db,
         Err(e) => {
             eprintln!("Error: {}", e);
-            std::process::exit(1);
+            std::process::exit(EXIT_USAGE);
         }
     };
 
@@ -119,7 +463,7 @@ fn main() {
         Ok(p) => p,
         Err(e) => {
             eprintln!("Error: {}", e);
-            std::process::exit(1);
+            std::process::exit(EXIT_USAGE);
         }
     };
 
@@ -127,91 +471,274 @@ fn main() {
         Ok(sb) => sb,
         Err(e) => {
             eprintln!("Error: {}", e);
-            std::process::exit(1);
+            std::process::exit(EXIT_USAGE);
         }
     };
 
-    println!("Opening serial port: {}", cli.port);
-    println!("Settings: {} baud, {:?}, {:?}, {:?}", cli.baud, data_bits, parity, stop_bits);
+    let output = match parse_output_format(&cli.output) {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let report_file = match cli.report.as_deref().map(parse_report_format).transpose() {
+        Ok(format) => cli.report.clone().zip(format),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let trace_format = match parse_trace_format(&cli.trace_format) {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let frame_bits = 1 + cli.data_bits as u32 + (parity != Parity::None) as u32 + cli.stop_bits as u32;
+    let auto = Timeouts::for_line(cli.baud, frame_bits, Duration::from_millis(cli.byte_delay.into()));
+    let timeouts = match parse_timeouts(&cli.timeouts, auto) {
+        Ok(timeouts) => timeouts,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let pacing = match cli.adaptive_pacing.as_deref().map(parse_pacing).transpose() {
+        Ok(pacing) => pacing,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let noise = match cli.simulate_noise.as_deref().map(parse_noise).transpose() {
+        Ok(noise) => noise,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let human = match output {
+        OutputFormat::Text => Stream::Stdout,
+        OutputFormat::Json => Stream::Stderr,
+    };
+
+    let modes = match &cli.command {
+        Commands::Send { modes, .. } | Commands::Receive { modes, .. } => mode_rules(modes),
+    };
+    let modes = match modes {
+        Ok(modes) => modes,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    let receive_options = match &cli.command {
+        Commands::Receive { on_conflict, trim, trim_ext, .. } => {
+            parse_on_conflict(on_conflict).and_then(|policy| Ok((policy, trim_rules(trim, trim_ext)?)))
+        }
+        Commands::Send { .. } => Ok((ConflictPolicy::default(), TrimRules::default())),
+    };
+    let (on_conflict, trim) = match receive_options {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    // Validate the whole batch before the port is opened, so a bad path
+    // can't kill the session halfway through
+    let files = match &cli.command {
+        Commands::Send { files, recursive, include, exclude, .. } => {
+            let options = FileListOptions {
+                recursive: *recursive,
+                include: include.clone(),
+                exclude: exclude.clone(),
+            };
+            match filelist::collect_files(files, &options) {
+                Ok(files) => files,
+                Err(errors) => {
+                    for e in errors {
+                        eprintln!("Error: {}", e);
+                    }
+                    std::process::exit(EXIT_USAGE);
+                }
+            }
+        }
+        Commands::Receive { .. } => Vec::new(),
+    };
+
+    human.println(format_args!("Opening serial port: {}", cli.port));
+    human.println(format_args!("Settings: {} baud, {:?}, {:?}, {:?}", cli.baud, data_bits, parity, stop_bits));
+    human.println(format_args!(
+        "Timeouts: handshake {:.1}s, control {:.1}s, byte {:.1}s, checksum {:.1}s",
+        timeouts.handshake.as_secs_f64(),
+        timeouts.control.as_secs_f64(),
+        timeouts.data_byte.as_secs_f64(),
+        timeouts.checksum.as_secs_f64()
+    ));
 
     let serial_port = match RealSerialPort::open(&cli.port, cli.baud, data_bits, parity, stop_bits) {
         Ok(port) => port,
         Err(e) => {
             eprintln!("Failed to open serial port: {}", e);
-            std::process::exit(1);
+            if output == OutputFormat::Json {
+                json::emit(&json::error_json(&e.to_string(), None, EXIT_USAGE));
+            }
+            std::process::exit(EXIT_USAGE);
         }
     };
-
-    match cli.command {
-        Commands::Send { file } => {
-            println!("\nSending file: {}", file.display());
-            if let Err(e) = send_file(serial_port, file, cli.byte_delay, cli.debug) {
-                eprintln!("Send failed: {}", e);
-                std::process::exit(1);
-            }
-            println!("\nFile sent successfully!");
+    // Noise goes between the port and the trace, so the trace shows the
+    // bytes the state machine actually saw
+    let serial_port: Box<dyn SerialPort> = match noise {
+        Some(noise) => {
+            let drain = noise.drain_rate.map_or("unlimited".to_string(), |rate| format!("{} cps into {} bytes", rate, noise.buffer));
+            human.println(format_args!(
+                "Simulating line noise: flip {}, drop {}, dup {}, latency {} ms, drain {} (seed {})",
+                noise.bit_flip, noise.drop, noise.duplicate, noise.latency.as_millis(), drain, noise.seed
+            ));
+            Box::new(NoisyPort::new(serial_port, noise))
         }
-        Commands::Receive { output_dir } => {
-            println!("\nReceiving files to: {}", output_dir.display());
-            if let Err(e) = receive_files(serial_port, output_dir, cli.debug) {
-                eprintln!("Receive failed: {}", e);
-                std::process::exit(1);
+        None => Box::new(serial_port),
+    };
+    let serial_port: Box<dyn SerialPort> = match &cli.trace {
+        Some(path) => {
+            match std::fs::File::create(path).and_then(|file| TracePort::new(serial_port, file, trace_format)) {
+                Ok(port) => Box::new(port),
+                Err(e) => {
+                    eprintln!("Failed to create trace file {}: {}", path.display(), e);
+                    std::process::exit(EXIT_USAGE);
+                }
             }
-            println!("\nFiles received successfully!");
         }
-    }
-}
-
-fn send_file(serial_port: RealSerialPort, file: PathBuf, byte_delay: u8, debug: bool) -> Result<(), sender::SenderError> {
-    use sender::{SenderFsm, InitialHandshake};
+        None => Box::new(serial_port),
+    };
 
-    if !file.exists() {
-        return Err(sender::SenderError::Io(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("File not found: {}", file.display()),
-        )));
+    // The first Ctrl-C stops the session cleanly at the next read, a second
+    // one exits at once
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = cancel.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        if handler_cancel.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_CANCELLED);
+        }
+    }) {
+        eprintln!("Warning: can't handle Ctrl-C: {}", e);
     }
+    let retry = retry_policy(&cli);
 
-    let mut state = SenderFsm::<InitialHandshake>::new(Box::new(serial_port), vec![file], byte_delay, debug);
+    match cli.command {
+        Commands::Send { .. } => {
+            human.println(format_args!("\nSending {} file(s):", files.len()));
+            let names = filink::assign_filenames(&files);
+            for (file, name) in files.iter().zip(&names) {
+                human.println(format_args!("  {:<12}  {}", filink::display_filename(name), file.display()));
+            }
+            let sizes: Vec<u64> = files.iter().map(|f| std::fs::metadata(f).map(|m| m.len()).unwrap_or(0)).collect();
 
-    loop {
-        match state.step() {
-            Ok(next_state) => {
-                state = next_state;
+            let mut sender = Sender::builder(serial_port)
+                .files(files)
+                .byte_delay(cli.byte_delay)
+                .modes(modes)
+                .retry(retry)
+                .timeouts(timeouts)
+                .cancel(cancel)
+                .observer(Progress::sender(&sizes, human))
+                .debug(cli.debug);
+            if let Some(pacing) = pacing {
+                sender = sender.adaptive_pacing(pacing);
+            }
+            if output == OutputFormat::Json {
+                sender = sender.observer(JsonLines);
             }
-            Err(sender::SenderError::TransferComplete) => {
-                return Ok(());
+
+            let (report, result) = sender.run_with_report();
+            finish_report(&report, report_file.as_ref(), human);
+            if let Err(e) = result {
+                eprintln!("Send failed: {}", e);
+                let code = sender_exit_code(&e);
+                if output == OutputFormat::Json {
+                    json::emit(&json::error_json(&e.to_string(), e.state(), code));
+                }
+                std::process::exit(code);
             }
-            Err(e) => {
-                return Err(e);
+            if report.rejected().next().is_some() {
+                human.println(format_args!("\nAll other files sent. Rejected by the receiver:"));
+                for file in report.rejected() {
+                    human.println(format_args!("  {}", file.path.display()));
+                }
+                std::process::exit(EXIT_REJECTED);
             }
+            human.println(format_args!("\nFiles sent successfully!"));
+        }
+        Commands::Receive { output_dir, keep_partial, fsync, .. } => {
+            human.println(format_args!("\nReceiving files to: {}", output_dir.display()));
+
+            let mut receiver = Receiver::builder(serial_port)
+                .output(output_dir)
+                .on_conflict(on_conflict)
+                .keep_partial(keep_partial)
+                .fsync(fsync)
+                .trim(trim)
+                .modes(modes)
+                .retry(retry)
+                .timeouts(timeouts)
+                .cancel(cancel)
+                .observer(Progress::receiver(human))
+                .debug(cli.debug);
+            if output == OutputFormat::Json {
+                receiver = receiver.observer(JsonLines);
+            }
+
+            let (report, result) = receiver.run_with_report();
+            finish_report(&report, report_file.as_ref(), human);
+            if let Err(e) = result {
+                eprintln!("Receive failed: {}", e);
+                let code = receiver_exit_code(&e);
+                if output == OutputFormat::Json {
+                    json::emit(&json::error_json(&e.to_string(), e.state(), code));
+                }
+                std::process::exit(code);
+            }
+            human.println(format_args!("\nFiles received successfully!"));
         }
     }
 }
 
-fn receive_files(serial_port: RealSerialPort, output_dir: PathBuf, debug: bool) -> Result<(), receiver::ReceiverError> {
-    use receiver::{ReceiverFsm, InitialHandshake};
-
-    if !output_dir.exists() {
-        return Err(receiver::ReceiverError::Io(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("Output directory not found: {}", output_dir.display()),
-        )));
+/// Print the summary table and write the `--report` file, if one was asked for
+fn finish_report(report: &SessionReport, file: Option<&(PathBuf, ReportFormat)>, human: Stream) {
+    report::print_table(report, human);
+    if let Some((path, format)) = file
+        && let Err(e) = report::write(path, *format, report)
+    {
+        eprintln!("Failed to write report {}: {}", path.display(), e);
+        std::process::exit(EXIT_LOCAL_FILE);
     }
+}
 
-    let mut state = ReceiverFsm::<InitialHandshake>::new(Box::new(serial_port), output_dir, debug);
+fn sender_exit_code(err: &SenderError) -> i32 {
+    match err {
+        SenderError::Io(_) => EXIT_IO,
+        SenderError::Timeout { .. } => EXIT_TIMEOUT,
+        SenderError::UnexpectedByte { .. } => EXIT_PROTOCOL,
+        SenderError::RetriesExhausted { .. } => EXIT_RETRIES,
+        SenderError::LocalFile { .. } => EXIT_LOCAL_FILE,
+        SenderError::NoFiles => EXIT_USAGE,
+        SenderError::Cancelled => EXIT_CANCELLED,
+        _ => EXIT_IO,
+    }
+}
 
-    loop {
-        match state.step() {
-            Ok(next_state) => {
-                state = next_state;
-            }
-            Err(receiver::ReceiverError::TransferComplete) => {
-                return Ok(());
-            }
-            Err(e) => {
-                return Err(e);
-            }
-        }
+fn receiver_exit_code(err: &ReceiverError) -> i32 {
+    match err {
+        ReceiverError::Io(_) => EXIT_IO,
+        ReceiverError::Timeout { .. } => EXIT_TIMEOUT,
+        ReceiverError::RetriesExhausted { .. } => EXIT_RETRIES,
+        ReceiverError::LocalFile { .. } => EXIT_LOCAL_FILE,
+        ReceiverError::Cancelled => EXIT_CANCELLED,
+        _ => EXIT_IO,
     }
 }
@@ -0,0 +1,288 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Simulated line faults
+//!
+//! [`NoisyPort`] wraps a serial port and damages the bytes passing through
+//! it in both directions the way a long cable does: flipped bits, lost and
+//! doubled bytes. It can also delay writes, and model a machine at the
+//! other end that loses bytes arriving faster than it can take them out
+//! of its input buffer. Faults are drawn from a seeded generator, so a
+//! failure can be reproduced with the same seed.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use crate::serial::SerialPort;
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+/// Which faults to inject, and how often
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoiseConfig {
+    /// Chance that a byte has one of its bits flipped
+    pub bit_flip: f64,
+    /// Chance that a byte is lost
+    pub drop: f64,
+    /// Chance that a byte arrives twice
+    pub duplicate: f64,
+    /// Delay before each write goes out
+    pub latency: Duration,
+    /// Bytes per second the other machine takes out of its input buffer,
+    /// or `None` if it always keeps up. Bytes written while the buffer is
+    /// full are lost.
+    pub drain_rate: Option<u32>,
+    /// Bytes the other machine's input buffer holds
+    pub buffer: u32,
+    /// Seed for the fault generator
+    pub seed: u64,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        NoiseConfig {
+            bit_flip: 0.0,
+            drop: 0.0,
+            duplicate: 0.0,
+            latency: Duration::ZERO,
+            drain_rate: None,
+            buffer: 1,
+            seed: 0,
+        }
+    }
+}
+
+/// Small seeded generator (SplitMix64), good enough to decide faults
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// True with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+// ============================================================================
+// Noisy Port
+// ============================================================================
+
+/// Serial port wrapper that injects the faults of a [`NoiseConfig`]
+pub struct NoisyPort<P> {
+    inner: P,
+    config: NoiseConfig,
+    rng: Rng,
+    /// Damaged bytes read but not yet returned
+    pending: VecDeque<u8>,
+    /// Bytes in the other machine's buffer at `drained_at`
+    buffered: f64,
+    drained_at: Instant,
+}
+
+impl<P: SerialPort> NoisyPort<P> {
+    pub fn new(inner: P, config: NoiseConfig) -> Self {
+        NoisyPort {
+            inner,
+            config,
+            rng: Rng(config.seed),
+            pending: VecDeque::new(),
+            buffered: 0.0,
+            drained_at: Instant::now(),
+        }
+    }
+
+    /// Pass one byte over the line, appending what arrives to `out`
+    fn damage(&mut self, byte: u8, out: &mut Vec<u8>) {
+        if self.rng.chance(self.config.drop) {
+            return;
+        }
+        let byte = if self.rng.chance(self.config.bit_flip) {
+            byte ^ (1 << (self.rng.next() % 8))
+        } else {
+            byte
+        };
+        out.push(byte);
+        if self.rng.chance(self.config.duplicate) {
+            out.push(byte);
+        }
+    }
+
+    /// Whether the other machine has room for another byte
+    fn accepts_byte(&mut self) -> bool {
+        let Some(rate) = self.config.drain_rate else { return true };
+        let now = Instant::now();
+        let drained = now.duration_since(self.drained_at).as_secs_f64() * rate as f64;
+        self.buffered = (self.buffered - drained).max(0.0);
+        self.drained_at = now;
+
+        if self.buffered + 1.0 > self.config.buffer as f64 {
+            return false;
+        }
+        self.buffered += 1.0;
+        true
+    }
+
+    fn take_pending(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.pending.len());
+        for (slot, byte) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *slot = byte;
+        }
+        n
+    }
+}
+
+impl<P: SerialPort> SerialPort for NoisyPort<P> {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if !self.config.latency.is_zero() {
+            std::thread::sleep(self.config.latency);
+        }
+
+        let mut line = Vec::with_capacity(buf.len());
+        for &byte in buf {
+            self.damage(byte, &mut line);
+        }
+        line.retain(|_| self.accepts_byte());
+
+        if line.is_empty() {
+            return Ok(());
+        }
+        self.inner.write_all(&line)
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+        if !self.pending.is_empty() {
+            return Ok(self.take_pending(buf));
+        }
+
+        // If every byte that arrived was lost, keep waiting for the rest of
+        // the timeout like a real port would
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut raw = vec![0u8; buf.len()];
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let n = self.inner.read_timeout(&mut raw, remaining)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut arrived = Vec::with_capacity(n);
+            for &byte in &raw[..n] {
+                self.damage(byte, &mut arrived);
+            }
+            self.pending.extend(arrived);
+            if !self.pending.is_empty() {
+                return Ok(self.take_pending(buf));
+            }
+            if Instant::now() >= deadline {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Simulated bytes lost"));
+            }
+        }
+    }
+
+    fn enter_state(&mut self, state: &'static str) {
+        self.inner.enter_state(state);
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::loopback;
+
+    fn through(config: NoiseConfig, data: &[u8]) -> Vec<u8> {
+        let (a, mut b) = loopback();
+        let mut noisy = NoisyPort::new(a, config);
+        noisy.write_all(data).unwrap();
+        let mut out = Vec::new();
+        let mut buf = [0u8; 64];
+        while let Ok(n) = b.read_timeout(&mut buf, Duration::from_millis(10)) {
+            out.extend_from_slice(&buf[..n]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_faults() {
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(through(NoiseConfig::default(), &data), data);
+        assert!(through(NoiseConfig { drop: 1.0, ..Default::default() }, &data).is_empty());
+        assert_eq!(through(NoiseConfig { duplicate: 1.0, ..Default::default() }, b"ab"), b"aabb");
+
+        let flipped = through(NoiseConfig { bit_flip: 1.0, ..Default::default() }, &data);
+        assert_eq!(flipped.len(), data.len());
+        assert!(flipped.iter().zip(&data).all(|(a, b)| (a ^ b).count_ones() == 1));
+    }
+
+    #[test]
+    fn test_faults_are_repeatable() {
+        let data = vec![0u8; 2000];
+        let config = NoiseConfig { bit_flip: 0.05, drop: 0.05, duplicate: 0.05, seed: 42, ..Default::default() };
+        let first = through(config, &data);
+        assert_ne!(first, data);
+        assert_eq!(through(config, &data), first);
+        assert_ne!(through(NoiseConfig { seed: 43, ..config }, &data), first);
+    }
+
+    #[test]
+    fn test_overrun() {
+        // A burst fills the buffer and the rest is lost
+        let config = NoiseConfig { drain_rate: Some(10), buffer: 16, ..Default::default() };
+        let data = vec![b'x'; 100];
+        let arrived = through(config, &data).len();
+        assert!((16..20).contains(&arrived), "{} bytes arrived", arrived);
+
+        // Slow enough, everything arrives
+        let (a, mut b) = loopback();
+        let mut noisy = NoisyPort::new(a, NoiseConfig { drain_rate: Some(1000), ..Default::default() });
+        for _ in 0..5 {
+            noisy.write_all(b"y").unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let mut buf = [0u8; 8];
+        assert_eq!(b.read_timeout(&mut buf, Duration::from_millis(10)).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_damaged_reads() {
+        let (mut a, b) = loopback();
+        let mut noisy = NoisyPort::new(b, NoiseConfig { duplicate: 1.0, ..Default::default() });
+        a.write_all(b"ok").unwrap();
+
+        let mut buf = [0u8; 3];
+        assert_eq!(noisy.read_timeout(&mut buf, Duration::from_millis(10)).unwrap(), 3);
+        assert_eq!(&buf, b"ook");
+        assert_eq!(noisy.read_timeout(&mut buf, Duration::from_millis(10)).unwrap(), 1);
+        assert_eq!(buf[0], b'k');
+
+        let (mut a, b) = loopback();
+        let mut noisy = NoisyPort::new(b, NoiseConfig { drop: 1.0, ..Default::default() });
+        a.write_all(b"lost").unwrap();
+        let err = noisy.read_timeout(&mut buf, Duration::from_millis(20)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
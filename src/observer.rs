@@ -0,0 +1,122 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Events reported while a session runs
+//!
+//! The state machines never print. Everything worth showing, from the
+//! handshake to each acknowledged block, is passed to the observers given
+//! to the session builder as an [`Event`].
+
+use std::path::PathBuf;
+use crate::pacing::Pacing;
+use crate::session::{FileReport, SessionReport};
+
+// ============================================================================
+// Events
+// ============================================================================
+
+/// Something that happened during a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// Waiting for the other side to answer the handshake, counting from 1
+    HandshakeAttempt { attempt: u32 },
+    /// The handshake completed
+    HandshakeCompleted,
+    /// A file was accepted by the receiver. `size` is only known when sending.
+    FileStarted { path: PathBuf, name: String, size: Option<u64> },
+    /// The receiver acknowledged a block. `bytes` is the file data sent so
+    /// far, `index` counts the blocks of this file from 0.
+    BlockSent { index: u32, bytes: u64 },
+    /// A block passed its checksum and was saved. `bytes` is the data
+    /// received so far, including padding.
+    BlockReceived { index: u32, bytes: u64 },
+    /// A block failed its checksum and is sent again
+    ChecksumRetry { index: u32, attempt: u32 },
+    /// Adaptive pacing changed the sender's delays
+    PacingChanged(Pacing),
+    /// A file was transferred completely
+    FileCompleted(FileReport),
+    /// The receiver refused a file with 'X'. `path` is the local file when
+    /// sending, or the file in the way when receiving.
+    FileRejected { name: String, path: Option<PathBuf>, reason: String },
+    /// A received file's name was taken; `note` says how that was handled
+    NameConflict { name: String, note: String },
+    /// The session failed while a file was being received. `kept` is the
+    /// partial file, if it was kept.
+    FileAbandoned { path: PathBuf, kept: Option<PathBuf> },
+    /// The session is complete
+    SessionEnded(SessionReport),
+    /// One line of protocol trace, only sent when debugging is enabled
+    Trace(String),
+}
+
+// ============================================================================
+// Observer Trait
+// ============================================================================
+
+/// Receives the events of a session as they happen
+pub trait Observer: Send {
+    fn on_event(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event) + Send> Observer for F {
+    fn on_event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Passes each event on to every observer given to a builder
+#[derive(Default)]
+pub(crate) struct Observers(Vec<Box<dyn Observer>>);
+
+impl Observers {
+    pub fn push(&mut self, observer: Box<dyn Observer>) {
+        self.0.push(observer);
+    }
+}
+
+impl Observer for Observers {
+    fn on_event(&mut self, event: &Event) {
+        for observer in &mut self.0 {
+            observer.on_event(event);
+        }
+    }
+}
+
+/// Send a line of protocol trace to the observers, if debugging is enabled
+macro_rules! trace {
+    ($fsm:expr, $($arg:tt)*) => {
+        if $fsm.debug {
+            let message = format!($($arg)*);
+            $fsm.observer.on_event(&$crate::observer::Event::Trace(message));
+        }
+    };
+}
+
+pub(crate) use trace;
+
+#[cfg(test)]
+impl Event {
+    /// Zero the durations in an event, so events from a test run can be compared
+    pub(crate) fn untimed(self) -> Self {
+        match self {
+            Event::FileCompleted(file) => Event::FileCompleted(file.untimed()),
+            Event::SessionEnded(report) => Event::SessionEnded(report.untimed()),
+            other => other,
+        }
+    }
+}
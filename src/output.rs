@@ -0,0 +1,478 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Writing received files to disk
+//!
+//! Data is written to `<name>.part` and only renamed to its real name once
+//! the sender signals the end of the file, so an aborted session never
+//! leaves a truncated file that looks complete.
+//!
+//! The sender pads the last block of every file with CP/M EOF (0x1A)
+//! characters. The most recent block is held back until the end of the
+//! file is known, so the padding can be trimmed from it. Files received in
+//! text mode are converted as they arrive instead, which also drops the
+//! padding.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::text::{TextDecoder, TransferMode};
+
+// ============================================================================
+// Padding Trimming
+// ============================================================================
+
+/// CP/M end-of-file marker, also used to pad the last block of a file
+pub const CPM_EOF: u8 = 0x1A;
+
+/// Extensions treated as text in `TrimMode::Auto`
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "doc", "bas", "asm", "mac", "z80", "pas", "c", "h", "for",
+    "prn", "lst", "sub", "bat", "csv", "me", "hlp",
+];
+
+/// How to remove the 0x1A padding from the last block of a received file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrimMode {
+    /// Text rules for known text extensions, otherwise trim only when the
+    /// last block looks like text followed by nothing but padding
+    #[default]
+    Auto,
+    /// Cut at the first 0x1A in the last block
+    Text,
+    /// Keep every byte that was received
+    Binary,
+}
+
+/// Chooses the trim mode for each received file
+#[derive(Debug, Clone, Default)]
+pub struct TrimRules {
+    /// Mode for files that no rule matches
+    pub default: TrimMode,
+    /// Per-extension overrides, as lowercase extensions without the dot
+    pub rules: Vec<(String, TrimMode)>,
+}
+
+impl TrimRules {
+    pub fn mode_for(&self, path: &Path) -> TrimMode {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        if let Some((_, mode)) = self.rules.iter().find(|(rule, _)| *rule == ext) {
+            return *mode;
+        }
+
+        if self.default == TrimMode::Auto && TEXT_EXTENSIONS.contains(&ext.as_str()) {
+            return TrimMode::Text;
+        }
+
+        self.default
+    }
+}
+
+/// Return the part of the last block that belongs to the file
+pub fn trim_padding(block: &[u8], mode: TrimMode) -> &[u8] {
+    let Some(eof) = block.iter().position(|&b| b == CPM_EOF) else {
+        return block;
+    };
+
+    match mode {
+        TrimMode::Binary => block,
+        TrimMode::Text => &block[..eof],
+        TrimMode::Auto => {
+            let only_padding = block[eof..].iter().all(|&b| b == CPM_EOF);
+            let looks_like_text = block[..eof]
+                .iter()
+                .all(|&b| b >= 0x20 || matches!(b, b'\t' | b'\n' | b'\r' | 0x0C));
+
+            if only_padding && looks_like_text {
+                &block[..eof]
+            } else {
+                block
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Partial File
+// ============================================================================
+
+/// A received file that is still being written.
+///
+/// Dropping a `PartialFile` without calling `commit` removes the `.part`
+/// file, unless it was created with `keep` set.
+pub struct PartialFile {
+    file: Option<File>,
+    part_path: PathBuf,
+    final_path: PathBuf,
+    backup_path: Option<PathBuf>,
+    trim: TrimMode,
+    last_block: Vec<u8>,
+    decoder: Option<TextDecoder>,
+    keep: bool,
+}
+
+impl PartialFile {
+    /// Start writing a file that will be saved as `final_path`. If
+    /// `backup_path` is given, whatever is at `final_path` is moved there
+    /// when the file is committed. `trim` is applied to the last block of
+    /// binary files.
+    pub fn create(
+        final_path: PathBuf,
+        backup_path: Option<PathBuf>,
+        mode: TransferMode,
+        trim: TrimMode,
+        keep: bool,
+    ) -> std::io::Result<Self> {
+        let (file, part_path) = create_part(&final_path)?;
+
+        Ok(PartialFile {
+            file: Some(file),
+            part_path,
+            final_path,
+            backup_path,
+            trim,
+            last_block: Vec::new(),
+            decoder: (mode == TransferMode::Text).then(TextDecoder::default),
+            keep,
+        })
+    }
+
+    pub fn final_path(&self) -> &Path {
+        &self.final_path
+    }
+
+    /// Append a block. In binary mode the block is held back until the next
+    /// one arrives, so padding can be trimmed if it turns out to be the last.
+    pub fn write_block(&mut self, block: &[u8]) -> std::io::Result<()> {
+        if let Some(ref mut decoder) = self.decoder {
+            let mut converted = Vec::with_capacity(block.len());
+            decoder.decode(block, &mut converted);
+            return match self.file {
+                Some(ref mut file) => file.write_all(&converted),
+                None => Ok(()),
+            };
+        }
+
+        if let Some(ref mut file) = self.file {
+            file.write_all(&self.last_block)?;
+        }
+        self.last_block.clear();
+        self.last_block.extend_from_slice(block);
+        Ok(())
+    }
+
+    /// Write the trimmed last block and move the finished file into place,
+    /// optionally forcing it to disk first. Returns the path the file was
+    /// saved as.
+    pub fn commit(mut self, fsync: bool) -> std::io::Result<PathBuf> {
+        if let Some(mut file) = self.file.take() {
+            match self.decoder {
+                Some(ref mut decoder) => {
+                    let mut tail = Vec::new();
+                    decoder.finish(&mut tail);
+                    file.write_all(&tail)?;
+                }
+                None => file.write_all(trim_padding(&self.last_block, self.trim))?,
+            }
+            file.flush()?;
+            if fsync {
+                file.sync_all()?;
+            }
+        }
+
+        if let Some(ref backup) = self.backup_path
+            && self.final_path.exists()
+        {
+            std::fs::rename(&self.final_path, backup)?;
+        }
+
+        std::fs::rename(&self.part_path, &self.final_path)?;
+        // The .part file is gone, so there is nothing left for drop to remove
+        self.keep = true;
+
+        if fsync {
+            sync_dir(&self.final_path)?;
+        }
+
+        Ok(self.final_path.clone())
+    }
+
+    /// Give up on the file. Returns the path of the partial data if it was
+    /// kept.
+    pub fn abandon(mut self) -> Option<PathBuf> {
+        if let Some(ref mut file) = self.file {
+            file.write_all(&self.last_block).ok();
+        }
+        self.file = None;
+        if self.keep {
+            Some(self.part_path.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        self.file = None;
+        if !self.keep {
+            std::fs::remove_file(&self.part_path).ok();
+        }
+    }
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// Create `<name>.part`, or `<name>.1.part`, `<name>.2.part`, ... if an
+/// earlier session left one behind, so a kept partial file is never
+/// overwritten
+fn create_part(final_path: &Path) -> std::io::Result<(File, PathBuf)> {
+    for n in 0u32.. {
+        let part_path = part_path(final_path, n);
+        match OpenOptions::new().write(true).create_new(true).open(&part_path) {
+            Ok(file) => return Ok((file, part_path)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+fn part_path(final_path: &Path, n: u32) -> PathBuf {
+    let mut name = final_path.file_name().unwrap_or_default().to_os_string();
+    if n > 0 {
+        name.push(format!(".{}", n));
+    }
+    name.push(".part");
+    final_path.with_file_name(name)
+}
+
+/// Make a rename durable by syncing the directory that holds the file
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_partial_file_commit() {
+        let dir = setup("output_commit");
+        let path = dir.join("notes.txt");
+
+        let mut file = PartialFile::create(path.clone(), None, TransferMode::Binary, TrimMode::Auto, false).unwrap();
+        file.write_block(b"hello").unwrap();
+        assert!(dir.join("notes.txt.part").exists());
+        assert!(!path.exists());
+
+        assert_eq!(file.commit(true).unwrap(), path);
+        assert!(!dir.join("notes.txt.part").exists());
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_partial_file_backup_on_commit() {
+        let dir = setup("output_backup");
+        let path = dir.join("notes.txt");
+        let backup = dir.join("notes.txt.~1~");
+        std::fs::write(&path, b"old").unwrap();
+
+        let mut file = PartialFile::create(path.clone(), Some(backup.clone()), TransferMode::Binary, TrimMode::Auto, false).unwrap();
+        file.write_block(b"new").unwrap();
+
+        // The existing file is untouched until the new one is complete
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        assert!(!backup.exists());
+
+        file.commit(false).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read(&backup).unwrap(), b"old");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_partial_file_abandon() {
+        let dir = setup("output_abandon");
+        let path = dir.join("notes.txt");
+
+        let mut file = PartialFile::create(path.clone(), None, TransferMode::Binary, TrimMode::Auto, false).unwrap();
+        file.write_block(b"trunc").unwrap();
+        assert_eq!(file.abandon(), None);
+        assert!(!dir.join("notes.txt.part").exists());
+        assert!(!path.exists());
+
+        let mut file = PartialFile::create(path.clone(), None, TransferMode::Binary, TrimMode::Auto, true).unwrap();
+        file.write_block(b"trunc").unwrap();
+        assert_eq!(file.abandon(), Some(dir.join("notes.txt.part")));
+        assert_eq!(std::fs::read(dir.join("notes.txt.part")).unwrap(), b"trunc");
+        assert!(!path.exists());
+
+        let file = PartialFile::create(dir.join("dropped.txt"), None, TransferMode::Binary, TrimMode::Auto, false).unwrap();
+        drop(file);
+        assert!(!dir.join("dropped.txt.part").exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_partial_file_keeps_existing_part() {
+        let dir = setup("output_existing_part");
+        let path = dir.join("notes.txt");
+        std::fs::write(dir.join("notes.txt.part"), b"kept").unwrap();
+        std::fs::write(dir.join("notes.txt.1.part"), b"kept too").unwrap();
+
+        let mut file = PartialFile::create(path.clone(), None, TransferMode::Binary, TrimMode::Auto, true).unwrap();
+        file.write_block(b"again").unwrap();
+        assert_eq!(file.abandon(), Some(dir.join("notes.txt.2.part")));
+        assert_eq!(std::fs::read(dir.join("notes.txt.part")).unwrap(), b"kept");
+        assert_eq!(std::fs::read(dir.join("notes.txt.1.part")).unwrap(), b"kept too");
+        assert_eq!(std::fs::read(dir.join("notes.txt.2.part")).unwrap(), b"again");
+
+        let mut file = PartialFile::create(path.clone(), None, TransferMode::Binary, TrimMode::Auto, false).unwrap();
+        file.write_block(b"done").unwrap();
+        file.commit(false).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"done");
+        assert!(!dir.join("notes.txt.3.part").exists());
+        assert_eq!(std::fs::read(dir.join("notes.txt.part")).unwrap(), b"kept");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn padded(data: &[u8]) -> Vec<u8> {
+        let mut block = data.to_vec();
+        block.resize(128, CPM_EOF);
+        block
+    }
+
+    #[test]
+    fn test_trim_padding() {
+        let text = padded(b"Hello, world\r\n");
+        assert_eq!(trim_padding(&text, TrimMode::Text), b"Hello, world\r\n");
+        assert_eq!(trim_padding(&text, TrimMode::Auto), b"Hello, world\r\n");
+        assert_eq!(trim_padding(&text, TrimMode::Binary), &text[..]);
+
+        // Binary data followed by padding is left alone in auto mode
+        let binary = padded(&[0xC3, 0x00, 0x01, 0x1A, 0x00]);
+        assert_eq!(trim_padding(&binary, TrimMode::Auto), &binary[..]);
+        assert_eq!(trim_padding(&binary, TrimMode::Text), &[0xC3, 0x00, 0x01]);
+
+        // A ^Z inside the data is only a cut point in text mode
+        let mut embedded = b"abc".to_vec();
+        embedded.push(CPM_EOF);
+        embedded.extend_from_slice(b"def");
+        let embedded = padded(&embedded);
+        assert_eq!(trim_padding(&embedded, TrimMode::Auto), &embedded[..]);
+        assert_eq!(trim_padding(&embedded, TrimMode::Text), b"abc");
+
+        // WordStar sets the high bit on some characters
+        let wordstar = padded(b"Soft\x8Dhyphen");
+        assert_eq!(trim_padding(&wordstar, TrimMode::Auto), b"Soft\x8Dhyphen");
+
+        let full = vec![b'x'; 128];
+        assert_eq!(trim_padding(&full, TrimMode::Text), &full[..]);
+
+        let all_padding = padded(b"");
+        assert_eq!(trim_padding(&all_padding, TrimMode::Auto), b"");
+    }
+
+    #[test]
+    fn test_trim_rules() {
+        let rules = TrimRules::default();
+        assert_eq!(rules.mode_for(Path::new("notes.txt")), TrimMode::Text);
+        assert_eq!(rules.mode_for(Path::new("PROG.ASM")), TrimMode::Text);
+        assert_eq!(rules.mode_for(Path::new("prog.com")), TrimMode::Auto);
+        assert_eq!(rules.mode_for(Path::new("readme")), TrimMode::Auto);
+
+        let rules = TrimRules {
+            default: TrimMode::Binary,
+            rules: vec![("doc".to_string(), TrimMode::Auto), ("".to_string(), TrimMode::Text)],
+        };
+        assert_eq!(rules.mode_for(Path::new("notes.txt")), TrimMode::Binary);
+        assert_eq!(rules.mode_for(Path::new("letter.doc")), TrimMode::Auto);
+        assert_eq!(rules.mode_for(Path::new("readme")), TrimMode::Text);
+    }
+
+    #[test]
+    fn test_partial_file_trims_last_block_only() {
+        let dir = setup("output_trim");
+        let path = dir.join("notes.txt");
+
+        let first = vec![CPM_EOF; 128];
+        let last = padded(b"end");
+
+        let mut file = PartialFile::create(path.clone(), None, TransferMode::Binary, TrimMode::Text, false).unwrap();
+        file.write_block(&first).unwrap();
+        file.write_block(&last).unwrap();
+        file.commit(false).unwrap();
+
+        let mut expected = first.clone();
+        expected.extend_from_slice(b"end");
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_partial_file_text_mode() {
+        let dir = setup("output_text");
+        let path = dir.join("notes.txt");
+
+        let mut first = vec![b'a'; 127];
+        first.push(b'\r');
+        let last = padded(b"\nline two\r\n");
+
+        let mut file = PartialFile::create(path.clone(), None, TransferMode::Text, TrimMode::Binary, false).unwrap();
+        file.write_block(&first).unwrap();
+        file.write_block(&last).unwrap();
+        file.commit(false).unwrap();
+
+        let mut expected = vec![b'a'; 127];
+        expected.extend_from_slice(b"\nline two\n");
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
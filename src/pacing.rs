@@ -0,0 +1,244 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Spacing out the bytes of each block for receivers that can't keep up
+//!
+//! Many vintage machines drop characters that arrive back to back. A fixed
+//! byte delay works once the right value is known; adaptive pacing finds
+//! it during the session. It starts at the fixed delay, raises the delay
+//! steeply whenever the receiver answers 'B', and lowers it a step at a
+//! time after a run of good blocks. A delay that produced a bad block is
+//! only tried again after a long run of good blocks just above it, so the
+//! delays settle just above the fastest pace the receiver failed at, but a
+//! single burst of line noise doesn't hold them up for the whole session.
+//!
+//! The delays never reach half the byte or control timeout, so a paced
+//! block is never taken for a sender that stopped.
+
+use std::time::Duration;
+use crate::timeouts::Timeouts;
+
+/// Runs of good blocks at the lowest delay that hasn't failed before the
+/// delay below it is tried again
+const FLOOR_RUNS: u32 = 4;
+
+/// Settings for adaptive pacing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptivePacing {
+    /// How much the byte delay changes by per step
+    pub byte_step: Duration,
+    /// How much the pause before each block changes by per step, or zero
+    /// to never pause between blocks
+    pub block_step: Duration,
+    /// Most steps the delays are raised by. Fewer are taken if the delays
+    /// would reach half the byte or control timeout.
+    pub max_steps: u32,
+    /// Blocks in a row that must be accepted before the delays are lowered
+    /// by a step
+    pub recover_after: u32,
+}
+
+impl Default for AdaptivePacing {
+    fn default() -> Self {
+        AdaptivePacing {
+            byte_step: Duration::from_millis(1),
+            block_step: Duration::ZERO,
+            max_steps: 255,
+            recover_after: 8,
+        }
+    }
+}
+
+/// Delays between the bytes of a block and before each block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pacing {
+    pub byte_delay: Duration,
+    pub block_delay: Duration,
+}
+
+/// Keeps track of the pace while a session runs
+#[derive(Debug, Clone)]
+pub(crate) struct Pacer {
+    base: Duration,
+    adaptive: Option<AdaptivePacing>,
+    /// Steps above the base delay
+    level: u32,
+    /// Most steps above the base delay, within `max_steps` and the timeouts
+    max_level: u32,
+    /// Lowest level that hasn't produced a bad block
+    floor: u32,
+    /// Blocks accepted since the level last changed
+    good_run: u32,
+    /// Runs of good blocks completed at the floor
+    floor_runs: u32,
+}
+
+impl Pacer {
+    /// Pace at `byte_delay`, adapting from there if `adaptive` is given.
+    /// `timeouts` are the waits the receiver is expected to use.
+    pub fn new(byte_delay: Duration, adaptive: Option<AdaptivePacing>, timeouts: &Timeouts) -> Self {
+        let max_level = adaptive.map_or(0, |adaptive| {
+            let byte_room = (timeouts.data_byte / 2).saturating_sub(byte_delay);
+            adaptive.max_steps.min(steps_within(byte_room, adaptive.byte_step))
+                .min(steps_within(timeouts.control / 2, adaptive.block_step))
+        });
+        Pacer { base: byte_delay, adaptive, level: 0, max_level, floor: 0, good_run: 0, floor_runs: 0 }
+    }
+
+    pub fn current(&self) -> Pacing {
+        match self.adaptive {
+            Some(adaptive) => Pacing {
+                byte_delay: self.base + adaptive.byte_step * self.level,
+                block_delay: adaptive.block_step * self.level,
+            },
+            None => Pacing { byte_delay: self.base, block_delay: Duration::ZERO },
+        }
+    }
+
+    /// The delays the session ended with, if they were adapted
+    pub fn settled(&self) -> Option<Pacing> {
+        self.adaptive.map(|_| self.current())
+    }
+
+    /// The receiver accepted a block. Returns whether the pace changed.
+    pub fn accepted(&mut self) -> bool {
+        let Some(adaptive) = self.adaptive else { return false };
+        self.good_run += 1;
+        if self.good_run < adaptive.recover_after || self.level == 0 {
+            return false;
+        }
+        self.good_run = 0;
+
+        if self.level <= self.floor {
+            self.floor_runs += 1;
+            if self.floor_runs < FLOOR_RUNS {
+                return false;
+            }
+            self.floor_runs = 0;
+            self.floor = self.level - 1;
+        }
+        self.level -= 1;
+        true
+    }
+
+    /// The receiver answered 'B'. Returns whether the pace changed.
+    pub fn rejected(&mut self) -> bool {
+        if self.adaptive.is_none() {
+            return false;
+        }
+        self.good_run = 0;
+        self.floor_runs = 0;
+        if self.level >= self.max_level {
+            return false;
+        }
+        self.floor = self.floor.max(self.level + 1);
+        self.level = (self.level * 2).max(self.level + 1).min(self.max_level);
+        true
+    }
+}
+
+/// How many `step`s fit in `room`, or no limit if the step is zero
+fn steps_within(room: Duration, step: Duration) -> u32 {
+    match room.as_nanos().checked_div(step.as_nanos()) {
+        Some(steps) => u32::try_from(steps).unwrap_or(u32::MAX),
+        None => u32::MAX,
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_fixed() {
+        let mut pacer = Pacer::new(ms(3), None, &Timeouts::default());
+        assert!(!pacer.rejected());
+        assert!(!pacer.accepted());
+        assert_eq!(pacer.current(), Pacing { byte_delay: ms(3), block_delay: Duration::ZERO });
+        assert_eq!(pacer.settled(), None);
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let adaptive = AdaptivePacing { block_step: ms(10), recover_after: 2, ..Default::default() };
+        let mut pacer = Pacer::new(ms(1), Some(adaptive), &Timeouts::default());
+        let byte_delays = |pacer: &Pacer| pacer.current().byte_delay;
+        let lowered = |pacer: &mut Pacer, blocks: u32| {
+            let mut seen = Vec::new();
+            for _ in 0..blocks {
+                if pacer.accepted() {
+                    seen.push(pacer.current().byte_delay);
+                }
+            }
+            seen
+        };
+
+        // Backs off steeply: 1, 2, 3, 5, 9 ms
+        for expected in [2, 3, 5, 9] {
+            assert!(pacer.rejected());
+            assert_eq!(byte_delays(&pacer), ms(expected));
+        }
+        assert_eq!(pacer.current().block_delay, ms(80));
+
+        // Creeps back one step per two good blocks, down to just above
+        // the last delay that failed, and stays there for a while
+        assert_eq!(lowered(&mut pacer, 12), [ms(8), ms(7), ms(6)]);
+        assert_eq!(pacer.settled(), Some(Pacing { byte_delay: ms(6), block_delay: ms(50) }));
+
+        // A bad block interrupts the run
+        assert!(pacer.rejected());
+        assert!(!pacer.accepted());
+        assert!(pacer.accepted());
+        assert_eq!(byte_delays(&pacer), ms(10));
+
+        // After four runs at the floor, the delay below it is tried again
+        assert_eq!(lowered(&mut pacer, 14), [ms(9), ms(8), ms(7), ms(6)]);
+        assert!(pacer.rejected());
+        assert_eq!(byte_delays(&pacer), ms(11));
+    }
+
+    #[test]
+    fn test_adaptive_limit() {
+        let adaptive = AdaptivePacing { max_steps: 4, ..Default::default() };
+        let mut pacer = Pacer::new(Duration::ZERO, Some(adaptive), &Timeouts::default());
+        assert!(pacer.rejected());
+        assert!(pacer.rejected());
+        assert!(pacer.rejected());
+        assert!(!pacer.rejected());
+        assert_eq!(pacer.current().byte_delay, ms(4));
+
+        // Both delays stay within half the timeouts: 8 steps of 1 ms fit
+        // between 2 ms and 10 ms, 10 steps of 100 ms in 1 second
+        let adaptive = AdaptivePacing { block_step: ms(100), ..Default::default() };
+        let timeouts = Timeouts { data_byte: ms(20), ..Default::default() };
+        let mut pacer = Pacer::new(ms(2), Some(adaptive), &timeouts);
+        while pacer.rejected() {}
+        assert_eq!(pacer.current(), Pacing { byte_delay: ms(10), block_delay: ms(800) });
+
+        let adaptive = AdaptivePacing { block_step: ms(300), ..Default::default() };
+        let mut pacer = Pacer::new(ms(2), Some(adaptive), &timeouts);
+        while pacer.rejected() {}
+        assert_eq!(pacer.current(), Pacing { byte_delay: ms(5), block_delay: ms(900) });
+    }
+}
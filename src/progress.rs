@@ -0,0 +1,326 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Progress display for the command line
+//!
+//! On a terminal each file gets a progress bar, plus one for the whole
+//! batch when sending several files. The receiver can't know file sizes in
+//! advance, so it only counts bytes. When the output isn't a terminal a
+//! plain status line is printed every few seconds instead.
+
+use std::io::IsTerminal;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use filink::{Event, FileReport, Observer};
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// How often a status line is printed when the output isn't a terminal
+const LINE_INTERVAL: Duration = Duration::from_secs(5);
+
+// ============================================================================
+// Output Stream
+// ============================================================================
+
+/// Where human-readable output goes. It moves to stderr when stdout
+/// carries machine-readable output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn println(self, args: std::fmt::Arguments) {
+        match self {
+            Stream::Stdout => println!("{}", args),
+            Stream::Stderr => eprintln!("{}", args),
+        }
+    }
+
+    fn is_terminal(self) -> bool {
+        match self {
+            Stream::Stdout => std::io::stdout().is_terminal(),
+            Stream::Stderr => std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn draw_target(self) -> ProgressDrawTarget {
+        match self {
+            Stream::Stdout => ProgressDrawTarget::stdout(),
+            Stream::Stderr => ProgressDrawTarget::stderr(),
+        }
+    }
+}
+
+// ============================================================================
+// Progress Observer
+// ============================================================================
+
+/// The file being transferred
+struct Current {
+    name: String,
+    size: Option<u64>,
+    bytes: u64,
+    blocks: u32,
+    started: Instant,
+    bar: Option<ProgressBar>,
+}
+
+/// Session observer that shows progress and messages
+pub struct Progress {
+    /// Name of the other side, for "Receiver not ready"
+    peer: &'static str,
+    stream: Stream,
+    /// Set when drawing progress bars, `None` for plain lines
+    multi: Option<MultiProgress>,
+    batch: Option<ProgressBar>,
+    /// Bytes of the batch in files already finished or skipped
+    batch_done: u64,
+    /// Files in the batch, and the number of the current one
+    batch_files: usize,
+    file_number: usize,
+    current: Option<Current>,
+    last_line: Instant,
+}
+
+impl Progress {
+    /// Progress of sending a batch of files with these sizes
+    pub fn sender(sizes: &[u64], stream: Stream) -> Self {
+        let mut progress = Progress::new("Receiver", stream);
+        if sizes.len() > 1
+            && let Some(multi) = &progress.multi
+        {
+            let bar = multi.add(ProgressBar::new(sizes.iter().sum()));
+            bar.set_style(style("{prefix:>12} [{bar:30}] {bytes}/{total_bytes}  {msg}  ETA {eta}"));
+            bar.set_prefix("Total");
+            progress.batch = Some(bar);
+        }
+        progress.batch_files = sizes.len();
+        progress
+    }
+
+    /// Progress of receiving files
+    pub fn receiver(stream: Stream) -> Self {
+        Progress::new("Sender", stream)
+    }
+
+    fn new(peer: &'static str, stream: Stream) -> Self {
+        let multi = stream
+            .is_terminal()
+            .then(|| MultiProgress::with_draw_target(stream.draw_target()));
+        Progress {
+            peer,
+            stream,
+            multi,
+            batch: None,
+            batch_done: 0,
+            batch_files: 0,
+            file_number: 0,
+            current: None,
+            last_line: Instant::now(),
+        }
+    }
+
+    /// Print a line above the progress bars
+    fn print(&self, line: &str) {
+        match &self.multi {
+            Some(multi) => multi.suspend(|| self.stream.println(format_args!("{}", line))),
+            None => self.stream.println(format_args!("{}", line)),
+        }
+    }
+
+    fn start_file(&mut self, name: &str, size: Option<u64>) {
+        self.file_number += 1;
+        let bar = self.multi.as_ref().map(|multi| {
+            let bar = match size {
+                Some(size) => {
+                    let bar = ProgressBar::new(size);
+                    bar.set_style(style("{prefix:>12} [{bar:30}] {bytes}/{total_bytes}  {msg}  ETA {eta}"));
+                    bar
+                }
+                None => {
+                    let bar = ProgressBar::new_spinner();
+                    bar.set_style(style("{prefix:>12} {spinner} {bytes}  {msg}"));
+                    bar
+                }
+            };
+            bar.set_prefix(name.to_string());
+            match &self.batch {
+                Some(batch) => {
+                    batch.set_message(format!("file {} of {}", self.file_number, self.batch_files));
+                    multi.insert_before(batch, bar)
+                }
+                None => multi.add(bar),
+            }
+        });
+
+        if self.multi.is_none() {
+            self.stream.println(format_args!("{}: started", name));
+        }
+        self.last_line = Instant::now();
+        self.current = Some(Current {
+            name: name.to_string(),
+            size,
+            bytes: 0,
+            blocks: 0,
+            started: Instant::now(),
+            bar,
+        });
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        let Some(current) = &mut self.current else { return };
+        current.bytes = bytes;
+        current.blocks += 1;
+        let shown = current.size.map_or(bytes, |size| bytes.min(size));
+        let elapsed = current.started.elapsed();
+
+        if let Some(bar) = &current.bar {
+            bar.set_position(shown);
+            bar.set_message(format!("{} blocks  {} cps", current.blocks, cps(bytes, elapsed)));
+        }
+        if let Some(batch) = &self.batch {
+            batch.set_position(self.batch_done + shown);
+        }
+
+        if self.multi.is_none() && self.last_line.elapsed() >= LINE_INTERVAL {
+            self.stream.println(format_args!("{}", status_line(&current.name, bytes, current.size, current.blocks, elapsed)));
+            self.last_line = Instant::now();
+        }
+    }
+
+    fn finish_file(&mut self, file: &FileReport) {
+        let Some(current) = self.current.take() else { return };
+        if let Some(bar) = &current.bar {
+            bar.finish_and_clear();
+        }
+        self.batch_done += current.size.unwrap_or(file.bytes);
+        if let Some(batch) = &self.batch {
+            batch.set_position(self.batch_done);
+        }
+
+        self.print(&format!(
+            "{}: {} bytes, {} blocks in {} ({} cps)",
+            current.name, file.bytes, file.blocks, HumanDuration(file.elapsed), file.cps() as u64
+        ));
+    }
+
+    /// A file the receiver refused no longer counts towards the batch
+    fn skip_file(&mut self, path: &Path) {
+        self.file_number += 1;
+        if let Some(batch) = &self.batch {
+            let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            batch.set_length(batch.length().unwrap_or(0).saturating_sub(size));
+        }
+    }
+
+    /// Remove the bar of a file that won't be finished
+    fn drop_file(&mut self) {
+        if let Some(bar) = self.current.take().and_then(|current| current.bar) {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+impl Observer for Progress {
+    fn on_event(&mut self, event: &Event) {
+        match event {
+            Event::HandshakeAttempt { attempt } if *attempt > 1 => self.print(&format!("{} not ready", self.peer)),
+            Event::FileStarted { name, size, .. } => self.start_file(name, *size),
+            Event::BlockSent { bytes, .. } | Event::BlockReceived { bytes, .. } => self.advance(*bytes),
+            Event::FileCompleted(file) => self.finish_file(file),
+            Event::FileRejected { name, path, reason } => {
+                if let Some(path) = path {
+                    self.skip_file(path);
+                }
+                let name = path.as_ref().map_or(name.clone(), |p| p.display().to_string());
+                self.print(&format!("{}: {}, skipping", name, reason));
+            }
+            Event::NameConflict { name, note } => self.print(&format!("{}: {}", name, note)),
+            Event::FileAbandoned { path, kept } => {
+                self.drop_file();
+                match kept {
+                    Some(part) => self.print(&format!("Incomplete file {} kept as {}", path.display(), part.display())),
+                    None => self.print(&format!("Incomplete file {} removed", path.display())),
+                }
+            }
+            Event::SessionEnded(_) => {
+                if let Some(batch) = self.batch.take() {
+                    batch.finish_and_clear();
+                }
+            }
+            Event::Trace(line) => self.print(line),
+            _ => {}
+        }
+    }
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("progress template is valid")
+        .progress_chars("=> ")
+}
+
+/// Effective characters per second
+fn cps(bytes: u64, elapsed: Duration) -> u64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 { (bytes as f64 / secs) as u64 } else { 0 }
+}
+
+/// One line of progress for output that isn't a terminal
+fn status_line(name: &str, bytes: u64, size: Option<u64>, blocks: u32, elapsed: Duration) -> String {
+    let rate = cps(bytes, elapsed);
+    match size {
+        Some(size) => {
+            let eta = match rate {
+                0 => "unknown".to_string(),
+                rate => HumanDuration(Duration::from_secs(size.saturating_sub(bytes) / rate)).to_string(),
+            };
+            format!("{}: {}/{} bytes, {} blocks, {} cps, ETA {}", name, bytes, size, blocks, rate, eta)
+        }
+        None => format!("{}: {} bytes, {} blocks, {} cps", name, bytes, blocks, rate),
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_line() {
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(
+            status_line("NOTES.TXT", 1280, Some(2560), 10, elapsed),
+            "NOTES.TXT: 1280/2560 bytes, 10 blocks, 128 cps, ETA 10 seconds"
+        );
+        assert_eq!(
+            status_line("NOTES.TXT", 1280, None, 10, elapsed),
+            "NOTES.TXT: 1280 bytes, 10 blocks, 128 cps"
+        );
+        assert_eq!(
+            status_line("NOTES.TXT", 0, Some(2560), 0, Duration::ZERO),
+            "NOTES.TXT: 0/2560 bytes, 0 blocks, 0 cps, ETA unknown"
+        );
+    }
+}
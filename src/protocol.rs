@@ -57,3 +57,23 @@ pub const RECEIVER_READY: u8 = b'S';
 
 /// Error - abort due to protocol violation or unexpected character
 pub const ERROR: u8 = b'X';
+
+/// Most bytes discarded while waiting for a noisy line to go quiet. A line
+/// still busy after two blocks' worth isn't going to settle.
+pub const MAX_DRAIN: usize = 256;
+
+/// Name of a byte as it appears in messages: control characters by their
+/// ASCII name, printable characters quoted, anything else in hex
+pub fn byte_name(b: u8) -> String {
+    match b {
+        STX => "STX".to_string(),
+        ETX => "ETX".to_string(),
+        EOT => "EOT".to_string(),
+        ENQ => "ENQ".to_string(),
+        BS => "BS".to_string(),
+        TAB => "TAB".to_string(),
+        XOFF => "XOFF".to_string(),
+        0x20..=0x7E => format!("'{}'", b as char),
+        _ => format!("0x{:02X}", b),
+    }
+}
@@ -15,28 +15,53 @@
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
 use std::marker::PhantomData;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::clock::{Clock, SharedClock};
+use crate::observer::{trace, Event, Observer, Observers};
 use crate::serial::SerialPort;
 use crate::protocol::*;
+use crate::retry::RetryPolicy;
+use crate::timeouts::Timeouts;
+use crate::output::{PartialFile, TrimRules};
+use crate::sender::display_filename;
+use crate::session::{FileReport, Outcome, SessionReport, SharedReport, Step};
+use crate::text::ModeRules;
 
 // ============================================================================
 // Error Types
 // ============================================================================
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ReceiverError {
+    /// The serial port failed
     Io(std::io::Error),
-    TransferComplete,
+    /// The sender didn't send anything in time
+    Timeout { state: &'static str, waited: Duration },
+    /// The sender kept sending blocks with a bad checksum, or bytes that
+    /// made no sense
+    RetriesExhausted { state: &'static str, attempts: u32 },
+    /// A received file couldn't be written to disk
+    LocalFile { path: PathBuf, source: std::io::Error },
+    /// The session was stopped by the user
+    Cancelled,
 }
 
 impl std::fmt::Display for ReceiverError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ReceiverError::Io(e) => write!(f, "I/O error: {}", e),
-            ReceiverError::TransferComplete => write!(f, "Transfer complete"),
+            ReceiverError::Timeout { state, waited } => {
+                write!(f, "Sender not responding (waited {:.1}s in state: {})", waited.as_secs_f32(), state)
+            }
+            ReceiverError::RetriesExhausted { state, attempts } => {
+                write!(f, "Giving up after {} attempts in state: {}", attempts, state)
+            }
+            ReceiverError::LocalFile { path, source } => write!(f, "{}: {}", path.display(), source),
+            ReceiverError::Cancelled => write!(f, "Cancelled"),
         }
     }
 }
@@ -45,6 +70,17 @@ impl std::error::Error for ReceiverError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             ReceiverError::Io(e) => Some(e),
+            ReceiverError::LocalFile { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl ReceiverError {
+    /// The state the session failed in, where that is known
+    pub fn state(&self) -> Option<&'static str> {
+        match self {
+            ReceiverError::Timeout { state, .. } | ReceiverError::RetriesExhausted { state, .. } => Some(state),
             _ => None,
         }
     }
@@ -56,33 +92,90 @@ impl From<std::io::Error> for ReceiverError {
     }
 }
 
+// ============================================================================
+// Options
+// ============================================================================
+
+/// What to do when a received file's name is already taken in the output
+/// directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file
+    Overwrite,
+    /// Refuse the file with 'X' so the sender moves on to the next one
+    Skip,
+    /// Save the new file under a free name such as `notes~1.txt`
+    #[default]
+    Rename,
+    /// Keep the existing file as a numbered backup such as `notes.txt.~1~`
+    Backup,
+}
+
+/// Receiver settings that don't change during a session
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReceiverOptions {
+    pub on_conflict: ConflictPolicy,
+    /// Keep the `.part` file of a transfer that didn't finish
+    pub keep_partial: bool,
+    /// Force each completed file to disk before acknowledging the next one
+    pub fsync: bool,
+    /// How to remove the 0x1A padding from the end of each binary file
+    pub trim: TrimRules,
+    /// Which files are converted from CP/M text conventions
+    pub modes: ModeRules,
+    /// When to give up on a silent or noisy line
+    pub retry: RetryPolicy,
+    /// How long to wait for each byte from the sender
+    pub timeouts: Timeouts,
+    /// Set from another thread to stop the session at the next read
+    pub cancel: Arc<AtomicBool>,
+    /// Time source for timestamps and pauses
+    pub clock: SharedClock,
+    /// Where the session keeps its report, so it can still be read after
+    /// the session failed
+    pub report: SharedReport,
+}
+
 // ============================================================================
 // States
 // ============================================================================
 
-pub struct InitialHandshake;
-pub struct WaitGood;
-pub struct WaitFileOrEnd;
-pub struct ReceiveFilename;
-pub struct EndFilename;
-pub struct WaitBlockOrEOF;
-pub struct ReceiveBlock;
-pub struct VerifyChecksum;
+pub(crate) struct InitialHandshake;
+pub(crate) struct WaitGood;
+pub(crate) struct WaitFileOrEnd;
+pub(crate) struct ReceiveFilename;
+pub(crate) struct EndFilename;
+pub(crate) struct WaitBlockOrEOF;
+pub(crate) struct ReceiveBlock;
+pub(crate) struct VerifyChecksum;
 
 // ============================================================================
 // FSM Structure
 // ============================================================================
 
-pub struct ReceiverFsm<State> {
+pub(crate) struct ReceiverFsm<State> {
     state: PhantomData<State>,
     serial: Box<dyn SerialPort>,
     output_dir: PathBuf,
-    current_file: Option<File>,
+    options: ReceiverOptions,
+    current_file: Option<PartialFile>,
     filename_buffer: [u8; 11],
     filename_idx: usize,
     block_buffer: [u8; 128],
     bytes_received: usize,
     checksum: u8,
+    last_block: [u8; 128],
+    resync: bool,
+    last_sent: Option<u8>,
+    retries: u32,
+    strays: u32,
+    block_index: u32,
+    file_bytes: u64,
+    file_retries: u32,
+    file_started: Instant,
+    observer: Observers,
+    handshake_attempts: u32,
+    started: Instant,
     debug: bool,
 }
 
@@ -90,8 +183,9 @@ pub struct ReceiverFsm<State> {
 // Trait
 // ============================================================================
 
-pub trait ReceiverState: Send {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError>;
+pub(crate) trait ReceiverState: Send {
+    /// Run one state. Returns `Step::Done` once the session is complete.
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError>;
 }
 
 // ============================================================================
@@ -99,28 +193,204 @@ pub trait ReceiverState: Send {
 // ============================================================================
 
 impl<S> ReceiverFsm<S> {
-    fn transition<T>(self) -> Box<ReceiverFsm<T>> {
+    fn transition<T>(mut self) -> Box<ReceiverFsm<T>> {
+        self.serial.enter_state(ReceiverFsm::<T>::state_name());
         Box::new(ReceiverFsm {
             state: PhantomData,
             serial: self.serial,
             output_dir: self.output_dir,
+            options: self.options,
             current_file: self.current_file,
             filename_buffer: self.filename_buffer,
             filename_idx: self.filename_idx,
             block_buffer: self.block_buffer,
             bytes_received: self.bytes_received,
             checksum: self.checksum,
+            last_block: self.last_block,
+            resync: self.resync,
+            last_sent: self.last_sent,
+            retries: self.retries,
+            strays: 0,
+            block_index: self.block_index,
+            file_bytes: self.file_bytes,
+            file_retries: self.file_retries,
+            file_started: self.file_started,
+            observer: self.observer,
+            handshake_attempts: self.handshake_attempts,
+            started: self.started,
             debug: self.debug,
         })
     }
 
-    fn io_error(&self, e: std::io::Error) -> ReceiverError {
+    fn state_name() -> &'static str {
         let type_name = std::any::type_name::<S>();
-        let state_name = type_name.split("::").last().unwrap_or(type_name);
-        ReceiverError::Io(std::io::Error::new(
+        type_name.split("::").last().unwrap_or(type_name)
+    }
+
+    /// Abort the session with `err`. Any file still being received is
+    /// abandoned and reported.
+    fn fail(&mut self, err: ReceiverError) -> ReceiverError {
+        if let Some(file) = self.current_file.take() {
+            let path = file.final_path().to_path_buf();
+            let kept = file.abandon();
+            self.notify(Event::FileAbandoned { path, kept });
+        }
+        err
+    }
+
+    fn io_error(&mut self, e: std::io::Error) -> ReceiverError {
+        let err = ReceiverError::Io(std::io::Error::new(
             e.kind(),
-            format!("{} (in state: {})", e, state_name)
-        ))
+            format!("{} (in state: {})", e, Self::state_name())
+        ));
+        self.fail(err)
+    }
+
+    /// Read one byte from the sender, waiting at most `timeout`
+    fn read_byte(&mut self, timeout: Duration) -> Result<u8, ReceiverError> {
+        if self.options.cancel.load(Ordering::SeqCst) {
+            return Err(ReceiverError::Cancelled);
+        }
+
+        let mut buf = [0u8; 1];
+        match self.serial.read_timeout(&mut buf, timeout) {
+            Ok(1..) => Ok(buf[0]),
+            Ok(0) => Err(ReceiverError::Timeout { state: Self::state_name(), waited: timeout }),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                Err(ReceiverError::Timeout { state: Self::state_name(), waited: timeout })
+            }
+            Err(e) => Err(self.io_error(e)),
+        }
+    }
+
+    /// Read the next byte from the sender. On a timeout `resend` is written
+    /// again, if given, and the wait repeated as often as the retry policy
+    /// allows; after that the session is aborted.
+    fn read_reply(&mut self, timeout: Duration, resend: Option<u8>) -> Result<u8, ReceiverError> {
+        let mut timeouts = 0;
+        loop {
+            match self.read_byte(timeout) {
+                Ok(byte) => return Ok(byte),
+                Err(ReceiverError::Timeout { .. }) if timeouts < self.options.retry.timeout_retries => {
+                    timeouts += 1;
+                    if let Some(byte) = resend {
+                        trace!(self, "Timeout, resending {}", byte_name(byte));
+                        self.send(byte)?;
+                    }
+                }
+                Err(ReceiverError::Timeout { state, .. }) => {
+                    return Err(self.fail(ReceiverError::Timeout { state, waited: timeout * (timeouts + 1) }));
+                }
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+    }
+
+    /// Discard whatever is still arriving until the line goes quiet, so
+    /// stray bytes that shifted a block aren't read as control bytes. Gives
+    /// up after `MAX_DRAIN` bytes on a line that never does.
+    fn drain(&mut self) -> Result<(), ReceiverError> {
+        let mut discarded = 0;
+        while discarded < MAX_DRAIN {
+            match self.read_byte(self.options.timeouts.data_byte) {
+                Ok(_) => discarded += 1,
+                Err(ReceiverError::Timeout { .. }) => break,
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+        if discarded > 0 { trace!(self, "Discarded {} stray bytes", discarded); }
+        Ok(())
+    }
+
+    /// Count another failed attempt at the current block
+    fn retry(&mut self) -> Result<(), ReceiverError> {
+        self.retries += 1;
+        if self.options.retry.retries_exhausted(self.retries) {
+            let attempts = self.retries;
+            return Err(self.fail(ReceiverError::RetriesExhausted { state: Self::state_name(), attempts }));
+        }
+        Ok(())
+    }
+
+    /// Count a byte that made no sense in the current state, aborting once
+    /// there have been more than the retry policy tolerates
+    fn stray(&mut self) -> Result<(), ReceiverError> {
+        self.strays += 1;
+        if self.options.retry.strays_exhausted(self.strays) {
+            let attempts = self.strays;
+            return Err(self.fail(ReceiverError::RetriesExhausted { state: Self::state_name(), attempts }));
+        }
+        Ok(())
+    }
+
+    /// Save the file being received under its real name
+    fn commit_file(&mut self) -> Result<(), ReceiverError> {
+        if let Some(file) = self.current_file.take() {
+            let path = file.final_path().to_path_buf();
+            match file.commit(self.options.fsync) {
+                Ok(path) => {
+                    trace!(self, "Saved file: {:?}", path);
+                    let bytes = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                    let file = self.file_report(path, Outcome::Completed).with_bytes(bytes);
+                    self.notify(Event::FileCompleted(file.clone()));
+                    self.options.report.update_last(file);
+                }
+                Err(source) => return Err(ReceiverError::LocalFile { path, source }),
+            }
+        }
+        Ok(())
+    }
+
+    /// The report entry of the file being received, as it stands
+    fn file_report(&self, path: PathBuf, outcome: Outcome) -> FileReport {
+        FileReport::new(path, display_filename(&self.filename_buffer), outcome)
+            .with_bytes(self.file_bytes)
+            .with_blocks(self.block_index)
+            .with_retries(self.file_retries)
+            .with_elapsed(self.options.clock.since(self.file_started))
+    }
+
+    /// Bring the report entry of the file being received up to date
+    fn record_progress(&self) {
+        if let Some(file) = &self.current_file {
+            let path = file.final_path().to_path_buf();
+            self.options.report.update_last(self.file_report(path, Outcome::Incomplete));
+        }
+    }
+
+    /// Refuse the file just offered with 'X'. The sender offers a refused
+    /// file a few times, but it is only reported once.
+    fn reject_file(&mut self, path: Option<PathBuf>, reason: &str) -> Result<(), ReceiverError> {
+        let name = display_filename(&self.filename_buffer);
+        let repeated = self.options.report.lock().files.last().is_some_and(|f| f.outcome == Outcome::Rejected && f.name == name);
+        if !repeated {
+            let local = path.clone().unwrap_or_else(|| PathBuf::from(&name));
+            self.options.report.lock().files.push(FileReport::rejected(local, name.clone()));
+            self.notify(Event::FileRejected { name, path, reason: reason.to_string() });
+        }
+        self.send(ERROR)
+    }
+
+    fn notify(&mut self, event: Event) {
+        self.observer.on_event(&event);
+    }
+
+    /// The sender stopped partway through a block, most likely because it
+    /// never saw our 'P'. Drop what arrived and answer 'N' so it sends STX
+    /// again.
+    fn restart_block(mut self) -> Result<Step<dyn ReceiverState>, ReceiverError> {
+        trace!(self, "Sender stopped after {} bytes, sending 'N'", self.bytes_received);
+        self.retry()?;
+        self.send(NAK)?;
+        let next = self.transition::<WaitBlockOrEOF>();
+        Ok(Step::Next(next as Box<dyn ReceiverState>))
+    }
+
+    /// Write a control byte to the sender
+    fn send(&mut self, byte: u8) -> Result<(), ReceiverError> {
+        self.serial.write_all(&[byte]).map_err(|e| self.io_error(e))?;
+        self.last_sent = Some(byte);
+        Ok(())
     }
 }
 
@@ -129,256 +399,376 @@ impl<S> ReceiverFsm<S> {
 // ============================================================================
 
 impl ReceiverState for ReceiverFsm<InitialHandshake> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
+        fsm.notify(Event::HandshakeAttempt { attempt: fsm.handshake_attempts + 1 });
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(5)) {
-            Ok(_) if buf[0] == SENDER_READY => {
-                if fsm.debug { println!("Received: 'R'"); }
+        match fsm.read_byte(fsm.options.timeouts.handshake) {
+            Ok(SENDER_READY) => {
+                trace!(fsm, "Received: 'R'");
+                fsm.notify(Event::HandshakeCompleted);
 
-                fsm.serial.write_all(&[RECEIVER_READY])?;
-                if fsm.debug { println!("Sent: 'S'"); }
+                fsm.send(RECEIVER_READY)?;
+                trace!(fsm, "Sent: 'S'");
 
                 let next = fsm.transition::<WaitGood>();
-                Ok(next as Box<dyn ReceiverState>)
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
             }
-            Err(e) if e.kind() != std::io::ErrorKind::TimedOut => Err(fsm.io_error(e)),
-            _ => {
-                println!("Sender not ready");
-                Ok(Box::new(fsm) as Box<dyn ReceiverState>)
+            Ok(_) | Err(ReceiverError::Timeout { .. }) => {
+                fsm.handshake_attempts += 1;
+                let elapsed = fsm.options.clock.since(fsm.started);
+                if fsm.options.retry.handshake_expired(fsm.handshake_attempts, elapsed) {
+                    return Err(ReceiverError::Timeout { state: Self::state_name(), waited: elapsed });
+                }
+                Ok(Step::Next(Box::new(fsm) as Box<dyn ReceiverState>))
             }
+            Err(e) => Err(e),
         }
     }
 }
 
 impl ReceiverState for ReceiverFsm<WaitGood> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == GOOD => {
-                if fsm.debug { println!("Received: 'G'"); }
+        match fsm.read_reply(fsm.options.timeouts.control, Some(RECEIVER_READY))? {
+            GOOD => {
+                trace!(fsm, "Received: 'G'");
                 let next = fsm.transition::<WaitFileOrEnd>();
-                Ok(next as Box<dyn ReceiverState>)
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
             }
-            Err(e) => Err(fsm.io_error(e)),
-            Ok(_) => {
-                if fsm.debug { println!("Wrong character, waiting for 'G'..."); }
-                Ok(Box::new(fsm) as Box<dyn ReceiverState>)
+            // The sender never saw our 'S' and is still handshaking
+            SENDER_READY => {
+                trace!(fsm, "Received: 'R' again");
+                fsm.send(RECEIVER_READY)?;
+                trace!(fsm, "Sent: 'S'");
+                Ok(Step::Next(Box::new(fsm) as Box<dyn ReceiverState>))
+            }
+            // The sender has moved on, so its 'G' was lost
+            EOT => {
+                trace!(fsm, "Received: EOT ('G' lost)");
+
+                fsm.send(BS)?;
+                trace!(fsm, "Sent: BS");
+
+                fsm.filename_idx = 0;
+                let next = fsm.transition::<ReceiveFilename>();
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
+            }
+            _ => {
+                fsm.stray()?;
+                trace!(fsm, "Wrong character, waiting for 'G'...");
+                Ok(Step::Next(Box::new(fsm) as Box<dyn ReceiverState>))
             }
         }
     }
 }
 
 impl ReceiverState for ReceiverFsm<WaitFileOrEnd> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == EOT => {
-                if fsm.debug { println!("Received: EOT"); }
+        match fsm.read_reply(fsm.options.timeouts.control, None)? {
+            EOT => {
+                trace!(fsm, "Received: EOT");
 
-                fsm.serial.write_all(&[BS])?;
-                if fsm.debug { println!("Sent: BS"); }
+                fsm.send(BS)?;
+                trace!(fsm, "Sent: BS");
 
                 fsm.filename_idx = 0;
                 let next = fsm.transition::<ReceiveFilename>();
-                Ok(next as Box<dyn ReceiverState>)
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
             }
-            Ok(_) if buf[0] == XOFF => {
-                if fsm.debug { println!("Received: XOFF (All transfers complete)"); }
-                Err(ReceiverError::TransferComplete)
+            XOFF => {
+                trace!(fsm, "Received: XOFF (All transfers complete)");
+                let report = {
+                    let mut report = fsm.options.report.lock();
+                    report.elapsed = fsm.options.clock.since(fsm.started);
+                    report.clone()
+                };
+                fsm.notify(Event::SessionEnded(report.clone()));
+                Ok(Step::Done(report))
             }
-            Ok(_) => {
-                if fsm.debug { println!("Received invalid char, sending 'X'"); }
-                fsm.serial.write_all(&[ERROR])?;
-                Ok(Box::new(fsm) as Box<dyn ReceiverState>)
+            _ => {
+                fsm.stray()?;
+                trace!(fsm, "Received invalid char, sending 'X'");
+                fsm.send(ERROR)?;
+                Ok(Step::Next(Box::new(fsm) as Box<dyn ReceiverState>))
             }
-            Err(e) => Err(fsm.io_error(e))
         }
     }
 }
 
 impl ReceiverState for ReceiverFsm<ReceiveFilename> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) => {
-                let ch = buf[0];
-
-                if ch < 0x20 {
-                    fsm.serial.write_all(&[ERROR])?;
-                    if fsm.debug { println!("Invalid filename character (< 0x20), sending 'X'"); }
-                    fsm.filename_idx = 0;
-                    let next = fsm.transition::<WaitFileOrEnd>();
-                    return Ok(next as Box<dyn ReceiverState>);
-                }
+        // Only BS is worth sending again; an echoed character would be
+        // taken by the sender as the echo of the next one
+        let resend = if fsm.filename_idx == 0 { fsm.last_sent } else { None };
+        let ch = fsm.read_reply(fsm.options.timeouts.control, resend)?;
+
+        // CP/M keeps file attributes (R/O, SYS, archive) in the high bits of
+        // the name, so they are echoed but not part of the name
+        if !is_filename_byte(ch & 0x7F) {
+            fsm.send(ERROR)?;
+            trace!(fsm, "Invalid filename character (0x{:02X}), sending 'X'", ch);
+            fsm.filename_idx = 0;
+            let next = fsm.transition::<WaitFileOrEnd>();
+            return Ok(Step::Next(next as Box<dyn ReceiverState>));
+        }
 
-                fsm.filename_buffer[fsm.filename_idx] = ch;
+        fsm.filename_buffer[fsm.filename_idx] = ch & 0x7F;
 
-                fsm.serial.write_all(&[ch])?;
-                if fsm.debug {
-                    println!("Received filename char[{}]: '{}' - Echoed", fsm.filename_idx, ch as char);
-                }
+        fsm.send(ch)?;
+        trace!(fsm, "Received filename char[{}]: '{}' - Echoed", fsm.filename_idx, (ch & 0x7F) as char);
 
-                fsm.filename_idx += 1;
+        fsm.filename_idx += 1;
 
-                if fsm.filename_idx >= 11 {
-                    let next = fsm.transition::<EndFilename>();
-                    Ok(next as Box<dyn ReceiverState>)
-                } else {
-                    Ok(Box::new(fsm) as Box<dyn ReceiverState>)
-                }
-            }
-            Err(e) => Err(fsm.io_error(e))
+        if fsm.filename_idx >= 11 {
+            let next = fsm.transition::<EndFilename>();
+            Ok(Step::Next(next as Box<dyn ReceiverState>))
+        } else {
+            Ok(Step::Next(Box::new(fsm) as Box<dyn ReceiverState>))
         }
     }
 }
 
 impl ReceiverState for ReceiverFsm<EndFilename> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == ENQ => {
-                if fsm.debug { println!("Received: ENQ"); }
+        match fsm.read_reply(fsm.options.timeouts.control, None)? {
+            ENQ => {
+                trace!(fsm, "Received: ENQ");
 
-                let filename = parse_filename(&fsm.filename_buffer);
-                let filepath = fsm.output_dir.join(&filename);
-
-                match File::create(&filepath) {
-                    Ok(file) => {
-                        if fsm.debug { println!("Created file: {:?}", filepath); }
+                let filepath = match output_path(&fsm.output_dir, &fsm.filename_buffer) {
+                    Some(path) => path,
+                    None => {
+                        trace!(fsm, "Rejected filename {:02X?}, sending 'X'", fsm.filename_buffer);
+                        fsm.reject_file(None, "invalid name")?;
+                        fsm.filename_idx = 0;
+                        let next = fsm.transition::<WaitFileOrEnd>();
+                        return Ok(Step::Next(next as Box<dyn ReceiverState>));
+                    }
+                };
+
+                let name = filepath.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                let created = resolve_conflict(&filepath, fsm.options.on_conflict)
+                    .and_then(|resolution| match resolution {
+                        Resolution::Write { path, backup, note } => {
+                            let mode = fsm.options.modes.mode_for(&path);
+                            let trim = fsm.options.trim.mode_for(&path);
+                            PartialFile::create(path, backup, mode, trim, fsm.options.keep_partial)
+                                .map(|file| Some((file, note)))
+                        }
+                        Resolution::Skip => Ok(None),
+                    });
+
+                match created {
+                    Ok(Some((file, note))) => {
+                        trace!(fsm, "Created file: {:?}", file.final_path());
+                        if let Some(note) = note {
+                            fsm.notify(Event::NameConflict { name, note });
+                        }
+                        let path = file.final_path().to_path_buf();
+                        let started = Event::FileStarted {
+                            path: path.clone(),
+                            name: display_filename(&fsm.filename_buffer),
+                            size: None,
+                        };
+                        fsm.notify(started);
                         fsm.current_file = Some(file);
+                        fsm.block_index = 0;
+                        fsm.file_bytes = 0;
+                        fsm.file_retries = 0;
+                        fsm.file_started = fsm.options.clock.now();
+                        fsm.resync = false;
+                        let entry = fsm.file_report(path, Outcome::Incomplete);
+                        fsm.options.report.lock().files.push(entry);
 
-                        fsm.serial.write_all(&[TAB])?;
-                        if fsm.debug { println!("Sent: TAB"); }
+                        fsm.send(TAB)?;
+                        trace!(fsm, "Sent: TAB");
 
                         let next = fsm.transition::<WaitBlockOrEOF>();
-                        Ok(next as Box<dyn ReceiverState>)
+                        Ok(Step::Next(next as Box<dyn ReceiverState>))
+                    }
+                    Ok(None) => {
+                        fsm.reject_file(Some(filepath), "already exists")?;
+                        fsm.filename_idx = 0;
+                        let next = fsm.transition::<WaitFileOrEnd>();
+                        Ok(Step::Next(next as Box<dyn ReceiverState>))
                     }
                     Err(e) => {
-                        if fsm.debug { println!("Failed to create file: {}", e); }
-                        fsm.serial.write_all(&[ERROR])?;
+                        trace!(fsm, "Failed to create file: {}", e);
+                        fsm.reject_file(Some(filepath), &e.to_string())?;
                         fsm.filename_idx = 0;
                         let next = fsm.transition::<WaitFileOrEnd>();
-                        Ok(next as Box<dyn ReceiverState>)
+                        Ok(Step::Next(next as Box<dyn ReceiverState>))
                     }
                 }
             }
-            Ok(_) => {
-                if fsm.debug { println!("Expected ENQ, sending 'X'"); }
-                fsm.serial.write_all(&[ERROR])?;
+            _ => {
+                trace!(fsm, "Expected ENQ, sending 'X'");
+                fsm.send(ERROR)?;
                 fsm.filename_idx = 0;
                 let next = fsm.transition::<WaitFileOrEnd>();
-                Ok(next as Box<dyn ReceiverState>)
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
             }
-            Err(e) => Err(fsm.io_error(e))
         }
     }
 }
 
 impl ReceiverState for ReceiverFsm<WaitBlockOrEOF> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == STX => {
-                if fsm.debug { println!("Received: STX"); }
+        let last_sent = fsm.last_sent;
+        match fsm.read_reply(fsm.options.timeouts.control, last_sent)? {
+            STX => {
+                trace!(fsm, "Received: STX");
 
-                fsm.serial.write_all(&[PROCEED])?;
-                if fsm.debug { println!("Sent: 'P'"); }
+                fsm.send(PROCEED)?;
+                trace!(fsm, "Sent: 'P'");
 
                 fsm.bytes_received = 0;
                 fsm.checksum = 0;
                 let next = fsm.transition::<ReceiveBlock>();
-                Ok(next as Box<dyn ReceiverState>)
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
+            }
+            ETX => {
+                trace!(fsm, "Received: ETX (End of file)");
+                fsm.commit_file()?;
+                let next = fsm.transition::<WaitFileOrEnd>();
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
             }
-            Ok(_) if buf[0] == ETX => {
-                if fsm.debug { println!("Received: ETX (End of file)"); }
+            // The sender only moves on to the next file or the end of the
+            // session after sending ETX, so that must have been lost
+            EOT => {
+                trace!(fsm, "Received: EOT (ETX lost)");
+                fsm.commit_file()?;
 
-                fsm.current_file = None;
+                fsm.send(BS)?;
+                trace!(fsm, "Sent: BS");
 
-                let next = fsm.transition::<WaitFileOrEnd>();
-                Ok(next as Box<dyn ReceiverState>)
+                fsm.filename_idx = 0;
+                let next = fsm.transition::<ReceiveFilename>();
+                Ok(Step::Next(next as Box<dyn ReceiverState>))
             }
-            Ok(_) => {
-                if fsm.debug { println!("Expected STX or ETX, sending 'N'"); }
-                fsm.serial.write_all(&[NAK])?;
-                Ok(Box::new(fsm) as Box<dyn ReceiverState>)
+            XOFF => {
+                trace!(fsm, "Received: XOFF (ETX lost, all transfers complete)");
+                fsm.commit_file()?;
+                let report = {
+                    let mut report = fsm.options.report.lock();
+                    report.elapsed = fsm.options.clock.since(fsm.started);
+                    report.clone()
+                };
+                fsm.notify(Event::SessionEnded(report.clone()));
+                Ok(Step::Done(report))
+            }
+            got => {
+                fsm.stray()?;
+                // 'N' after a 'G' or 'B' is the sender asking again because
+                // the answer was garbled, so give the same answer. Should the
+                // block come round once more anyway, it is the one already
+                // saved. Anything else is noise, and the next block may well
+                // have the same contents as the last.
+                let reply = match fsm.last_sent {
+                    Some(verdict @ (GOOD | BAD)) if got == NAK => {
+                        trace!(fsm, "Received: 'N', repeating {}", byte_name(verdict));
+                        if verdict == GOOD {
+                            fsm.resync = true;
+                        }
+                        verdict
+                    }
+                    _ => {
+                        trace!(fsm, "Expected STX or ETX, sending 'N'");
+                        NAK
+                    }
+                };
+                fsm.send(reply)?;
+                Ok(Step::Next(Box::new(fsm) as Box<dyn ReceiverState>))
             }
-            Err(e) => Err(fsm.io_error(e))
         }
     }
 }
 
 impl ReceiverState for ReceiverFsm<ReceiveBlock> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
 
         while fsm.bytes_received < 128 {
-            let mut buf = [0u8; 1];
-            match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-                Ok(_) => {
-                    let byte = buf[0];
+            match fsm.read_byte(fsm.options.timeouts.data_byte) {
+                Ok(byte) => {
                     fsm.block_buffer[fsm.bytes_received] = byte;
                     fsm.checksum ^= byte;
                     fsm.bytes_received += 1;
                 }
-                Err(e) => return Err(fsm.io_error(e))
+                Err(ReceiverError::Timeout { .. }) => return fsm.restart_block(),
+                Err(e) => return Err(fsm.fail(e)),
             }
         }
 
-        if fsm.debug { println!("Received: 128 byte block"); }
+        trace!(fsm, "Received: 128 byte block");
 
         let next = fsm.transition::<VerifyChecksum>();
-        Ok(next as Box<dyn ReceiverState>)
+        Ok(Step::Next(next as Box<dyn ReceiverState>))
     }
 }
 
 impl ReceiverState for ReceiverFsm<VerifyChecksum> {
-    fn step(self: Box<Self>) -> Result<Box<dyn ReceiverState>, ReceiverError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn ReceiverState>, ReceiverError> {
         let mut fsm = *self;
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) => {
-                let received_checksum = buf[0];
-                if fsm.debug {
-                    println!("Received: Checksum 0x{:02X}, Expected: 0x{:02X}",
-                             received_checksum, fsm.checksum);
-                }
-
-                if received_checksum == fsm.checksum {
-                    if fsm.debug { println!("Checksum OK"); }
-
-                    if let Some(ref mut file) = fsm.current_file {
-                        file.write_all(&fsm.block_buffer)?;
-                    }
-
-                    fsm.serial.write_all(&[GOOD])?;
-                    if fsm.debug { println!("Sent: 'G'"); }
-
-                    let next = fsm.transition::<WaitBlockOrEOF>();
-                    Ok(next as Box<dyn ReceiverState>)
-                } else {
-                    if fsm.debug { println!("Checksum mismatch!"); }
-
-                    fsm.serial.write_all(&[BAD])?;
-                    if fsm.debug { println!("Sent: 'B'"); }
-
-                    let next = fsm.transition::<WaitBlockOrEOF>();
-                    Ok(next as Box<dyn ReceiverState>)
+        let received_checksum = match fsm.read_byte(fsm.options.timeouts.data_byte) {
+            Ok(byte) => byte,
+            Err(ReceiverError::Timeout { .. }) => return fsm.restart_block(),
+            Err(e) => return Err(fsm.fail(e)),
+        };
+        trace!(fsm, "Received: Checksum 0x{:02X}, Expected: 0x{:02X}", received_checksum, fsm.checksum);
+
+        if received_checksum == fsm.checksum {
+            trace!(fsm, "Checksum OK");
+            fsm.retries = 0;
+
+            if fsm.resync && fsm.block_buffer == fsm.last_block {
+                trace!(fsm, "Duplicate of the last block, already saved");
+            } else {
+                if let Some(ref mut file) = fsm.current_file
+                    && let Err(source) = file.write_block(&fsm.block_buffer)
+                {
+                    let path = file.final_path().to_path_buf();
+                    return Err(fsm.fail(ReceiverError::LocalFile { path, source }));
                 }
+                fsm.file_bytes += 128;
+                fsm.notify(Event::BlockReceived { index: fsm.block_index, bytes: fsm.file_bytes });
+                fsm.block_index += 1;
+                fsm.record_progress();
             }
-            Err(e) => Err(fsm.io_error(e))
+            fsm.resync = false;
+            fsm.last_block = fsm.block_buffer;
+
+            fsm.send(GOOD)?;
+            trace!(fsm, "Sent: 'G'");
+
+            let next = fsm.transition::<WaitBlockOrEOF>();
+            Ok(Step::Next(next as Box<dyn ReceiverState>))
+        } else {
+            trace!(fsm, "Checksum mismatch!");
+
+            // Stray bytes shift the block, so the real checksum may still
+            // be on its way
+            fsm.drain()?;
+            fsm.retry()?;
+            fsm.file_retries += 1;
+            fsm.record_progress();
+            fsm.notify(Event::ChecksumRetry { index: fsm.block_index, attempt: fsm.retries });
+
+            fsm.send(BAD)?;
+            trace!(fsm, "Sent: 'B'");
+
+            let next = fsm.transition::<WaitBlockOrEOF>();
+            Ok(Step::Next(next as Box<dyn ReceiverState>))
         }
     }
 }
@@ -388,50 +778,327 @@ impl ReceiverState for ReceiverFsm<VerifyChecksum> {
 // ============================================================================
 
 impl ReceiverFsm<InitialHandshake> {
-    pub fn new(serial: Box<dyn SerialPort>, output_dir: PathBuf, debug: bool) -> Box<dyn ReceiverState> {
+    #[allow(clippy::new_ret_no_self)]
+    pub(crate) fn new(mut serial: Box<dyn SerialPort>, output_dir: PathBuf, options: ReceiverOptions, observer: Observers, debug: bool) -> Box<dyn ReceiverState> {
+        let now = options.clock.now();
+        serial.enter_state(ReceiverFsm::<InitialHandshake>::state_name());
         Box::new(ReceiverFsm {
             state: PhantomData::<InitialHandshake>,
             serial,
             output_dir,
+            options,
             current_file: None,
             filename_buffer: [b' '; 11],
             filename_idx: 0,
             block_buffer: [0; 128],
             bytes_received: 0,
             checksum: 0,
+            last_block: [0; 128],
+            resync: false,
+            last_sent: None,
+            retries: 0,
+            strays: 0,
+            block_index: 0,
+            file_bytes: 0,
+            file_retries: 0,
+            file_started: now,
+            observer,
+            handshake_attempts: 0,
+            started: now,
             debug,
         })
     }
 }
 
+// ============================================================================
+// Session API
+// ============================================================================
+
+/// Receives files from a FILINK sender until it ends the session
+///
+/// ```no_run
+/// use filink::serial::RealSerialPort;
+/// use serialport::{DataBits, Parity, StopBits};
+///
+/// let port = RealSerialPort::open("/dev/ttyUSB0", 9600, DataBits::Eight, Parity::None, StopBits::One)?;
+/// let report = filink::Receiver::builder(port)
+///     .output("received")
+///     .run()?;
+/// for file in report.completed() {
+///     println!("{} -> {}", file.name, file.path.display());
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Receiver;
+
+impl Receiver {
+    /// Start setting up a session on `serial`
+    pub fn builder(serial: impl SerialPort + 'static) -> ReceiverBuilder {
+        ReceiverBuilder {
+            serial: Box::new(serial),
+            output_dir: PathBuf::from("."),
+            options: ReceiverOptions::default(),
+            observer: Observers::default(),
+            debug: false,
+        }
+    }
+}
+
+/// Settings for one receive session, created by [`Receiver::builder`]
+pub struct ReceiverBuilder {
+    serial: Box<dyn SerialPort>,
+    output_dir: PathBuf,
+    options: ReceiverOptions,
+    observer: Observers,
+    debug: bool,
+}
+
+impl ReceiverBuilder {
+    /// Directory to save received files in (default: the current directory)
+    pub fn output(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output_dir = dir.into();
+        self
+    }
+
+    /// What to do when a received file already exists (default: rename)
+    pub fn on_conflict(mut self, policy: ConflictPolicy) -> Self {
+        self.options.on_conflict = policy;
+        self
+    }
+
+    /// Keep the `.part` file of a transfer that didn't finish
+    pub fn keep_partial(mut self, keep: bool) -> Self {
+        self.options.keep_partial = keep;
+        self
+    }
+
+    /// Force each completed file to disk before acknowledging the next one
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.options.fsync = fsync;
+        self
+    }
+
+    /// How to remove the 0x1A padding from the end of each binary file
+    pub fn trim(mut self, trim: TrimRules) -> Self {
+        self.options.trim = trim;
+        self
+    }
+
+    /// Which files are converted from CP/M text conventions (default: none)
+    pub fn modes(mut self, modes: ModeRules) -> Self {
+        self.options.modes = modes;
+        self
+    }
+
+    /// When to give up on a silent or noisy line
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// How long to wait for each byte from the sender
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.options.timeouts = timeouts;
+        self
+    }
+
+    /// Flag that stops the session at the next read once it is set, e.g.
+    /// from a Ctrl-C handler
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.options.cancel = cancel;
+        self
+    }
+
+    /// Run the session on `clock` instead of the real time, e.g. a
+    /// [`FakeClock`](crate::clock::FakeClock) in tests
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.options.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Subscribe to the session's events. May be given more than once.
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer.push(Box::new(observer));
+        self
+    }
+
+    /// Also send [`Event::Trace`] lines describing each protocol step
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Run the session until the sender ends it. Files that were refused
+    /// are listed in the report.
+    pub fn run(self) -> Result<SessionReport, ReceiverError> {
+        let (report, result) = self.run_with_report();
+        result.map(|()| report)
+    }
+
+    /// Run the session like [`run`](Self::run), but return the report even
+    /// if the session fails. It then lists the files dealt with before the
+    /// failure, and the one cut off as [`Outcome::Incomplete`].
+    pub fn run_with_report(self) -> (SessionReport, Result<(), ReceiverError>) {
+        if !self.output_dir.is_dir() {
+            let err = ReceiverError::LocalFile {
+                source: std::io::Error::new(std::io::ErrorKind::NotFound, "Output directory not found"),
+                path: self.output_dir,
+            };
+            return (SessionReport::default(), Err(err));
+        }
+
+        let report = self.options.report.clone();
+        let clock = self.options.clock.clone();
+        let started = clock.now();
+        let mut state = ReceiverFsm::new(self.serial, self.output_dir, self.options, self.observer, self.debug);
+        loop {
+            match state.step() {
+                Ok(Step::Next(next)) => state = next,
+                Ok(Step::Done(report)) => return (report, Ok(())),
+                Err(e) => {
+                    let mut report = report.snapshot();
+                    report.elapsed = clock.since(started);
+                    return (report, Err(e));
+                }
+            }
+        }
+    }
+}
+
 // ============================================================================
 // Helper Functions
 // ============================================================================
 
-fn parse_filename(buffer: &[u8; 11]) -> String {
-    let mut result = String::new();
+/// Characters that CP/M or a common host filesystem cannot use in a name.
+/// Each one is replaced with `_` when building the local filename.
+const RESERVED_CHARS: &[u8] = b"<>,;:=?*[]/\\|\".";
+
+/// Names that Windows reserves for devices, whatever the extension
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Only printable 7-bit ASCII may appear in a filename, once the attribute
+/// bits are masked off
+fn is_filename_byte(b: u8) -> bool {
+    (0x20..0x7F).contains(&b)
+}
 
-    let name: String = buffer[0..8]
-        .iter()
-        .map(|&b| (b as char).to_lowercase().to_string())
-        .collect::<String>()
-        .trim_end()
-        .to_string();
+/// Convert an 11-byte 8.3 filename from the wire into a local filename.
+///
+/// The mapping is:
+/// - trailing space padding is removed from the name and the extension
+/// - letters are lowercased
+/// - embedded spaces and the characters in `RESERVED_CHARS` become `_`
+/// - a name that Windows reserves for a device (`CON`, `LPT1`, ...) gets a
+///   leading `_`
+///
+/// The name is rejected (`None`) if it contains control or 8-bit bytes, or
+/// if the 8-character name part is blank. Because `.`, `/` and `\` are
+/// always replaced, the result is a single path component.
+fn sanitize_filename(buffer: &[u8; 11]) -> Option<String> {
+    if !buffer.iter().all(|&b| is_filename_byte(b)) {
+        return None;
+    }
 
-    let ext: String = buffer[8..11]
-        .iter()
-        .map(|&b| (b as char).to_lowercase().to_string())
-        .collect::<String>()
-        .trim_end()
-        .to_string();
+    let map = |part: &[u8]| -> String {
+        let trimmed = part.iter().rposition(|&b| b != b' ').map_or(&part[..0], |end| &part[..=end]);
+        trimmed
+            .iter()
+            .map(|&b| {
+                if b == b' ' || RESERVED_CHARS.contains(&b) {
+                    '_'
+                } else {
+                    (b as char).to_ascii_lowercase()
+                }
+            })
+            .collect()
+    };
+
+    let mut name = map(&buffer[0..8]);
+    let ext = map(&buffer[8..11]);
+
+    if name.is_empty() {
+        return None;
+    }
+
+    if RESERVED_NAMES.contains(&name.as_str()) {
+        name.insert(0, '_');
+    }
 
-    result.push_str(&name);
     if !ext.is_empty() {
-        result.push('.');
-        result.push_str(&ext);
+        name.push('.');
+        name.push_str(&ext);
     }
 
-    result
+    Some(name)
+}
+
+/// Resolve a wire filename to a path that is guaranteed to be a direct
+/// child of `output_dir`, or `None` if the name must be rejected.
+fn output_path(output_dir: &Path, buffer: &[u8; 11]) -> Option<PathBuf> {
+    let name = sanitize_filename(buffer)?;
+
+    let mut components = Path::new(&name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Some(output_dir.join(name)),
+        _ => None,
+    }
+}
+
+/// How the output file for a received name should be written
+enum Resolution {
+    /// Write to `path`, moving any existing file to `backup` once the new
+    /// one is complete; `note` describes how a name conflict was handled
+    Write { path: PathBuf, backup: Option<PathBuf>, note: Option<String> },
+    /// The name is taken and the policy says to refuse the file
+    Skip,
+}
+
+/// Apply the conflict policy to the path a received file would be saved as
+fn resolve_conflict(path: &Path, policy: ConflictPolicy) -> std::io::Result<Resolution> {
+    if !path.exists() {
+        return Ok(Resolution::Write { path: path.to_path_buf(), backup: None, note: None });
+    }
+
+    match policy {
+        ConflictPolicy::Overwrite => Ok(Resolution::Write {
+            path: path.to_path_buf(),
+            backup: None,
+            note: Some("already exists, overwriting".to_string()),
+        }),
+        ConflictPolicy::Skip => Ok(Resolution::Skip),
+        ConflictPolicy::Rename => {
+            let renamed = numbered_path(path, |stem, ext, n| match ext {
+                Some(ext) => format!("{}~{}.{}", stem, n, ext),
+                None => format!("{}~{}", stem, n),
+            });
+            let note = format!("already exists, saving as {}", renamed.file_name().unwrap_or_default().to_string_lossy());
+            Ok(Resolution::Write { path: renamed, backup: None, note: Some(note) })
+        }
+        ConflictPolicy::Backup => {
+            let backup = numbered_path(path, |stem, ext, n| match ext {
+                Some(ext) => format!("{}.{}.~{}~", stem, ext, n),
+                None => format!("{}.~{}~", stem, n),
+            });
+            let note = format!("already exists, keeping previous version as {}", backup.file_name().unwrap_or_default().to_string_lossy());
+            Ok(Resolution::Write { path: path.to_path_buf(), backup: Some(backup), note: Some(note) })
+        }
+    }
+}
+
+/// Find the first `n` for which `format(stem, ext, n)` names a free file
+/// next to `path`
+fn numbered_path(path: &Path, format: impl Fn(&str, Option<&str>, u32) -> String) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().map(|e| e.to_string_lossy());
+
+    (1..)
+        .map(|n| path.with_file_name(format(&stem, ext.as_deref(), n)))
+        .find(|candidate| !candidate.exists())
+        .expect("ran out of numbered filenames")
 }
 
 // ============================================================================
@@ -441,31 +1108,309 @@ fn parse_filename(buffer: &[u8; 11]) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::serial::MockSerialPort;
+    use std::collections::BTreeSet;
+    use crate::clock::FakeClock;
+    use crate::serial::{MockSerialPort, BabblingPort, Script};
+    use crate::output::TrimMode;
+    use crate::text::TransferMode;
 
-    fn run_receiver(mut fsm: Box<dyn ReceiverState>) -> Result<(), ReceiverError> {
+    fn run_receiver(mut fsm: Box<dyn ReceiverState>) -> Result<SessionReport, ReceiverError> {
         loop {
-            match fsm.step() {
-                Ok(next) => fsm = next,
-                Err(ReceiverError::TransferComplete) => return Ok(()),
-                Err(e) => return Err(e),
+            match fsm.step()? {
+                Step::Next(next) => fsm = next,
+                Step::Done(report) => return Ok(report),
             }
         }
     }
 
     #[test]
-    fn test_parse_filename() {
+    fn test_sanitize_filename() {
         let buffer = *b"TEST    TXT";
-        let result = parse_filename(&buffer);
-        assert_eq!(result, "test.txt");
+        let result = sanitize_filename(&buffer);
+        assert_eq!(result.as_deref(), Some("test.txt"));
 
         let buffer = *b"EXAMPLE C  ";
-        let result = parse_filename(&buffer);
-        assert_eq!(result, "example.c");
+        let result = sanitize_filename(&buffer);
+        assert_eq!(result.as_deref(), Some("example.c"));
 
         let buffer = *b"README     ";
-        let result = parse_filename(&buffer);
-        assert_eq!(result, "readme");
+        let result = sanitize_filename(&buffer);
+        assert_eq!(result.as_deref(), Some("readme"));
+
+        let buffer = *b"MY FILE TXT";
+        let result = sanitize_filename(&buffer);
+        assert_eq!(result.as_deref(), Some("my_file.txt"));
+
+        let buffer = *b"CON     TXT";
+        let result = sanitize_filename(&buffer);
+        assert_eq!(result.as_deref(), Some("_con.txt"));
+
+        let buffer = *b"LPT1       ";
+        let result = sanitize_filename(&buffer);
+        assert_eq!(result.as_deref(), Some("_lpt1"));
+
+        let buffer = *b"CONFIG  SYS";
+        let result = sanitize_filename(&buffer);
+        assert_eq!(result.as_deref(), Some("config.sys"));
+    }
+
+    #[test]
+    fn test_sanitize_filename_hostile() {
+        let cases: &[(&[u8; 11], Option<&str>)] = &[
+            (b"../../ETCPW", Some("______et.cpw")),
+            (b"/ETC/PASSWD", Some("_etc_pas.swd")),
+            (b"..\\..\\WINXX", Some("______wi.nxx")),
+            (b"C:AUTOEXBAT", Some("c_autoex.bat")),
+            (b"..      ..-", Some("__.__-")),
+            (b".       ...", Some("_.___")),
+            (b"A*B?C<D>E|F", Some("a_b_c_d_.e_f")),
+            (b"[X];Y=Z,\"Q.", Some("_x__y_z_._q_")),
+            (b"        TXT", None),
+            (b"           ", None),
+            (b"CAF\xC9    TXT", None),
+            (b"TEST\x7F   TXT", None),
+            (b"TEST\0   TXT", None),
+            (b"TEST\n   TXT", None),
+            (b"TEST    T\xFFT", None),
+        ];
+
+        let output_dir = Path::new("/srv/filink");
+        for (buffer, expected) in cases {
+            assert_eq!(sanitize_filename(buffer).as_deref(), *expected, "buffer {:02X?}", buffer);
+
+            match output_path(output_dir, buffer) {
+                Some(path) => assert_eq!(path.parent(), Some(output_dir), "buffer {:02X?}", buffer),
+                None => assert!(expected.is_none(), "buffer {:02X?}", buffer),
+            }
+        }
+    }
+
+    #[test]
+    fn test_output_path_never_escapes() {
+        let output_dir = Path::new("/srv/filink");
+
+        // Every byte value in every position of the name and extension
+        for pos in 0..11 {
+            for b in 0..=255u8 {
+                let mut buffer = *b"NAME    EXT";
+                buffer[pos] = b;
+                if let Some(path) = output_path(output_dir, &buffer) {
+                    assert_eq!(path.parent(), Some(output_dir), "buffer {:02X?}", buffer);
+                    assert!(path.to_str().unwrap().is_ascii());
+                }
+            }
+        }
+
+        // Whole buffers made of a single byte value
+        for b in 0..=255u8 {
+            let buffer = [b; 11];
+            if let Some(path) = output_path(output_dir, &buffer) {
+                assert_eq!(path.parent(), Some(output_dir), "buffer {:02X?}", buffer);
+            }
+        }
+    }
+
+    #[test]
+    fn test_receiver_rejects_bad_filename() {
+        let temp_dir = std::env::temp_dir();
+
+        let mut responses = vec![
+            Some(SENDER_READY),
+            Some(GOOD),
+            Some(EOT),
+        ];
+
+        // Blank name part is echoed but refused at ENQ
+        for ch in b"        TXT" {
+            responses.push(Some(*ch));
+        }
+        responses.push(Some(ENQ));
+
+        // A control character is refused as soon as it arrives, even with
+        // an attribute bit set
+        responses.push(Some(EOT));
+        responses.push(Some(b'A'));
+        responses.push(Some(0x85));
+
+        responses.push(Some(XOFF));
+
+        let mut expected_writes = vec![
+            RECEIVER_READY,
+            BS,
+        ];
+        expected_writes.extend_from_slice(b"        TXT");
+        expected_writes.push(ERROR);
+        expected_writes.push(BS);
+        expected_writes.push(b'A');
+        expected_writes.push(ERROR);
+
+        let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir, ReceiverOptions::default(), Observers::default(), true);
+
+        run_receiver(fsm).expect("Session should complete");
+    }
+
+    #[test]
+    fn test_resolve_conflict() {
+        let dir = std::env::temp_dir().join("receiver_conflict");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("notes.txt");
+        let resolution = resolve_conflict(&path, ConflictPolicy::Skip).unwrap();
+        assert!(matches!(resolution, Resolution::Write { path: ref p, backup: None, note: None } if *p == path));
+
+        std::fs::write(&path, b"v1").unwrap();
+
+        assert!(matches!(resolve_conflict(&path, ConflictPolicy::Skip).unwrap(), Resolution::Skip));
+
+        match resolve_conflict(&path, ConflictPolicy::Overwrite).unwrap() {
+            Resolution::Write { path: p, note, .. } => {
+                assert_eq!(p, path);
+                assert!(note.is_some());
+            }
+            Resolution::Skip => panic!("overwrite should not skip"),
+        }
+
+        std::fs::write(dir.join("notes~1.txt"), b"taken").unwrap();
+        match resolve_conflict(&path, ConflictPolicy::Rename).unwrap() {
+            Resolution::Write { path: p, .. } => assert_eq!(p, dir.join("notes~2.txt")),
+            Resolution::Skip => panic!("rename should not skip"),
+        }
+
+        for version in [b"v2", b"v3"] {
+            match resolve_conflict(&path, ConflictPolicy::Backup).unwrap() {
+                Resolution::Write { path: p, backup, .. } => {
+                    assert_eq!(p, path);
+                    let mut file = PartialFile::create(p, backup, TransferMode::Binary, TrimMode::Auto, false).unwrap();
+                    file.write_block(version).unwrap();
+                    file.commit(false).unwrap();
+                }
+                Resolution::Skip => panic!("backup should not skip"),
+            }
+        }
+        assert_eq!(std::fs::read(dir.join("notes.txt.~1~")).unwrap(), b"v1");
+        assert_eq!(std::fs::read(dir.join("notes.txt.~2~")).unwrap(), b"v2");
+        assert_eq!(std::fs::read(&path).unwrap(), b"v3");
+
+        let readme = dir.join("readme");
+        std::fs::write(&readme, b"old").unwrap();
+        match resolve_conflict(&readme, ConflictPolicy::Rename).unwrap() {
+            Resolution::Write { path: p, .. } => assert_eq!(p, dir.join("readme~1")),
+            Resolution::Skip => panic!("rename should not skip"),
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_receiver_skip_existing() {
+        let temp_dir = std::env::temp_dir().join("receiver_skip");
+        std::fs::remove_dir_all(&temp_dir).ok();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        std::fs::write(temp_dir.join("notes.txt"), b"precious").unwrap();
+
+        let mut responses = vec![
+            Some(SENDER_READY),
+            Some(GOOD),
+            Some(EOT),
+        ];
+        for ch in b"NOTES   TXT" {
+            responses.push(Some(*ch));
+        }
+        responses.push(Some(ENQ));
+        responses.push(Some(XOFF));
+
+        let mut expected_writes = vec![
+            RECEIVER_READY,
+            BS,
+        ];
+        expected_writes.extend_from_slice(b"NOTES   TXT");
+        expected_writes.push(ERROR);
+
+        let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
+        let report = Receiver::builder(mock_serial)
+            .output(&temp_dir)
+            .on_conflict(ConflictPolicy::Skip)
+            .debug(true)
+            .run()
+            .expect("Session should complete");
+
+        assert_eq!(std::fs::read(temp_dir.join("notes.txt")).unwrap(), b"precious");
+        let rejected = FileReport::rejected(temp_dir.join("notes.txt"), "NOTES.TXT".into());
+        assert_eq!(report.untimed().files, vec![rejected]);
+
+        std::fs::remove_dir_all(&temp_dir).ok();
+    }
+
+    #[test]
+    fn test_receiver_abort_removes_partial() {
+        for keep_partial in [false, true] {
+            let temp_dir = std::env::temp_dir().join(format!("receiver_abort_{}", keep_partial));
+            std::fs::remove_dir_all(&temp_dir).ok();
+            std::fs::create_dir_all(&temp_dir).unwrap();
+
+            let mut responses = vec![
+                Some(SENDER_READY),
+                Some(GOOD),
+                Some(EOT),
+            ];
+            for ch in b"ABORT   TXT" {
+                responses.push(Some(*ch));
+            }
+            responses.push(Some(ENQ));
+
+            let mut block = b"First block".to_vec();
+            block.resize(128, b'.');
+            let checksum: u8 = block.iter().fold(0u8, |acc, &b| acc ^ b);
+            responses.push(Some(STX));
+            for &byte in &block {
+                responses.push(Some(byte));
+            }
+            responses.push(Some(checksum));
+
+            // Sender goes silent partway through the second block
+            responses.push(Some(STX));
+            for &byte in &block[..10] {
+                responses.push(Some(byte));
+            }
+            responses.push(None);
+
+            let mut expected_writes = vec![
+                RECEIVER_READY,
+                BS,
+            ];
+            expected_writes.extend_from_slice(b"ABORT   TXT");
+            expected_writes.push(TAB);
+            expected_writes.push(PROCEED);
+            expected_writes.push(GOOD);
+            expected_writes.push(PROCEED);
+            expected_writes.push(NAK);
+
+            let report = SharedReport::default();
+            let options = ReceiverOptions { keep_partial, report: report.clone(), ..Default::default() };
+            let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
+            let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), options, Observers::default(), true);
+
+            let err = run_receiver(fsm).expect_err("Session should abort");
+            assert!(matches!(err, ReceiverError::Timeout { state: "WaitBlockOrEOF", .. }), "{}", err);
+
+            // The report still has the file, cut off after its first block
+            let expected = FileReport::new(temp_dir.join("abort.txt"), "ABORT.TXT".into(), Outcome::Incomplete)
+                .with_bytes(128)
+                .with_blocks(1);
+            assert_eq!(report.snapshot().untimed().files, vec![expected]);
+
+            assert!(!temp_dir.join("abort.txt").exists(), "Incomplete file must not look complete");
+            let part = temp_dir.join("abort.txt.part");
+            if keep_partial {
+                assert_eq!(std::fs::read(&part).unwrap(), block);
+            } else {
+                assert!(!part.exists());
+            }
+
+            std::fs::remove_dir_all(&temp_dir).ok();
+        }
     }
 
     #[test]
@@ -513,19 +1458,29 @@ mod tests {
         expected_writes.push(GOOD);
 
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
-        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), true);
+        let options = ReceiverOptions { on_conflict: ConflictPolicy::Overwrite, ..Default::default() };
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), options, Observers::default(), true);
 
-        match run_receiver(fsm) {
-            Ok(()) => {},
-            Err(ReceiverError::TransferComplete) => {},
+        let report = match run_receiver(fsm) {
+            Ok(report) => report,
             Err(e) => panic!("Transfer failed: {:?}", e),
-        }
+        };
 
         let filepath = temp_dir.join("small.txt");
         assert!(filepath.exists(), "File should be created");
+        let expected = FileReport {
+            path: filepath.clone(),
+            name: "SMALL.TXT".into(),
+            bytes: 9,
+            blocks: 1,
+            retries: 0,
+            elapsed: Duration::ZERO,
+            outcome: Outcome::Completed,
+        };
+        assert_eq!(report.untimed(), SessionReport { files: vec![expected], elapsed: Duration::ZERO, pacing: None });
 
         let content = std::fs::read(&filepath).expect("Should read file");
-        assert_eq!(&content[0..9], b"Test data", "File content should match");
+        assert_eq!(content, b"Test data", "Padding should be trimmed from a .txt file");
 
         std::fs::remove_file(&filepath).ok();
     }
@@ -560,6 +1515,7 @@ mod tests {
             responses.push(Some(byte));
         }
         responses.push(Some(bad_checksum));
+        responses.push(None);
 
         responses.push(Some(STX));
         for &byte in &block {
@@ -584,11 +1540,10 @@ mod tests {
         expected_writes.push(GOOD);
 
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
-        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), true);
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), ReceiverOptions::default(), Observers::default(), true);
 
         match run_receiver(fsm) {
-            Ok(()) => {},
-            Err(ReceiverError::TransferComplete) => {},
+            Ok(_) => {},
             Err(e) => panic!("Transfer failed: {:?}", e),
         }
 
@@ -620,10 +1575,9 @@ mod tests {
         for block_num in 0..3 {
             responses.push(Some(STX));
 
-            let mut block = vec![0u8; 128];
-            for i in 0..128 {
-                block[i] = ((block_num * 128 + i) % 256) as u8;
-            }
+            let block: Vec<u8> = (0..128)
+                .map(|i| ((block_num * 128 + i) % 256) as u8)
+                .collect();
 
             let checksum: u8 = block.iter().fold(0u8, |acc, &b| acc ^ b);
 
@@ -651,11 +1605,15 @@ mod tests {
         }
 
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
-        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), true);
+        // Every byte value appears in the data, including 0x1A in the last block
+        let options = ReceiverOptions {
+            trim: TrimRules { default: TrimMode::Binary, ..Default::default() },
+            ..Default::default()
+        };
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), options, Observers::default(), true);
 
         match run_receiver(fsm) {
-            Ok(()) => {},
-            Err(ReceiverError::TransferComplete) => {},
+            Ok(_) => {},
             Err(e) => panic!("Transfer failed: {:?}", e),
         }
 
@@ -683,10 +1641,361 @@ mod tests {
         ];
 
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
-        let mut fsm = ReceiverFsm::new(mock_serial, temp_dir, true);
+        let mut fsm = ReceiverFsm::new(mock_serial, temp_dir, ReceiverOptions::default(), Observers::default(), true);
 
         for _ in 0..3 {
-            fsm = fsm.step().expect("Should succeed");
+            fsm = match fsm.step().expect("Should succeed") {
+                Step::Next(next) => next,
+                Step::Done(_) => panic!("Session should continue"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_receiver_events() {
+        let temp_dir = std::env::temp_dir().join("receiver_events");
+        std::fs::remove_dir_all(&temp_dir).ok();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+
+        let mut block = b"hi".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+
+        let mut s = Script::default();
+        s.reply(&[SENDER_READY]).sent(&[RECEIVER_READY]).reply(&[GOOD]);
+        s.reply(&[EOT]).sent(&[BS]);
+        for &c in b"EVENTS  DAT" { s.reply(&[c]).sent(&[c]); }
+        s.reply(&[ENQ]).sent(&[TAB]);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&block).reply(&[checksum ^ 1]).quiet().sent(&[BAD]);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&block).reply(&[checksum]).sent(&[GOOD]);
+        s.reply(&[ETX, XOFF]);
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = events.clone();
+        let report = Receiver::builder(s.mock())
+            .output(&temp_dir)
+            .observer(move |event: &Event| recorder.lock().unwrap().push(event.clone()))
+            .run()
+            .expect("Session should complete");
+
+        let path = temp_dir.join("events.dat");
+        let file = FileReport {
+            path: path.clone(),
+            name: "EVENTS.DAT".into(),
+            bytes: 2,
+            blocks: 1,
+            retries: 1,
+            elapsed: Duration::ZERO,
+            outcome: Outcome::Completed,
+        };
+        let events: Vec<Event> = events.lock().unwrap().drain(..).map(Event::untimed).collect();
+        assert_eq!(events, vec![
+            Event::HandshakeAttempt { attempt: 1 },
+            Event::HandshakeCompleted,
+            Event::FileStarted { path, name: "EVENTS.DAT".into(), size: None },
+            Event::ChecksumRetry { index: 0, attempt: 1 },
+            Event::BlockReceived { index: 0, bytes: 128 },
+            Event::FileCompleted(file),
+            Event::SessionEnded(report.untimed()),
+        ]);
+
+        std::fs::remove_dir_all(&temp_dir).ok();
+    }
+
+    #[test]
+    fn test_receiver_timing() {
+        let temp_dir = std::env::temp_dir().join("receiver_timing");
+        std::fs::remove_dir_all(&temp_dir).ok();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+
+        let mut block = b"timing".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+
+        // The sender is slow to answer the first 'R', and a bad checksum is
+        // only answered once the line has gone quiet
+        let clock = FakeClock::new();
+        let mut s = Script::default();
+        s.clock(&clock);
+        s.quiet().reply(&[SENDER_READY]).sent(&[RECEIVER_READY]).reply(&[GOOD]);
+        s.reply(&[EOT]).sent(&[BS]);
+        for &c in b"TIMING  DAT" { s.reply(&[c]).sent(&[c]); }
+        s.reply(&[ENQ]).sent(&[TAB]);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&block).reply(&[checksum ^ 1]).quiet().sent(&[BAD]);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&block).reply(&[checksum]).sent(&[GOOD]);
+        s.reply(&[ETX, XOFF]);
+        let mock = s.mock();
+        let reads = mock.reads();
+
+        let report = Receiver::builder(mock)
+            .output(&temp_dir)
+            .clock(clock.clone())
+            .run()
+            .expect("Session should complete");
+
+        let waited = Duration::from_secs(5) + Duration::from_secs(1);
+        assert_eq!(clock.elapsed(), waited);
+        assert_eq!(clock.slept(), Duration::ZERO);
+        assert_eq!(report.elapsed, waited);
+        assert_eq!(report.files[0].elapsed, Duration::from_secs(1));
+
+        // Every state waits the usual two seconds, except the handshake and
+        // the gaps inside a block. The quiet wait before answering BAD is
+        // one byte timeout too.
+        let secs = Duration::from_secs;
+        let timeouts: BTreeSet<(&str, Duration)> = reads.lock().unwrap().iter().copied().collect();
+        assert_eq!(timeouts, BTreeSet::from([
+            ("InitialHandshake", secs(5)),
+            ("WaitGood", secs(2)),
+            ("WaitFileOrEnd", secs(2)),
+            ("ReceiveFilename", secs(2)),
+            ("EndFilename", secs(2)),
+            ("WaitBlockOrEOF", secs(2)),
+            ("ReceiveBlock", secs(1)),
+            ("VerifyChecksum", secs(1)),
+        ]));
+
+        std::fs::remove_dir_all(&temp_dir).ok();
+    }
+
+    #[test]
+    fn test_receiver_attribute_bits() {
+        let temp_dir = std::env::temp_dir().join("receiver_attributes");
+        std::fs::remove_dir_all(&temp_dir).ok();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+
+        // R/O and SYS set on the extension, as CP/M's directory has them
+        let mut name = *b"ATTRIB  COM";
+        name[8] |= 0x80;
+        name[9] |= 0x80;
+
+        let mut block = b"attributes".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+
+        let mut s = Script::default();
+        s.reply(&[SENDER_READY]).sent(&[RECEIVER_READY]).reply(&[GOOD]);
+        s.reply(&[EOT]).sent(&[BS]);
+        for &c in &name { s.reply(&[c]).sent(&[c]); }
+        s.reply(&[ENQ]).sent(&[TAB]);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&block).reply(&[checksum]).sent(&[GOOD]);
+        s.reply(&[ETX, XOFF]);
+
+        let report = Receiver::builder(s.mock()).output(&temp_dir).run().expect("Session should complete");
+        assert_eq!(report.files[0].name, "ATTRIB.COM");
+        assert!(temp_dir.join("attrib.com").exists());
+
+        std::fs::remove_dir_all(&temp_dir).ok();
+    }
+
+    #[test]
+    fn test_receiver_retry_policy() {
+        let temp_dir = std::env::temp_dir().join("receiver_retry");
+        std::fs::remove_dir_all(&temp_dir).ok();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+
+        // A lost 'S' is sent again after a timeout
+        let options = ReceiverOptions {
+            retry: RetryPolicy { timeout_retries: 1, ..Default::default() },
+            ..Default::default()
+        };
+        let responses = vec![Some(SENDER_READY), None, Some(GOOD), Some(XOFF)];
+        let mock_serial = Box::new(MockSerialPort::new(responses, vec![RECEIVER_READY, RECEIVER_READY]));
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), options, Observers::default(), true);
+        run_receiver(fsm).expect("Session should complete");
+
+        // A block that keeps failing its checksum ends the session
+        let options = ReceiverOptions {
+            retry: RetryPolicy { max_retries: Some(0), ..Default::default() },
+            ..Default::default()
+        };
+        let mut responses = vec![Some(SENDER_READY), Some(GOOD), Some(EOT)];
+        responses.extend(b"RETRY   TXT".iter().map(|&b| Some(b)));
+        responses.extend([Some(ENQ), Some(STX)]);
+        responses.extend([Some(0u8); 128]);
+        responses.push(Some(0xFF));
+        let mut writes = vec![RECEIVER_READY, BS];
+        writes.extend_from_slice(b"RETRY   TXT");
+        writes.extend([TAB, PROCEED]);
+        let mock_serial = Box::new(MockSerialPort::new(responses, writes));
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), options, Observers::default(), true);
+        match run_receiver(fsm) {
+            Err(ReceiverError::RetriesExhausted { state, attempts }) => {
+                assert_eq!((state, attempts), ("VerifyChecksum", 1));
+            }
+            other => panic!("Expected RetriesExhausted, got {:?}", other),
+        }
+        assert!(!temp_dir.join("retry.txt").exists());
+
+        std::fs::remove_dir_all(&temp_dir).ok();
+    }
+
+    #[test]
+    fn test_receiver_noisy_line() {
+        let temp_dir = std::env::temp_dir().join("filink_test_noisy");
+        std::fs::create_dir_all(&temp_dir).ok();
+
+        let mut in_block = vec![SENDER_READY, GOOD, EOT];
+        in_block.extend_from_slice(b"NOISY   TXT");
+        in_block.extend([ENQ, STX]);
+
+        // However patient the policy, a line that never stops babbling
+        // ends the session, even right after a bad block when the receiver
+        // waits for the line to go quiet
+        let cases: [(&[u8], &str); 3] = [
+            (&[SENDER_READY], "WaitGood"),
+            (&[SENDER_READY, GOOD], "WaitFileOrEnd"),
+            (&in_block, "WaitBlockOrEOF"),
+        ];
+        for (replies, want_state) in cases {
+            let options = ReceiverOptions {
+                retry: RetryPolicy { max_stray: 4, ..Default::default() },
+                ..Default::default()
+            };
+            let fsm = ReceiverFsm::new(BabblingPort::new(replies, b'?'), temp_dir.clone(), options, Observers::default(), true);
+            match run_receiver(fsm) {
+                Err(ReceiverError::RetriesExhausted { state, attempts }) => {
+                    assert_eq!((state, attempts), (want_state, 5));
+                }
+                other => panic!("Expected RetriesExhausted in {}, got {:?}", want_state, other),
+            }
+        }
+        assert!(!temp_dir.join("noisy.txt").exists());
+
+        std::fs::remove_dir_all(&temp_dir).ok();
+    }
+
+    #[test]
+    fn test_receiver_resync() {
+        let name = b"RESYNC  TXT";
+        let mut block = b"resync data".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+
+        let handshake = |s: &mut Script| { s.reply(&[SENDER_READY]).sent(&[RECEIVER_READY]).reply(&[GOOD]); };
+        let name_chars = |s: &mut Script, name: &[u8]| { for &c in name { s.reply(&[c]).sent(&[c]); } };
+        let filename = |s: &mut Script, name: &[u8]| {
+            s.reply(&[EOT]).sent(&[BS]);
+            name_chars(s, name);
+            s.reply(&[ENQ]).sent(&[TAB]);
+        };
+        let block_ok = |s: &mut Script| {
+            s.reply(&[STX]).sent(&[PROCEED]).reply(&block).reply(&[checksum]).sent(&[GOOD]);
+        };
+        let finish = |s: &mut Script| { s.reply(&[ETX, XOFF]); };
+
+        let mut cases: Vec<(&str, Script)> = Vec::new();
+
+        let mut s = Script::default();
+        s.reply(b"?");
+        handshake(&mut s); filename(&mut s, name); block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled 'R'", s));
+
+        let mut s = Script::default();
+        s.reply(&[SENDER_READY]).sent(&[RECEIVER_READY]).reply(&[SENDER_READY]).sent(&[RECEIVER_READY]).reply(&[GOOD]);
+        filename(&mut s, name); block_ok(&mut s); finish(&mut s);
+        cases.push(("lost 'S'", s));
+
+        let mut s = Script::default();
+        s.reply(&[SENDER_READY]).sent(&[RECEIVER_READY]);
+        filename(&mut s, name); block_ok(&mut s); finish(&mut s);
+        cases.push(("lost handshake 'G'", s));
+
+        let mut s = Script::default();
+        handshake(&mut s);
+        s.reply(b"?").sent(&[ERROR]);
+        filename(&mut s, name); block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled EOT", s));
+
+        let mut s = Script::default();
+        handshake(&mut s);
+        s.reply(&[EOT]).sent(&[BS]).reply(b"RE").sent(b"RE").reply(&[0x85]).sent(&[ERROR]);
+        filename(&mut s, name); block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled filename character", s));
+
+        let mut s = Script::default();
+        handshake(&mut s);
+        s.reply(&[EOT]).sent(&[BS]);
+        name_chars(&mut s, name);
+        s.reply(b"?").sent(&[ERROR]);
+        filename(&mut s, name); block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled ENQ", s));
+
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name);
+        s.reply(b"?").sent(&[NAK]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled STX", s));
+
+        let mut bad_block = block.clone();
+        bad_block[3] ^= 0x40;
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&bad_block).reply(&[checksum]).quiet().sent(&[BAD]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("corrupted data byte", s));
+
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&block).reply(&[checksum ^ 0x01]).quiet().sent(&[BAD]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("corrupted checksum", s));
+
+        // The last data byte is taken as the checksum and the real checksum
+        // is drained before answering 'B'
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&block[..64]).reply(&[0x55]).reply(&block[64..]).reply(&[checksum]);
+        s.quiet().sent(&[BAD]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("stray byte in block", s));
+
+        // The sender never saw 'P' and repeated STX, which arrives as data
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&[STX]).quiet().sent(&[NAK]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("sender stopped mid-block", s));
+
+        // Asked again with 'N', the receiver repeats its answer; a block
+        // sent again anyway must not be saved twice
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name); block_ok(&mut s);
+        s.reply(&[NAK]).sent(&[GOOD]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("lost 'G'", s));
+
+        let mut bad_block = block.clone();
+        bad_block[3] ^= 0x40;
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name);
+        s.reply(&[STX]).sent(&[PROCEED]).reply(&bad_block).reply(&[checksum]).quiet().sent(&[BAD]);
+        s.reply(&[NAK]).sent(&[BAD]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("lost 'B'", s));
+
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name); block_ok(&mut s);
+        s.reply(b"?").sent(&[NAK]).reply(&[XOFF]);
+        cases.push(("garbled ETX", s));
+
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s, name); block_ok(&mut s);
+        filename(&mut s, b"SECOND  TXT"); block_ok(&mut s); finish(&mut s);
+        cases.push(("lost ETX", s));
+
+        for (i, (case, mut script)) in cases.into_iter().enumerate() {
+            let temp_dir = std::env::temp_dir().join(format!("receiver_resync_{}", i));
+            std::fs::remove_dir_all(&temp_dir).ok();
+            std::fs::create_dir_all(&temp_dir).unwrap();
+
+            let fsm = ReceiverFsm::new(script.mock(), temp_dir.clone(), ReceiverOptions::default(), Observers::default(), true);
+            if let Err(e) = run_receiver(fsm) {
+                panic!("{}: {}", case, e);
+            }
+            assert_eq!(std::fs::read(temp_dir.join("resync.txt")).unwrap(), b"resync data", "{}", case);
+
+            std::fs::remove_dir_all(&temp_dir).ok();
         }
     }
 
@@ -754,11 +2063,10 @@ mod tests {
         expected_writes.push(GOOD);
 
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
-        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), true);
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), ReceiverOptions::default(), Observers::default(), true);
 
         match run_receiver(fsm) {
-            Ok(()) => {},
-            Err(ReceiverError::TransferComplete) => {},
+            Ok(_) => {},
             Err(e) => panic!("Transfer failed: {:?}", e),
         }
 
@@ -777,4 +2085,55 @@ mod tests {
         std::fs::remove_file(&filepath1).ok();
         std::fs::remove_file(&filepath2).ok();
     }
+
+    #[test]
+    fn test_receiver_text_mode() {
+        let temp_dir = std::env::temp_dir().join("receiver_text");
+        std::fs::remove_dir_all(&temp_dir).ok();
+        std::fs::create_dir_all(&temp_dir).unwrap();
+
+        let mut responses = vec![
+            Some(SENDER_READY),
+            Some(GOOD),
+            Some(EOT),
+        ];
+        for ch in b"LETTER  TXT" {
+            responses.push(Some(*ch));
+        }
+        responses.push(Some(ENQ));
+
+        let mut block = b"Dear Sir,\r\nRegards\r\n".to_vec();
+        block.resize(128, 0x1A);
+        let checksum: u8 = block.iter().fold(0u8, |acc, &b| acc ^ b);
+        responses.push(Some(STX));
+        for &byte in &block {
+            responses.push(Some(byte));
+        }
+        responses.push(Some(checksum));
+        responses.push(Some(ETX));
+        responses.push(Some(XOFF));
+
+        let mut expected_writes = vec![
+            RECEIVER_READY,
+            BS,
+        ];
+        expected_writes.extend_from_slice(b"LETTER  TXT");
+        expected_writes.push(TAB);
+        expected_writes.push(PROCEED);
+        expected_writes.push(GOOD);
+
+        let options = ReceiverOptions {
+            modes: ModeRules { default: TransferMode::Text, ..Default::default() },
+            ..Default::default()
+        };
+        let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
+        let fsm = ReceiverFsm::new(mock_serial, temp_dir.clone(), options, Observers::default(), true);
+
+        run_receiver(fsm).expect("Session should complete");
+
+        let content = std::fs::read(temp_dir.join("letter.txt")).unwrap();
+        assert_eq!(content, b"Dear Sir,\nRegards\n");
+
+        std::fs::remove_dir_all(&temp_dir).ok();
+    }
 }
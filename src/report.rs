@@ -0,0 +1,183 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! The per-file summary printed at the end of a session, and the
+//! `--report` file with the same figures for scripts and spreadsheets
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Duration;
+use filink::{FileReport, SessionReport};
+use crate::json;
+use crate::progress::Stream;
+
+/// How a `--report` file is written, chosen by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+// ============================================================================
+// Table
+// ============================================================================
+
+/// Print one row per file, followed by the totals
+pub fn print_table(report: &SessionReport, stream: Stream) {
+    stream.println(format_args!("\n{}", table(report)));
+}
+
+fn table(report: &SessionReport) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<12}  {:>9}  {:>6}  {:>7}  {:>8}  {:>6}  {:<10}  Local path",
+        "File", "Bytes", "Blocks", "Retries", "Time", "cps", "Outcome"
+    );
+    for file in &report.files {
+        let _ = writeln!(
+            out,
+            "{:<12}  {:>9}  {:>6}  {:>7}  {:>8}  {:>6}  {:<10}  {}",
+            file.name,
+            file.bytes,
+            file.blocks,
+            file.retries,
+            seconds(file.elapsed),
+            file.cps().round() as u64,
+            file.outcome,
+            file.path.display()
+        );
+    }
+    let _ = write!(
+        out,
+        "{} of {} file(s), {} bytes in {}",
+        report.completed().count(),
+        report.files.len(),
+        report.bytes(),
+        seconds(report.elapsed)
+    );
+    if let Some(pacing) = report.pacing {
+        let _ = write!(out, "\nAdaptive pacing settled on a byte delay of {} ms", pacing.byte_delay.as_millis());
+        if !pacing.block_delay.is_zero() {
+            let _ = write!(out, " and a block delay of {} ms", pacing.block_delay.as_millis());
+        }
+    }
+    out
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+// ============================================================================
+// Report File
+// ============================================================================
+
+/// Write the report to `path`, replacing anything already there
+pub fn write(path: &Path, format: ReportFormat, report: &SessionReport) -> std::io::Result<()> {
+    let contents = match format {
+        ReportFormat::Json => format!("{:#}\n", json::report_json(report)),
+        ReportFormat::Csv => csv(report),
+    };
+    std::fs::write(path, contents)
+}
+
+fn csv(report: &SessionReport) -> String {
+    let mut out = String::from("name,path,bytes,blocks,retries,elapsed,cps,outcome\n");
+    for file in &report.files {
+        out.push_str(&csv_row(file));
+    }
+    out
+}
+
+fn csv_row(file: &FileReport) -> String {
+    format!(
+        "{},{},{},{},{},{:.3},{},{}\n",
+        csv_field(&file.name),
+        csv_field(&file.path.display().to_string()),
+        file.bytes,
+        file.blocks,
+        file.retries,
+        file.elapsed.as_secs_f64(),
+        file.cps().round() as u64,
+        file.outcome
+    )
+}
+
+/// Quote a field if it holds a comma, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use filink::{Outcome, Pacing};
+
+    fn sample() -> SessionReport {
+        let notes = FileReport::new(PathBuf::from("out/notes.txt"), "NOTES.TXT".into(), Outcome::Completed)
+            .with_bytes(300)
+            .with_blocks(3)
+            .with_retries(1)
+            .with_elapsed(Duration::from_millis(1500));
+
+        let mut report = SessionReport::default();
+        let big = FileReport::new(PathBuf::from("out/big.dat"), "BIG.DAT".into(), Outcome::Incomplete)
+            .with_bytes(256)
+            .with_blocks(2)
+            .with_elapsed(Duration::from_secs(2));
+        report.files = vec![notes, FileReport::rejected(PathBuf::from("odd, \"name\".dat"), "ODD.DAT".into()), big];
+        report.elapsed = Duration::from_secs(4);
+        report
+    }
+
+    #[test]
+    fn test_table() {
+        let table = table(&sample());
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "NOTES.TXT           300       3        1      1.5s     200  completed   out/notes.txt");
+        assert_eq!(lines[2], "ODD.DAT               0       0        0      0.0s       0  rejected    odd, \"name\".dat");
+        assert_eq!(lines[3], "BIG.DAT             256       2        0      2.0s     128  incomplete  out/big.dat");
+        assert_eq!(lines[4], "1 of 3 file(s), 556 bytes in 4.0s");
+
+        let mut paced = sample();
+        paced.pacing = Some(Pacing { byte_delay: Duration::from_millis(4), block_delay: Duration::from_millis(40) });
+        assert_eq!(
+            super::table(&paced).lines().last(),
+            Some("Adaptive pacing settled on a byte delay of 4 ms and a block delay of 40 ms")
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(csv(&sample()), concat!(
+            "name,path,bytes,blocks,retries,elapsed,cps,outcome\n",
+            "NOTES.TXT,out/notes.txt,300,3,1,1.500,200,completed\n",
+            "ODD.DAT,\"odd, \"\"name\"\".dat\",0,0,0,0.000,0,rejected\n",
+            "BIG.DAT,out/big.dat,256,2,0,2.000,128,incomplete\n",
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Limits on how long the sender and receiver keep trying before they give
+//! up on a session
+
+use std::time::Duration;
+
+/// How persistent a state machine is in the face of a silent or noisy line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Handshake attempts before giving up, or `None` to keep trying
+    pub handshake_attempts: Option<u32>,
+    /// Give up on the handshake once this much time has passed, or `None`
+    /// to keep trying
+    pub connect_timeout: Option<Duration>,
+    /// Times one filename or block may be retried, after a bad checksum, a
+    /// broken block or a garbled filename, before the session aborts, or
+    /// `None` to keep trying. Stray bytes while waiting for a reply don't
+    /// count; they are limited by `max_stray`.
+    pub max_retries: Option<u32>,
+    /// Times a reply may time out before the session aborts. After each
+    /// timeout the last control byte is sent again, or 'N' to ask for the
+    /// answer to a block again.
+    pub timeout_retries: u32,
+    /// Times a file is offered to a receiver that refuses it with 'X'
+    /// before it is skipped
+    pub reject_attempts: u32,
+    /// Wrong bytes tolerated while waiting for one reply before the session
+    /// aborts. Each one means the last control byte is sent again, so a line
+    /// that never stops producing them would otherwise go on for ever.
+    pub max_stray: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            handshake_attempts: None,
+            connect_timeout: None,
+            max_retries: None,
+            timeout_retries: 0,
+            reject_attempts: 3,
+            max_stray: 32,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether `retries` failed attempts at one filename or block are too many
+    pub fn retries_exhausted(&self, retries: u32) -> bool {
+        self.max_retries.is_some_and(|max| retries > max)
+    }
+
+    /// Whether `strays` wrong replies to one control byte are too many
+    pub fn strays_exhausted(&self, strays: u32) -> bool {
+        strays > self.max_stray
+    }
+
+    /// Whether the handshake should stop after `attempts` failed attempts
+    /// spread over `elapsed`
+    pub fn handshake_expired(&self, attempts: u32, elapsed: Duration) -> bool {
+        self.handshake_attempts.is_some_and(|max| attempts >= max)
+            || self.connect_timeout.is_some_and(|deadline| elapsed >= deadline)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_expired() {
+        let policy = RetryPolicy::default();
+        assert!(!policy.handshake_expired(1000, Duration::from_secs(3600)));
+
+        let policy = RetryPolicy { handshake_attempts: Some(3), ..Default::default() };
+        assert!(!policy.handshake_expired(2, Duration::ZERO));
+        assert!(policy.handshake_expired(3, Duration::ZERO));
+
+        let policy = RetryPolicy { connect_timeout: Some(Duration::from_secs(30)), ..Default::default() };
+        assert!(!policy.handshake_expired(100, Duration::from_secs(29)));
+        assert!(policy.handshake_expired(1, Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_retries_exhausted() {
+        let policy = RetryPolicy::default();
+        assert!(!policy.retries_exhausted(1000));
+
+        let policy = RetryPolicy { max_retries: Some(2), ..Default::default() };
+        assert!(!policy.retries_exhausted(2));
+        assert!(policy.retries_exhausted(3));
+    }
+
+    #[test]
+    fn test_strays_exhausted() {
+        let policy = RetryPolicy { max_stray: 2, ..Default::default() };
+        assert!(!policy.strays_exhausted(2));
+        assert!(policy.strays_exhausted(3));
+    }
+}
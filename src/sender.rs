@@ -14,29 +14,65 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::io::Read;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use crate::clock::{Clock, SharedClock};
+use crate::observer::{trace, Event, Observer, Observers};
 use crate::serial::SerialPort;
 use crate::protocol::*;
+use crate::pacing::{AdaptivePacing, Pacer};
+use crate::retry::RetryPolicy;
+use crate::timeouts::Timeouts;
+use crate::session::{FileReport, Outcome, SessionReport, SharedReport, Step};
+use crate::text::{ModeRules, TextEncoder, TransferMode};
 
 // ============================================================================
 // Error Types
 // ============================================================================
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SenderError {
+    /// The serial port failed
     Io(std::io::Error),
-    TransferComplete,
+    /// The receiver didn't answer in time
+    Timeout { state: &'static str, waited: Duration },
+    /// The receiver answered with a byte that makes no sense in this state
+    UnexpectedByte { state: &'static str, got: u8, expected: &'static [u8] },
+    /// The receiver kept rejecting a block's checksum
+    RetriesExhausted { state: &'static str, attempts: u32 },
+    /// A file to send couldn't be opened or read
+    LocalFile { path: PathBuf, source: std::io::Error },
+    /// The sender was started without any files
+    NoFiles,
+    /// The session was stopped by the user
+    Cancelled,
 }
 
 impl std::fmt::Display for SenderError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SenderError::Io(e) => write!(f, "I/O error: {}", e),
-            SenderError::TransferComplete => write!(f, "Transfer complete"),
+            SenderError::Timeout { state, waited } => {
+                write!(f, "Receiver not responding (waited {:.1}s in state: {})", waited.as_secs_f32(), state)
+            }
+            SenderError::UnexpectedByte { state, got, expected } => {
+                let expected: Vec<String> = expected.iter().map(|&b| byte_name(b)).collect();
+                write!(f, "Unexpected {} from receiver in state {}, expected {}",
+                       byte_name(*got), state, expected.join(" or "))
+            }
+            SenderError::RetriesExhausted { state, attempts } => {
+                write!(f, "Giving up after {} attempts in state: {}", attempts, state)
+            }
+            SenderError::LocalFile { path, source } => write!(f, "{}: {}", path.display(), source),
+            SenderError::NoFiles => write!(f, "No files to send"),
+            SenderError::Cancelled => write!(f, "Cancelled"),
         }
     }
 }
@@ -45,6 +81,19 @@ impl std::error::Error for SenderError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             SenderError::Io(e) => Some(e),
+            SenderError::LocalFile { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl SenderError {
+    /// The state the session failed in, where that is known
+    pub fn state(&self) -> Option<&'static str> {
+        match self {
+            SenderError::Timeout { state, .. }
+            | SenderError::UnexpectedByte { state, .. }
+            | SenderError::RetriesExhausted { state, .. } => Some(state),
             _ => None,
         }
     }
@@ -56,35 +105,100 @@ impl From<std::io::Error> for SenderError {
     }
 }
 
+// ============================================================================
+// Options
+// ============================================================================
+
+/// Sender settings that don't change during a session
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SenderOptions {
+    /// Delay in milliseconds between the bytes of a data block
+    pub byte_delay: u8,
+    /// Adapt the byte delay to the receiver, starting from `byte_delay`
+    pub pacing: Option<AdaptivePacing>,
+    /// Which files are converted to CP/M text conventions
+    pub modes: ModeRules,
+    /// When to give up on a silent or noisy line
+    pub retry: RetryPolicy,
+    /// How long to wait for each reply
+    pub timeouts: Timeouts,
+    /// Set from another thread to stop the session at the next read
+    pub cancel: Arc<AtomicBool>,
+    /// Time source for timestamps and pauses
+    pub clock: SharedClock,
+    /// Where the session keeps its report, so it can still be read after
+    /// the session failed
+    pub report: SharedReport,
+}
+
 // ============================================================================
 // States
 // ============================================================================
 
-pub struct InitialHandshake;
-pub struct SendGood;
-pub struct RequestFilename;
-pub struct TransmitFilename;
-pub struct EndFilename;
-pub struct CheckMoreData;
-pub struct TransmitBlock;
-pub struct SendChecksum;
-pub struct EndFile;
+pub(crate) struct InitialHandshake;
+pub(crate) struct SendGood;
+pub(crate) struct RequestFilename;
+pub(crate) struct TransmitFilename;
+pub(crate) struct EndFilename;
+pub(crate) struct CheckMoreData;
+pub(crate) struct TransmitBlock;
+pub(crate) struct SendChecksum;
+pub(crate) struct EndFile;
 
 // ============================================================================
 // FSM Structure
 // ============================================================================
 
-pub struct SenderFsm<State> {
+/// The file being sent, converted for CP/M in text mode
+enum SourceFile {
+    Binary { file: File, consumed: u64 },
+    Text(TextEncoder<File>),
+}
+
+impl SourceFile {
+    fn read_block(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            SourceFile::Binary { file, consumed } => {
+                let n = read_block(file, buf)?;
+                *consumed += n as u64;
+                Ok(n)
+            }
+            SourceFile::Text(encoder) => read_block(encoder, buf),
+        }
+    }
+
+    /// Bytes taken from the file on disk so far
+    fn consumed(&self) -> u64 {
+        match self {
+            SourceFile::Binary { consumed, .. } => *consumed,
+            SourceFile::Text(encoder) => encoder.consumed(),
+        }
+    }
+}
+
+pub(crate) struct SenderFsm<State> {
     state: PhantomData<State>,
     serial: Box<dyn SerialPort>,
     files: Vec<PathBuf>,
-    current_file: Option<File>,
+    names: Vec<[u8; 11]>,
+    options: SenderOptions,
+    current_file: Option<SourceFile>,
     filename: [u8; 11],
     filename_idx: usize,
     buffer: [u8; 128],
     checksum: u8,
     retransmit: bool,
-    byte_delay: u8,
+    retries: u32,
+    strays: u32,
+    rejections: u32,
+    file_bytes: u64,
+    block_index: u32,
+    file_retries: u32,
+    file_started: Instant,
+    pacer: Pacer,
+    observer: Observers,
+    handshake_attempts: u32,
+    started: Instant,
     debug: bool,
 }
 
@@ -92,8 +206,9 @@ pub struct SenderFsm<State> {
 // Trait
 // ============================================================================
 
-pub trait SenderState: Send {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError>;
+pub(crate) trait SenderState: Send {
+    /// Run one state. Returns `Step::Done` once the session is complete.
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError>;
 }
 
 // ============================================================================
@@ -101,30 +216,148 @@ pub trait SenderState: Send {
 // ============================================================================
 
 impl<S> SenderFsm<S> {
-    fn transition<T>(self) -> Box<SenderFsm<T>> {
+    fn transition<T>(mut self) -> Box<SenderFsm<T>> {
+        self.serial.enter_state(SenderFsm::<T>::state_name());
         Box::new(SenderFsm {
             state: PhantomData,
             serial: self.serial,
             files: self.files,
+            names: self.names,
+            options: self.options,
             current_file: self.current_file,
             filename: self.filename,
             filename_idx: self.filename_idx,
             buffer: self.buffer,
             checksum: self.checksum,
             retransmit: self.retransmit,
-            byte_delay: self.byte_delay,
+            retries: self.retries,
+            strays: 0,
+            rejections: self.rejections,
+            file_bytes: self.file_bytes,
+            block_index: self.block_index,
+            file_retries: self.file_retries,
+            file_started: self.file_started,
+            pacer: self.pacer,
+            observer: self.observer,
+            handshake_attempts: self.handshake_attempts,
+            started: self.started,
             debug: self.debug,
         })
     }
 
-    fn io_error(&self, e: std::io::Error) -> SenderError {
+    fn state_name() -> &'static str {
         let type_name = std::any::type_name::<S>();
-        let state_name = type_name.split("::").last().unwrap_or(type_name);
+        type_name.split("::").last().unwrap_or(type_name)
+    }
+
+    fn io_error(&self, e: std::io::Error) -> SenderError {
         SenderError::Io(std::io::Error::new(
             e.kind(),
-            format!("{} (in state: {})", e, state_name)
+            format!("{} (in state: {})", e, Self::state_name())
         ))
     }
+
+    /// Read one byte from the receiver, waiting at most `timeout`
+    fn read_byte(&mut self, timeout: Duration) -> Result<u8, SenderError> {
+        if self.options.cancel.load(Ordering::SeqCst) {
+            return Err(SenderError::Cancelled);
+        }
+
+        let mut buf = [0u8; 1];
+        match self.serial.read_timeout(&mut buf, timeout) {
+            Ok(1..) => Ok(buf[0]),
+            Ok(0) => Err(SenderError::Timeout { state: Self::state_name(), waited: timeout }),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                Err(SenderError::Timeout { state: Self::state_name(), waited: timeout })
+            }
+            Err(e) => Err(self.io_error(e)),
+        }
+    }
+
+    /// Read the receiver's reply to the byte just written. On a timeout
+    /// `resend` is written again, if given, and the wait repeated as often as
+    /// the retry policy allows.
+    fn read_reply(&mut self, timeout: Duration, resend: Option<u8>) -> Result<u8, SenderError> {
+        let mut timeouts = 0;
+        loop {
+            match self.read_byte(timeout) {
+                Err(SenderError::Timeout { .. }) if timeouts < self.options.retry.timeout_retries => {
+                    timeouts += 1;
+                    if let Some(byte) = resend {
+                        trace!(self, "Timeout, resending {}", byte_name(byte));
+                        self.serial.write_all(&[byte])?;
+                    }
+                }
+                Err(SenderError::Timeout { state, .. }) => {
+                    return Err(SenderError::Timeout { state, waited: timeout * (timeouts + 1) });
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Discard whatever is still arriving until the line goes quiet, so a
+    /// garbled reply isn't followed by stale bytes. Gives up after
+    /// `MAX_DRAIN` bytes on a line that never does.
+    fn drain(&mut self) -> Result<(), SenderError> {
+        let mut discarded = 0;
+        while discarded < MAX_DRAIN {
+            match self.read_byte(self.options.timeouts.data_byte) {
+                Ok(_) => discarded += 1,
+                Err(SenderError::Timeout { .. }) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if discarded > 0 { trace!(self, "Discarded {} stray bytes", discarded); }
+        Ok(())
+    }
+
+    /// Count another failed attempt at the current filename or block
+    fn retry(&mut self) -> Result<(), SenderError> {
+        self.retries += 1;
+        if self.options.retry.retries_exhausted(self.retries) {
+            return Err(SenderError::RetriesExhausted { state: Self::state_name(), attempts: self.retries });
+        }
+        Ok(())
+    }
+
+    /// Count a reply that was none of `expected`, aborting once there have
+    /// been more than the retry policy tolerates
+    fn stray(&mut self, got: u8, expected: &'static [u8]) -> Result<(), SenderError> {
+        self.strays += 1;
+        if self.options.retry.strays_exhausted(self.strays) {
+            return Err(self.unexpected(got, expected));
+        }
+        Ok(())
+    }
+
+    fn unexpected(&self, got: u8, expected: &'static [u8]) -> SenderError {
+        SenderError::UnexpectedByte { state: Self::state_name(), got, expected }
+    }
+
+    /// The report entry of the file being sent, as it stands
+    fn file_report(&self, outcome: Outcome) -> FileReport {
+        FileReport::new(self.files[0].clone(), display_filename(&self.names[0]), outcome)
+            .with_bytes(self.file_bytes)
+            .with_blocks(self.block_index)
+            .with_retries(self.file_retries)
+            .with_elapsed(self.options.clock.since(self.file_started))
+    }
+
+    fn local_error(&self, source: std::io::Error) -> SenderError {
+        SenderError::LocalFile { path: self.files[0].clone(), source }
+    }
+
+    fn notify(&mut self, event: Event) {
+        self.observer.on_event(&event);
+    }
+
+    /// Tell observers about the pace the pacer just switched to
+    fn pace_changed(&mut self, why: &str) {
+        let pacing = self.pacer.current();
+        trace!(self, "Pacing: {}, byte delay {:?}, block delay {:?}", why, pacing.byte_delay, pacing.block_delay);
+        self.notify(Event::PacingChanged(pacing));
+    }
 }
 
 // ============================================================================
@@ -132,137 +365,179 @@ impl<S> SenderFsm<S> {
 // ============================================================================
 
 impl SenderState for SenderFsm<InitialHandshake> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
+        fsm.notify(Event::HandshakeAttempt { attempt: fsm.handshake_attempts + 1 });
         fsm.serial.write_all(&[SENDER_READY])?;
-        if fsm.debug { println!("Sent: 'R'"); }
+        trace!(fsm, "Sent: 'R'");
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(5)) {
-            Ok(_) if buf[0] == RECEIVER_READY => {
-                if fsm.debug { println!("Received: 'S'"); }
+        match fsm.read_byte(fsm.options.timeouts.handshake) {
+            Ok(RECEIVER_READY) => {
+                trace!(fsm, "Received: 'S'");
+                fsm.notify(Event::HandshakeCompleted);
                 let next = fsm.transition::<SendGood>();
-                Ok(next as Box<dyn SenderState>)
+                Ok(Step::Next(next as Box<dyn SenderState>))
             }
-            Err(e) if e.kind() != std::io::ErrorKind::TimedOut => Err(fsm.io_error(e)),
-            _ => {
-                println!("Receiver not ready");
-                Ok(Box::new(fsm) as Box<dyn SenderState>)
+            Ok(_) | Err(SenderError::Timeout { .. }) => {
+                fsm.handshake_attempts += 1;
+                let elapsed = fsm.options.clock.since(fsm.started);
+                if fsm.options.retry.handshake_expired(fsm.handshake_attempts, elapsed) {
+                    return Err(SenderError::Timeout { state: Self::state_name(), waited: elapsed });
+                }
+                Ok(Step::Next(Box::new(fsm) as Box<dyn SenderState>))
             }
+            Err(e) => Err(e),
         }
     }
 }
 
 impl SenderState for SenderFsm<SendGood> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
         fsm.serial.write_all(&[GOOD])?;
-        if fsm.debug { println!("Sent: 'G'"); }
+        trace!(fsm, "Sent: 'G'");
         let next = fsm.transition::<RequestFilename>();
-        Ok(next as Box<dyn SenderState>)
+        Ok(Step::Next(next as Box<dyn SenderState>))
     }
 }
 
 impl SenderState for SenderFsm<RequestFilename> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
-        if fsm.files.is_empty() {
-            return Err(SenderError::Io(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "No files to send",
-            )));
-        }
-
         fsm.serial.write_all(&[EOT])?;
-        if fsm.debug { println!("Sent: EOT"); }
+        trace!(fsm, "Sent: EOT");
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == BS => {
-                if fsm.debug { println!("Received: BS"); }
-                fsm.filename = prepare_filename(&fsm.files[0]);
-                fsm.filename_idx = 0;
-                let next = fsm.transition::<TransmitFilename>();
-                Ok(next as Box<dyn SenderState>)
-            }
-            Err(e) => Err(fsm.io_error(e)),
-            Ok(_) => {
-                if fsm.debug { println!("Wrong character, waiting for BS..."); }
-                Ok(Box::new(fsm) as Box<dyn SenderState>)
+        loop {
+            match fsm.read_reply(fsm.options.timeouts.control, Some(EOT))? {
+                BS => {
+                    trace!(fsm, "Received: BS");
+                    fsm.filename = fsm.names[0];
+                    fsm.filename_idx = 0;
+                    let next = fsm.transition::<TransmitFilename>();
+                    return Ok(Step::Next(next as Box<dyn SenderState>));
+                }
+                // The receiver saw our EOT in the wrong state, so its BS
+                // was lost or it is still reading an earlier filename. This
+                // is noise rather than a failed attempt, so keep waiting.
+                got => {
+                    fsm.stray(got, &[BS])?;
+                    trace!(fsm, "Received {} instead of BS, resending EOT", byte_name(got));
+                    if got != ERROR && got != NAK {
+                        fsm.drain()?;
+                    }
+                    fsm.serial.write_all(&[EOT])?;
+                }
             }
         }
     }
 }
 
 impl SenderState for SenderFsm<TransmitFilename> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
         let ch = fsm.filename[fsm.filename_idx];
         fsm.serial.write_all(&[ch])?;
-        if fsm.debug { print!("Sent: '{}'", ch as char); }
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == ch => {
-                if fsm.debug { println!(" - OK"); }
-                fsm.filename_idx += 1;
-
-                if fsm.filename_idx >= 11 {
-                    let next = fsm.transition::<EndFilename>();
-                    Ok(next as Box<dyn SenderState>)
-                } else {
-                    Ok(Box::new(fsm) as Box<dyn SenderState>)
-                }
-            }
-            Ok(_) => {
-                if fsm.debug { println!(" - Mismatch"); }
-                fsm.filename_idx = 0;
-                let next = fsm.transition::<RequestFilename>();
-                Ok(next as Box<dyn SenderState>)
+        // A repeated filename character would be taken as the next one, so
+        // a timeout only means waiting longer
+        if fsm.read_reply(fsm.options.timeouts.control, None)? == ch {
+            trace!(fsm, "Sent: '{}' - OK", ch as char);
+            fsm.filename_idx += 1;
+
+            if fsm.filename_idx >= 11 {
+                let next = fsm.transition::<EndFilename>();
+                Ok(Step::Next(next as Box<dyn SenderState>))
+            } else {
+                Ok(Step::Next(Box::new(fsm) as Box<dyn SenderState>))
             }
-            Err(e) => Err(fsm.io_error(e))
+        } else {
+            trace!(fsm, "Sent: '{}' - Mismatch", ch as char);
+            fsm.retry()?;
+            fsm.drain()?;
+            fsm.filename_idx = 0;
+            let next = fsm.transition::<RequestFilename>();
+            Ok(Step::Next(next as Box<dyn SenderState>))
         }
     }
 }
 
 impl SenderState for SenderFsm<EndFilename> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
         fsm.serial.write_all(&[ENQ])?;
-        if fsm.debug { println!("Sent: ENQ"); }
+        trace!(fsm, "Sent: ENQ");
 
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == TAB => {
-                if fsm.debug { println!("Received: TAB"); }
-                let path = fsm.files[0].clone();
-                fsm.current_file = Some(File::open(&path)?);
-                if fsm.debug { println!("Opened: {:?}", path); }
-                let next = fsm.transition::<CheckMoreData>();
-                Ok(next as Box<dyn SenderState>)
-            }
-            Err(e) => Err(fsm.io_error(e)),
-            Ok(_) => {
-                if fsm.debug { println!("Wrong character, restarting filename exchange..."); }
-                fsm.filename_idx = 0;
-                let next = fsm.transition::<RequestFilename>();
-                Ok(next as Box<dyn SenderState>)
+        loop {
+            match fsm.read_reply(fsm.options.timeouts.control, Some(ENQ))? {
+                // 'N' is the answer to a repeated ENQ once the receiver has
+                // accepted the file, so it means the TAB was lost
+                reply @ (TAB | NAK) => {
+                    trace!(fsm, "Received: {}", byte_name(reply));
+                    let path = fsm.files[0].clone();
+                    let mode = fsm.options.modes.mode_for(&path);
+                    let file = File::open(&path).map_err(|e| fsm.local_error(e))?;
+                    let size = file.metadata().map(|m| m.len()).ok();
+                    fsm.current_file = Some(match mode {
+                        TransferMode::Binary => SourceFile::Binary { file, consumed: 0 },
+                        TransferMode::Text => SourceFile::Text(TextEncoder::new(file)),
+                    });
+                    trace!(fsm, "Opened: {:?} ({:?})", path, mode);
+                    fsm.notify(Event::FileStarted { path, name: display_filename(&fsm.names[0]), size });
+                    fsm.file_bytes = 0;
+                    fsm.block_index = 0;
+                    fsm.file_retries = 0;
+                    fsm.file_started = fsm.options.clock.now();
+                    fsm.retries = 0;
+                    let file = fsm.file_report(Outcome::Incomplete);
+                    fsm.options.report.lock().files.push(file);
+                    let next = fsm.transition::<CheckMoreData>();
+                    return Ok(Step::Next(next as Box<dyn SenderState>));
+                }
+                ERROR => {
+                    trace!(fsm, "Received: 'X'");
+                    fsm.rejections += 1;
+                    if fsm.rejections < fsm.options.retry.reject_attempts {
+                        trace!(fsm, "File rejected, offering it again");
+                        fsm.filename_idx = 0;
+                        let next = fsm.transition::<RequestFilename>();
+                        return Ok(Step::Next(next as Box<dyn SenderState>));
+                    }
+
+                    let path = fsm.files[0].clone();
+                    fsm.notify(Event::FileRejected {
+                        name: display_filename(&fsm.names[0]),
+                        path: Some(path.clone()),
+                        reason: "rejected by receiver".to_string(),
+                    });
+                    let name = display_filename(&fsm.names[0]);
+                    fsm.options.report.lock().files.push(FileReport::rejected(path, name));
+                    let next = fsm.transition::<EndFile>();
+                    return Ok(Step::Next(next as Box<dyn SenderState>));
+                }
+                got => {
+                    fsm.retry().map_err(|_| fsm.unexpected(got, &[TAB, ERROR]))?;
+                    trace!(fsm, "Received {} instead of TAB, resending ENQ", byte_name(got));
+                    fsm.drain()?;
+                    fsm.serial.write_all(&[ENQ])?;
+                }
             }
         }
     }
 }
 
 impl SenderState for SenderFsm<CheckMoreData> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
 
         let is_eof = if fsm.retransmit {
             fsm.retransmit = false;
-            if fsm.debug { println!("Retransmitting block"); }
+            trace!(fsm, "Retransmitting block");
             false
         } else {
             let bytes_read = if let Some(ref mut file) = fsm.current_file {
-                file.read(&mut fsm.buffer)?
+                let result = file.read_block(&mut fsm.buffer);
+                fsm.file_bytes = file.consumed();
+                result.map_err(|e| fsm.local_error(e))?
             } else {
                 0
             };
@@ -279,31 +554,41 @@ impl SenderState for SenderFsm<CheckMoreData> {
                     fsm.checksum ^= fsm.buffer[i];
                 }
 
-                if fsm.debug { println!("Prepared block ({} bytes)", bytes_read); }
+                trace!(fsm, "Prepared block ({} bytes)", bytes_read);
                 false
             }
         };
 
         if is_eof {
             fsm.serial.write_all(&[ETX])?;
-            if fsm.debug { println!("Sent: ETX"); }
+            trace!(fsm, "Sent: ETX");
             let next = fsm.transition::<EndFile>();
-            Ok(next as Box<dyn SenderState>)
-        } else {
-            fsm.serial.write_all(&[STX])?;
-            if fsm.debug { println!("Sent: STX"); }
+            return Ok(Step::Next(next as Box<dyn SenderState>));
+        }
+
+        let block_delay = fsm.pacer.current().block_delay;
+        if !block_delay.is_zero() {
+            fsm.options.clock.sleep(block_delay);
+        }
+        fsm.serial.write_all(&[STX])?;
+        trace!(fsm, "Sent: STX");
 
-            let mut buf = [0u8; 1];
-            match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-                Ok(_) if buf[0] == PROCEED => {
-                    if fsm.debug { println!("Received: 'P'"); }
+        // Until the receiver answers 'P' the block is still ours, so any
+        // other answer just means STX has to be sent again
+        loop {
+            match fsm.read_reply(fsm.options.timeouts.control, Some(STX))? {
+                PROCEED => {
+                    trace!(fsm, "Received: 'P'");
                     let next = fsm.transition::<TransmitBlock>();
-                    Ok(next as Box<dyn SenderState>)
+                    return Ok(Step::Next(next as Box<dyn SenderState>));
                 }
-                Err(e) => Err(fsm.io_error(e)),
-                Ok(_) => {
-                    if fsm.debug { println!("Wrong character, waiting for 'P'..."); }
-                    Ok(Box::new(fsm) as Box<dyn SenderState>)
+                got => {
+                    fsm.stray(got, &[PROCEED])?;
+                    trace!(fsm, "Received {} instead of 'P', resending STX", byte_name(got));
+                    if got != NAK {
+                        fsm.drain()?;
+                    }
+                    fsm.serial.write_all(&[STX])?;
                 }
             }
         }
@@ -311,67 +596,127 @@ impl SenderState for SenderFsm<CheckMoreData> {
 }
 
 impl SenderState for SenderFsm<TransmitBlock> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
 
         // Send block byte-by-byte with optional delay to prevent receiver buffer overflow
+        let byte_delay = fsm.pacer.current().byte_delay;
         for i in 0..128 {
             fsm.serial.write_all(&[fsm.buffer[i]])?;
-            if fsm.byte_delay > 0 {
-                std::thread::sleep(Duration::from_millis(fsm.byte_delay as u64));
+            if !byte_delay.is_zero() {
+                fsm.options.clock.sleep(byte_delay);
             }
         }
 
-        if fsm.debug { println!("Sent: 128 byte block"); }
+        trace!(fsm, "Sent: 128 byte block");
 
         let next = fsm.transition::<SendChecksum>();
-        Ok(next as Box<dyn SenderState>)
+        Ok(Step::Next(next as Box<dyn SenderState>))
     }
 }
 
 impl SenderState for SenderFsm<SendChecksum> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
         fsm.serial.write_all(&[fsm.checksum])?;
-        if fsm.debug { println!("Sent: Checksum 0x{:02X}", fsm.checksum); }
-
-        let mut buf = [0u8; 1];
-        match fsm.serial.read_timeout(&mut buf, Duration::from_secs(2)) {
-            Ok(_) if buf[0] == GOOD => {
-                if fsm.debug { println!("Received: 'G'"); }
-                fsm.retransmit = false;
-                let next = fsm.transition::<CheckMoreData>();
-                Ok(next as Box<dyn SenderState>)
-            }
-            Ok(_) if buf[0] == BAD => {
-                if fsm.debug { println!("Received: 'B' (bad checksum)"); }
-                fsm.retransmit = true;
-                let next = fsm.transition::<CheckMoreData>();
-                Ok(next as Box<dyn SenderState>)
-            }
-            Err(e) => Err(fsm.io_error(e)),
-            Ok(_) => {
-                if fsm.debug { println!("Wrong character, waiting for 'G' or 'B'..."); }
-                Ok(Box::new(fsm) as Box<dyn SenderState>)
+        trace!(fsm, "Sent: Checksum 0x{:02X}", fsm.checksum);
+
+        // A garbled answer could have been 'G' or 'B', so ask again with 'N'.
+        // A receiver still waiting for the checksum takes the 'N' as one and
+        // judges the block; filink-rs, having answered already, repeats its
+        // answer. FILINK.COM and QXFILINK.COM answer 'N' instead, which
+        // doesn't say whether the block was saved, so the session has to end
+        // rather than risk saving it twice.
+        let timeout = fsm.options.timeouts.checksum;
+        let mut timeouts = 0;
+        let mut asked_again = false;
+        loop {
+            let reply = match fsm.read_byte(timeout) {
+                Err(SenderError::Timeout { .. }) if timeouts < fsm.options.retry.timeout_retries => {
+                    timeouts += 1;
+                    trace!(fsm, "Timeout, asking again with 'N'");
+                    fsm.serial.write_all(&[NAK])?;
+                    asked_again = true;
+                    continue;
+                }
+                Err(SenderError::Timeout { state, .. }) => {
+                    return Err(SenderError::Timeout { state, waited: timeout * (timeouts + 1) });
+                }
+                result => result?,
+            };
+            match reply {
+                GOOD => {
+                    trace!(fsm, "Received: 'G'");
+                    fsm.notify(Event::BlockSent { index: fsm.block_index, bytes: fsm.file_bytes });
+                    fsm.block_index += 1;
+                    fsm.retransmit = false;
+                    fsm.retries = 0;
+                    fsm.options.report.update_last(fsm.file_report(Outcome::Incomplete));
+                    if fsm.pacer.accepted() {
+                        fsm.pace_changed("speeding up");
+                    }
+                    let next = fsm.transition::<CheckMoreData>();
+                    return Ok(Step::Next(next as Box<dyn SenderState>));
+                }
+                NAK if asked_again => {
+                    trace!(fsm, "Received: 'N' (receiver doesn't repeat its answer)");
+                    return Err(fsm.unexpected(NAK, &[GOOD, BAD]));
+                }
+                reply @ (BAD | NAK) => {
+                    trace!(fsm, "Received: {} (block not accepted)", byte_name(reply));
+                    fsm.retry()?;
+                    fsm.file_retries += 1;
+                    fsm.options.report.update_last(fsm.file_report(Outcome::Incomplete));
+                    fsm.notify(Event::ChecksumRetry { index: fsm.block_index, attempt: fsm.retries });
+                    // An unprompted 'N' means bytes of the block went missing,
+                    // which is as much a sign of going too fast as a 'B'
+                    if fsm.pacer.rejected() {
+                        fsm.pace_changed("slowing down");
+                    }
+                    fsm.retransmit = true;
+                    let next = fsm.transition::<CheckMoreData>();
+                    return Ok(Step::Next(next as Box<dyn SenderState>));
+                }
+                got => {
+                    fsm.stray(got, &[GOOD, BAD])?;
+                    trace!(fsm, "Received {} instead of 'G' or 'B', asking again", byte_name(got));
+                    fsm.drain()?;
+                    fsm.serial.write_all(&[NAK])?;
+                    asked_again = true;
+                }
             }
         }
     }
 }
 
 impl SenderState for SenderFsm<EndFile> {
-    fn step(self: Box<Self>) -> Result<Box<dyn SenderState>, SenderError> {
+    fn step(self: Box<Self>) -> Result<Step<dyn SenderState>, SenderError> {
         let mut fsm = *self;
-        fsm.current_file = None;
+        if fsm.current_file.take().is_some() {
+            let file = fsm.file_report(Outcome::Completed);
+            fsm.notify(Event::FileCompleted(file.clone()));
+            fsm.options.report.update_last(file);
+        }
         fsm.files.remove(0);
+        fsm.names.remove(0);
+        fsm.rejections = 0;
+        fsm.retries = 0;
 
         if fsm.files.is_empty() {
             fsm.serial.write_all(&[XOFF])?;
-            if fsm.debug { println!("Sent: XOFF"); }
-            Err(SenderError::TransferComplete)
+            trace!(fsm, "Sent: XOFF");
+            let report = {
+                let mut report = fsm.options.report.lock();
+                report.elapsed = fsm.options.clock.since(fsm.started);
+                report.pacing = fsm.pacer.settled();
+                report.clone()
+            };
+            fsm.notify(Event::SessionEnded(report.clone()));
+            Ok(Step::Done(report))
         } else {
-            if fsm.debug { println!("{} files remaining", fsm.files.len()); }
+            trace!(fsm, "{} files remaining", fsm.files.len());
             let next = fsm.transition::<RequestFilename>();
-            Ok(next as Box<dyn SenderState>)
+            Ok(Step::Next(next as Box<dyn SenderState>))
         }
     }
 }
@@ -381,51 +726,359 @@ impl SenderState for SenderFsm<EndFile> {
 // ============================================================================
 
 impl SenderFsm<InitialHandshake> {
-    pub fn new(serial: Box<dyn SerialPort>, files: Vec<PathBuf>, byte_delay: u8, debug: bool) -> Box<dyn SenderState> {
+    #[allow(clippy::new_ret_no_self)]
+    pub(crate) fn new(mut serial: Box<dyn SerialPort>, files: Vec<PathBuf>, options: SenderOptions, observer: Observers, debug: bool) -> Box<dyn SenderState> {
+        let names = assign_filenames(&files);
+        let now = options.clock.now();
+        let pacer = Pacer::new(Duration::from_millis(options.byte_delay.into()), options.pacing, &options.timeouts);
+        serial.enter_state(SenderFsm::<InitialHandshake>::state_name());
         Box::new(SenderFsm {
             state: PhantomData::<InitialHandshake>,
             serial,
             files,
+            names,
+            options,
             current_file: None,
             filename: [b' '; 11],
             filename_idx: 0,
             buffer: [0; 128],
             checksum: 0,
             retransmit: false,
-            byte_delay,
+            retries: 0,
+            strays: 0,
+            rejections: 0,
+            file_bytes: 0,
+            block_index: 0,
+            file_retries: 0,
+            file_started: now,
+            pacer,
+            observer,
+            handshake_attempts: 0,
+            started: now,
             debug,
         })
     }
 }
 
+// ============================================================================
+// Session API
+// ============================================================================
+
+/// Sends a batch of files to a FILINK receiver
+///
+/// ```no_run
+/// use filink::serial::RealSerialPort;
+/// use serialport::{DataBits, Parity, StopBits};
+///
+/// let port = RealSerialPort::open("/dev/ttyUSB0", 9600, DataBits::Eight, Parity::None, StopBits::One)?;
+/// let report = filink::Sender::builder(port)
+///     .files(["notes.txt", "prog.com"])
+///     .byte_delay(2)
+///     .run()?;
+/// println!("{} file(s) sent", report.completed().count());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Sender;
+
+impl Sender {
+    /// Start setting up a session on `serial`
+    pub fn builder(serial: impl SerialPort + 'static) -> SenderBuilder {
+        SenderBuilder {
+            serial: Box::new(serial),
+            files: Vec::new(),
+            options: SenderOptions::default(),
+            observer: Observers::default(),
+            debug: false,
+        }
+    }
+}
+
+/// Settings for one send session, created by [`Sender::builder`]
+pub struct SenderBuilder {
+    serial: Box<dyn SerialPort>,
+    files: Vec<PathBuf>,
+    options: SenderOptions,
+    observer: Observers,
+    debug: bool,
+}
+
+impl SenderBuilder {
+    /// Files to send, in order. Each one gets a distinct 8.3 name, see
+    /// [`assign_filenames`].
+    pub fn files<I, P>(mut self, files: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.files = files.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Delay in milliseconds between the bytes of a data block (default: 0)
+    pub fn byte_delay(mut self, ms: u8) -> Self {
+        self.options.byte_delay = ms;
+        self
+    }
+
+    /// Tune the byte delay during the session, starting from
+    /// [`byte_delay`](Self::byte_delay). The delays it settles on are in
+    /// [`SessionReport::pacing`].
+    pub fn adaptive_pacing(mut self, pacing: AdaptivePacing) -> Self {
+        self.options.pacing = Some(pacing);
+        self
+    }
+
+    /// Which files are converted to CP/M text conventions (default: none)
+    pub fn modes(mut self, modes: ModeRules) -> Self {
+        self.options.modes = modes;
+        self
+    }
+
+    /// When to give up on a silent or noisy line
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// How long to wait for each reply from the receiver
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.options.timeouts = timeouts;
+        self
+    }
+
+    /// Flag that stops the session at the next read once it is set, e.g.
+    /// from a Ctrl-C handler
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.options.cancel = cancel;
+        self
+    }
+
+    /// Run the session on `clock` instead of the real time, e.g. a
+    /// [`FakeClock`](crate::clock::FakeClock) in tests
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.options.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Subscribe to the session's events. May be given more than once.
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer.push(Box::new(observer));
+        self
+    }
+
+    /// Also send [`Event::Trace`] lines describing each protocol step
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Run the session to the end. Files the receiver refused are skipped
+    /// and listed in the report.
+    pub fn run(self) -> Result<SessionReport, SenderError> {
+        let (report, result) = self.run_with_report();
+        result.map(|()| report)
+    }
+
+    /// Run the session like [`run`](Self::run), but return the report even
+    /// if the session fails. It then lists the files dealt with before the
+    /// failure, and the one cut off as [`Outcome::Incomplete`].
+    pub fn run_with_report(self) -> (SessionReport, Result<(), SenderError>) {
+        if self.files.is_empty() {
+            return (SessionReport::default(), Err(SenderError::NoFiles));
+        }
+
+        let report = self.options.report.clone();
+        let clock = self.options.clock.clone();
+        let started = clock.now();
+        let mut state = SenderFsm::new(self.serial, self.files, self.options, self.observer, self.debug);
+        loop {
+            match state.step() {
+                Ok(Step::Next(next)) => state = next,
+                Ok(Step::Done(report)) => return (report, Ok(())),
+                Err(e) => {
+                    let mut report = report.snapshot();
+                    report.elapsed = clock.since(started);
+                    return (report, Err(e));
+                }
+            }
+        }
+    }
+}
+
 // ============================================================================
 // Helper Functions
 // ============================================================================
 
-fn prepare_filename(path: &PathBuf) -> [u8; 11] {
-    let mut result = [b' '; 11];
+/// Fill `buf` from `reader`, stopping early only at end of file
+fn read_block(reader: &mut dyn Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Give every file in the batch a distinct 8.3 name.
+///
+/// Files whose names reduce to the same 8.3 name are all mangled VFAT
+/// style, in batch order: `report2023.txt` and `report2024.txt` become
+/// `REPORT~1TXT` and `REPORT~2TXT`. Numbers already used by another file
+/// in the batch are skipped.
+pub fn assign_filenames(files: &[PathBuf]) -> Vec<[u8; 11]> {
+    let base: Vec<[u8; 11]> = files.iter().map(|f| prepare_filename(f)).collect();
+
+    let mut counts: HashMap<[u8; 11], usize> = HashMap::new();
+    for name in &base {
+        *counts.entry(*name).or_default() += 1;
+    }
 
-    if let Some(filename) = path.file_name() {
-        if let Some(s) = filename.to_str() {
-            let upper = s.to_uppercase();
-            let parts: Vec<&str> = upper.splitn(2, '.').collect();
+    let mut used: HashSet<[u8; 11]> = base.iter().copied().filter(|name| counts[name] == 1).collect();
+    let mut next_number: HashMap<[u8; 11], usize> = HashMap::new();
 
-            for (i, ch) in parts.get(0).unwrap_or(&"").chars().take(8).enumerate() {
-                result[i] = ch as u8;
+    base.iter()
+        .map(|name| {
+            if counts[name] == 1 {
+                return *name;
             }
 
-            if let Some(ext) = parts.get(1) {
-                let ext_first = ext.split('.').next().unwrap_or("");
-                for (i, ch) in ext_first.chars().take(3).enumerate() {
-                    result[8 + i] = ch as u8;
+            let n = next_number.entry(*name).or_insert(1);
+            loop {
+                let candidate = mangle_filename(name, *n);
+                *n += 1;
+                if used.insert(candidate) {
+                    return candidate;
                 }
             }
-        }
+        })
+        .collect()
+}
+
+/// Replace the end of the name part with `~n`, keeping the extension
+fn mangle_filename(name: &[u8; 11], n: usize) -> [u8; 11] {
+    let suffix = format!("~{}", n);
+    let stem_len = name[..8].iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    let keep = stem_len.min(8 - suffix.len());
+
+    let mut result = [b' '; 11];
+    result[..keep].copy_from_slice(&name[..keep]);
+    result[keep..keep + suffix.len()].copy_from_slice(suffix.as_bytes());
+    result[8..].copy_from_slice(&name[8..]);
+    result
+}
+
+/// Format an 11-byte wire filename as `NAME.EXT` for display
+pub fn display_filename(name: &[u8; 11]) -> String {
+    let stem = String::from_utf8_lossy(&name[..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&name[8..]).trim_end().to_string();
+    if ext.is_empty() { stem } else { format!("{}.{}", stem, ext) }
+}
+
+/// Characters CP/M can't use in a filename, plus path separators and other
+/// characters that hosts refuse. Each one becomes `_`.
+const RESERVED_CHARS: &[char] = &['<', '>', ',', ';', ':', '=', '?', '*', '[', ']', '/', '\\', '|', '"'];
+
+/// Build the 11-byte 8.3 wire name for a file.
+///
+/// Leading dots are ignored, the name is split at the first remaining dot,
+/// and only the first extension is kept. Each character is then mapped to
+/// printable uppercase ASCII:
+/// - ASCII letters are uppercased
+/// - accented Latin letters are transliterated (`é` → `E`, `ß` → `SS`)
+/// - spaces and control characters are removed
+/// - CP/M-reserved characters and anything without a transliteration
+///   become `_`
+///
+/// An empty name part becomes `_`, so every name can be addressed on CP/M.
+fn prepare_filename(path: &Path) -> [u8; 11] {
+    let mut result = [b' '; 11];
+
+    let filename = path.file_name().map(|f| f.to_string_lossy()).unwrap_or_default();
+    let filename = filename.trim_start_matches('.');
+    let (stem, ext) = match filename.split_once('.') {
+        Some((stem, rest)) => (stem, rest.split('.').next().unwrap_or("")),
+        None => (filename, ""),
+    };
+
+    let mut stem = to_cpm_chars(stem);
+    if stem.is_empty() {
+        stem.push(b'_');
+    }
+    let ext = to_cpm_chars(ext);
+
+    for (i, &b) in stem.iter().take(8).enumerate() {
+        result[i] = b;
+    }
+    for (i, &b) in ext.iter().take(3).enumerate() {
+        result[8 + i] = b;
     }
 
     result
 }
 
+/// Map a name part to the characters allowed in a CP/M filename
+fn to_cpm_chars(part: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    for ch in part.chars() {
+        if ch == ' ' || ch.is_control() {
+            continue;
+        }
+
+        if RESERVED_CHARS.contains(&ch) {
+            out.push(b'_');
+        } else if ch.is_ascii_graphic() {
+            out.push(ch.to_ascii_uppercase() as u8);
+        } else {
+            match transliterate(ch) {
+                Some(ascii) => out.extend_from_slice(ascii.as_bytes()),
+                None => out.push(b'_'),
+            }
+        }
+    }
+    out
+}
+
+/// Uppercase ASCII spelling of a non-ASCII letter, if there is an obvious one
+fn transliterate(ch: char) -> Option<&'static str> {
+    let ascii = match ch {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ā' | 'Ă' | 'Ą'
+        | 'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "A",
+        'Æ' | 'æ' => "AE",
+        'Ç' | 'Ć' | 'Ĉ' | 'Ċ' | 'Č' | 'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "C",
+        'Ð' | 'Ď' | 'Đ' | 'ð' | 'ď' | 'đ' => "D",
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ē' | 'Ĕ' | 'Ė' | 'Ę' | 'Ě'
+        | 'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "E",
+        'Ĝ' | 'Ğ' | 'Ġ' | 'Ģ' | 'ĝ' | 'ğ' | 'ġ' | 'ģ' => "G",
+        'Ĥ' | 'Ħ' | 'ĥ' | 'ħ' => "H",
+        'Ì' | 'Í' | 'Î' | 'Ï' | 'Ĩ' | 'Ī' | 'Ĭ' | 'Į' | 'İ'
+        | 'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "I",
+        'Ĳ' | 'ĳ' => "IJ",
+        'Ĵ' | 'ĵ' => "J",
+        'Ķ' | 'ķ' => "K",
+        'Ĺ' | 'Ļ' | 'Ľ' | 'Ŀ' | 'Ł' | 'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "L",
+        'Ñ' | 'Ń' | 'Ņ' | 'Ň' | 'ñ' | 'ń' | 'ņ' | 'ň' => "N",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' | 'Ō' | 'Ŏ' | 'Ő'
+        | 'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "O",
+        'Œ' | 'œ' => "OE",
+        'Ŕ' | 'Ŗ' | 'Ř' | 'ŕ' | 'ŗ' | 'ř' => "R",
+        'Ś' | 'Ŝ' | 'Ş' | 'Š' | 'ś' | 'ŝ' | 'ş' | 'š' => "S",
+        'ß' | 'ẞ' => "SS",
+        'Ţ' | 'Ť' | 'Ŧ' | 'ţ' | 'ť' | 'ŧ' => "T",
+        'Þ' | 'þ' => "TH",
+        'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ũ' | 'Ū' | 'Ŭ' | 'Ů' | 'Ű' | 'Ų'
+        | 'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "U",
+        'Ŵ' | 'ŵ' => "W",
+        'Ý' | 'Ÿ' | 'Ŷ' | 'ý' | 'ÿ' | 'ŷ' => "Y",
+        'Ź' | 'Ż' | 'Ž' | 'ź' | 'ż' | 'ž' => "Z",
+        _ => return None,
+    };
+    Some(ascii)
+}
+
 // ============================================================================
 // Tests
 // ============================================================================
@@ -433,14 +1086,16 @@ fn prepare_filename(path: &PathBuf) -> [u8; 11] {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::serial::MockSerialPort;
+    use std::collections::BTreeMap;
+    use crate::clock::FakeClock;
+    use crate::pacing::Pacing;
+    use crate::serial::{MockSerialPort, BabblingPort, Script};
 
-    fn run_sender(mut fsm: Box<dyn SenderState>) -> Result<(), SenderError> {
+    fn run_sender(mut fsm: Box<dyn SenderState>) -> Result<SessionReport, SenderError> {
         loop {
-            match fsm.step() {
-                Ok(next) => fsm = next,
-                Err(SenderError::TransferComplete) => return Ok(()),
-                Err(e) => return Err(e),
+            match fsm.step()? {
+                Step::Next(next) => fsm = next,
+                Step::Done(report) => return Ok(report),
             }
         }
     }
@@ -492,6 +1147,143 @@ mod tests {
         assert_eq!(&result, b"A          ");
     }
 
+    #[test]
+    fn test_prepare_filename_transliteration() {
+        let cases: &[(&str, &[u8; 11])] = &[
+            ("café.txt", b"CAFE    TXT"),
+            ("straße.doc", b"STRASSE DOC"),
+            ("größe.txt", b"GROSSE  TXT"),
+            ("Ærø.bas", b"AERO    BAS"),
+            ("œuvre.txt", b"OEUVRE  TXT"),
+            ("Łódź.asm", b"LODZ    ASM"),
+            ("naïve.dat", b"NAIVE   DAT"),
+            ("résumé.été", b"RESUME  ETE"),
+            ("ßßßßß.txt", b"SSSSSSSSTXT"),
+            ("file.ñ", b"FILE    N  "),
+            ("日本語.txt", b"___     TXT"),
+            ("emoji😀.txt", b"EMOJI_  TXT"),
+            ("Ωmega.txt", b"_MEGA   TXT"),
+        ];
+
+        for (name, expected) in cases {
+            let result = prepare_filename(&PathBuf::from(name));
+            assert_eq!(&result, *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_prepare_filename_reserved_chars() {
+        let cases: &[(&str, &[u8; 11])] = &[
+            ("my file.txt", b"MYFILE  TXT"),
+            ("  spaced  .txt", b"SPACED  TXT"),
+            ("a*b.txt", b"A_B     TXT"),
+            ("what?.txt", b"WHAT_   TXT"),
+            ("<tag>.htm", b"_TAG_   HTM"),
+            ("a=b.txt", b"A_B     TXT"),
+            ("a,b;c.txt", b"A_B_C   TXT"),
+            ("c:file.txt", b"C_FILE  TXT"),
+            ("[draft].txt", b"_DRAFT_ TXT"),
+            ("pipe|quote\".txt", b"PIPE_QUOTXT"),
+            ("back\\slash.txt", b"BACK_SLATXT"),
+            ("file.t*t", b"FILE    T_T"),
+            ("tab\there.txt", b"TABHERE TXT"),
+            ("under_score-dash.txt", b"UNDER_SCTXT"),
+            ("~tilde!.txt", b"~TILDE! TXT"),
+            (".profile", b"PROFILE    "),
+            ("..hidden.cfg", b"HIDDEN  CFG"),
+            (".txt.", b"TXT        "),
+            ("   .txt", b"_       TXT"),
+            ("...", b"_          "),
+        ];
+
+        for (name, expected) in cases {
+            let result = prepare_filename(&PathBuf::from(name));
+            assert_eq!(&result, *expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_prepare_filename_always_printable() {
+        // Every character from ASCII through Latin Extended-B, plus a few
+        // from other scripts, in both the name and the extension
+        let chars = (0u32..0x250)
+            .chain([0x3A9, 0x416, 0x5D0, 0x65E5, 0xFFFD, 0x1F600])
+            .filter_map(char::from_u32);
+
+        for ch in chars {
+            for name in [format!("a{}b.c{}d", ch, ch), format!("{}", ch), format!(".{}", ch)] {
+                let result = prepare_filename(&PathBuf::from(&name));
+                for &b in &result {
+                    assert!((0x20..0x7F).contains(&b), "{:?} gave {:02X?}", name, result);
+                    assert!(!b.is_ascii_lowercase(), "{:?} gave {:02X?}", name, result);
+                    assert!(!b".<>,;:=?*[]/\\|\"".contains(&b), "{:?} gave {:02X?}", name, result);
+                }
+                assert_ne!(result[0], b' ', "{:?} gave an empty name", name);
+                let ext_len = result[8..].iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+                assert!(!result[8..8 + ext_len].contains(&b' '), "{:?} gave {:02X?}", name, result);
+            }
+        }
+    }
+
+    #[test]
+    fn test_assign_filenames() {
+        let files = vec![
+            PathBuf::from("report2023.txt"),
+            PathBuf::from("notes.txt"),
+            PathBuf::from("report2024.txt"),
+        ];
+        let names = assign_filenames(&files);
+        assert_eq!(&names[0], b"REPORT~1TXT");
+        assert_eq!(&names[1], b"NOTES   TXT");
+        assert_eq!(&names[2], b"REPORT~2TXT");
+
+        // Short names keep their stem, only the same extension collides
+        let files = vec![
+            PathBuf::from("a/ab.c"),
+            PathBuf::from("b/AB.C"),
+            PathBuf::from("ab.h"),
+        ];
+        let names = assign_filenames(&files);
+        assert_eq!(&names[0], b"AB~1    C  ");
+        assert_eq!(&names[1], b"AB~2    C  ");
+        assert_eq!(&names[2], b"AB      H  ");
+
+        // A file that is really called REPORT~1.TXT keeps its name
+        let files = vec![
+            PathBuf::from("report2023.txt"),
+            PathBuf::from("REPORT~1.TXT"),
+            PathBuf::from("report2024.txt"),
+        ];
+        let names = assign_filenames(&files);
+        assert_eq!(&names[0], b"REPORT~2TXT");
+        assert_eq!(&names[1], b"REPORT~1TXT");
+        assert_eq!(&names[2], b"REPORT~3TXT");
+    }
+
+    #[test]
+    fn test_assign_filenames_many() {
+        let files: Vec<PathBuf> = (0..12).map(|i| PathBuf::from(format!("logfile{:04}.txt", i))).collect();
+        let names = assign_filenames(&files);
+
+        assert_eq!(&names[0], b"LOGFIL~1TXT");
+        assert_eq!(&names[8], b"LOGFIL~9TXT");
+        assert_eq!(&names[9], b"LOGFI~10TXT");
+        assert_eq!(&names[11], b"LOGFI~12TXT");
+
+        let unique: HashSet<_> = names.iter().collect();
+        assert_eq!(unique.len(), names.len());
+
+        // Same input, same names
+        assert_eq!(assign_filenames(&files), names);
+    }
+
+    #[test]
+    fn test_display_filename() {
+        assert_eq!(display_filename(b"REPORT~1TXT"), "REPORT~1.TXT");
+        assert_eq!(display_filename(b"README     "), "README");
+        assert_eq!(display_filename(b"AB      C  "), "AB.C");
+    }
+
     #[test]
     fn test_sender_full_transfer() {
         let test_file = std::env::temp_dir().join("sender_test_small.txt");
@@ -531,15 +1323,23 @@ mod tests {
         expected_writes.push(XOFF);
 
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
-        let files = vec![test_file.clone()];
-
-        let fsm = SenderFsm::new(mock_serial, files, 0, true);
 
-        match run_sender(fsm) {
-            Ok(()) => {},
-            Err(SenderError::TransferComplete) => {},
-            Err(e) => panic!("Transfer failed: {:?}", e),
-        }
+        let report = Sender::builder(mock_serial)
+            .files([&test_file])
+            .debug(true)
+            .run()
+            .expect("Transfer failed");
+
+        let expected = FileReport {
+            path: test_file.clone(),
+            name: "SENDER_T.TXT".into(),
+            bytes: 9,
+            blocks: 1,
+            retries: 0,
+            elapsed: Duration::ZERO,
+            outcome: Outcome::Completed,
+        };
+        assert_eq!(report.untimed(), SessionReport { files: vec![expected], elapsed: Duration::ZERO, pacing: None });
 
         std::fs::remove_file(&test_file).ok();
     }
@@ -557,11 +1357,629 @@ mod tests {
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
         let files = vec![PathBuf::from("dummy.txt")];
 
-        let mut fsm = SenderFsm::new(mock_serial, files, 0, true);
+        let mut fsm = SenderFsm::new(mock_serial, files, SenderOptions::default(), Observers::default(), true);
 
         for _ in 0..3 {
-            fsm = fsm.step().expect("Should succeed");
+            fsm = match fsm.step().expect("Should succeed") {
+                Step::Next(next) => next,
+                Step::Done(_) => panic!("Session should continue"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_sender_errors() {
+        let test_file = std::env::temp_dir().join("errors.txt");
+        std::fs::write(&test_file, b"test").unwrap();
+
+        let handshake = |responses: &mut Vec<Option<u8>>, writes: &mut Vec<u8>| {
+            responses.extend([Some(RECEIVER_READY), Some(BS)]);
+            responses.extend(b"ERRORS  TXT".iter().map(|&b| Some(b)));
+            writes.extend([SENDER_READY, GOOD, EOT]);
+            writes.extend_from_slice(b"ERRORS  TXT");
+            writes.push(ENQ);
+        };
+
+        // Receiver answers ENQ with garbage and there are no retries left
+        let (mut responses, mut writes) = (Vec::new(), Vec::new());
+        handshake(&mut responses, &mut writes);
+        responses.push(Some(b'?'));
+        let options = SenderOptions {
+            retry: RetryPolicy { max_retries: Some(0), ..Default::default() },
+            ..Default::default()
+        };
+        let mock_serial = Box::new(MockSerialPort::new(responses, writes));
+        let fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], options, Observers::default(), true);
+        match run_sender(fsm) {
+            Err(SenderError::UnexpectedByte { state, got, expected }) => {
+                assert_eq!((state, got, expected), ("EndFilename", b'?', &[TAB, ERROR][..]));
+            }
+            other => panic!("Expected UnexpectedByte, got {:?}", other),
+        }
+
+        // Receiver goes silent after the checksum
+        let (mut responses, mut writes) = (Vec::new(), Vec::new());
+        handshake(&mut responses, &mut writes);
+        responses.extend([Some(TAB), Some(PROCEED), None]);
+        let mut block = b"test".to_vec();
+        block.resize(128, 0x1A);
+        writes.push(STX);
+        writes.extend_from_slice(&block);
+        writes.push(block.iter().fold(0u8, |acc, &b| acc ^ b));
+        let mock_serial = Box::new(MockSerialPort::new(responses, writes));
+        let fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], SenderOptions::default(), Observers::default(), true);
+        match run_sender(fsm) {
+            Err(SenderError::Timeout { state, .. }) => assert_eq!(state, "SendChecksum"),
+            other => panic!("Expected Timeout, got {:?}", other),
+        }
+
+        // A failed session still reports the file it was cut off in, with
+        // the blocks the receiver acknowledged
+        let big_file = std::env::temp_dir().join("errors2.txt");
+        std::fs::write(&big_file, [b'x'; 200]).unwrap();
+        let first = vec![b'x'; 128];
+        let mut second = vec![b'x'; 72];
+        second.resize(128, 0x1A);
+        let checksum = |block: &[u8]| block.iter().fold(0u8, |acc, &b| acc ^ b);
+        let mut script = Script::default();
+        script.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD, EOT]).reply(&[BS]);
+        for &c in b"ERRORS2 TXT" { script.sent(&[c]).reply(&[c]); }
+        script.sent(&[ENQ]).reply(&[TAB]);
+        script.sent(&[STX]).reply(&[PROCEED]).sent(&first).sent(&[checksum(&first)]).reply(&[GOOD]);
+        script.sent(&[STX]).reply(&[PROCEED]).sent(&second).sent(&[checksum(&second)]).quiet();
+        let (report, result) = Sender::builder(script.mock()).files([&big_file]).run_with_report();
+        assert!(matches!(result, Err(SenderError::Timeout { state: "SendChecksum", .. })), "{:?}", result);
+        let expected = FileReport::new(big_file.clone(), "ERRORS2.TXT".into(), Outcome::Incomplete)
+            .with_bytes(128)
+            .with_blocks(1);
+        assert_eq!(report.untimed().files, vec![expected]);
+        std::fs::remove_file(&big_file).ok();
+
+        // Nothing to send is refused before anything goes on the wire
+        let mock_serial = MockSerialPort::new(vec![], vec![]);
+        assert!(matches!(Sender::builder(mock_serial).run(), Err(SenderError::NoFiles)));
+
+        // Cancelled before anything is read
+        let options = SenderOptions { cancel: Arc::new(AtomicBool::new(true)), ..Default::default() };
+        let mock_serial = Box::new(MockSerialPort::new(vec![], vec![SENDER_READY]));
+        let fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], options, Observers::default(), true);
+        assert!(matches!(run_sender(fsm), Err(SenderError::Cancelled)));
+
+        std::fs::remove_file(&test_file).ok();
+    }
+
+    #[test]
+    fn test_sender_noisy_line() {
+        let test_file = std::env::temp_dir().join("noisy.txt");
+        std::fs::write(&test_file, b"noise").unwrap();
+
+        let mut to_block = vec![RECEIVER_READY, BS];
+        to_block.extend_from_slice(b"NOISY   TXT");
+        to_block.push(TAB);
+        let mut to_checksum = to_block.clone();
+        to_checksum.push(PROCEED);
+
+        // However patient the policy, a line that never stops babbling
+        // ends the session in the state it was waiting in
+        let cases: [(&[u8], &str, &[u8]); 3] = [
+            (&[RECEIVER_READY], "RequestFilename", &[BS]),
+            (&to_block, "CheckMoreData", &[PROCEED]),
+            (&to_checksum, "SendChecksum", &[GOOD, BAD]),
+        ];
+        for (replies, want_state, want_expected) in cases {
+            let options = SenderOptions {
+                retry: RetryPolicy { max_stray: 4, ..Default::default() },
+                ..Default::default()
+            };
+            let fsm = SenderFsm::new(BabblingPort::new(replies, b'?'), vec![test_file.clone()], options, Observers::default(), true);
+            match run_sender(fsm) {
+                Err(SenderError::UnexpectedByte { state, got, expected }) => {
+                    assert_eq!((state, got, expected), (want_state, b'?', want_expected));
+                }
+                other => panic!("Expected UnexpectedByte in {}, got {:?}", want_state, other),
+            }
         }
+
+        std::fs::remove_file(&test_file).ok();
+    }
+
+    #[test]
+    fn test_sender_skips_rejected_file() {
+        let rejected = std::env::temp_dir().join("rejected.txt");
+        let accepted = std::env::temp_dir().join("accepted.txt");
+        std::fs::write(&rejected, b"no").unwrap();
+        std::fs::write(&accepted, b"yes").unwrap();
+
+        let mut block = b"yes".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+
+        let mut script = Script::default();
+        script.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD]);
+        for _ in 0..2 {
+            script.sent(&[EOT]).reply(&[BS]);
+            for &c in b"REJECTEDTXT" { script.sent(&[c]).reply(&[c]); }
+            script.sent(&[ENQ]).reply(&[ERROR]);
+        }
+        script.sent(&[EOT]).reply(&[BS]);
+        for &c in b"ACCEPTEDTXT" { script.sent(&[c]).reply(&[c]); }
+        script.sent(&[ENQ]).reply(&[TAB]);
+        script.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(&[GOOD]);
+        script.sent(&[ETX, XOFF]);
+
+        let report = Sender::builder(script.mock())
+            .files([&rejected, &accepted])
+            .retry(RetryPolicy { reject_attempts: 2, ..Default::default() })
+            .debug(true)
+            .run()
+            .expect("Session should complete");
+        let report = report.untimed();
+        assert_eq!(report.rejected().collect::<Vec<_>>(), vec![&FileReport::rejected(rejected.clone(), "REJECTED.TXT".into())]);
+        assert_eq!(report.completed().collect::<Vec<_>>(), vec![&FileReport {
+            path: accepted.clone(),
+            name: "ACCEPTED.TXT".into(),
+            bytes: 3,
+            blocks: 1,
+            retries: 0,
+            elapsed: Duration::ZERO,
+            outcome: Outcome::Completed,
+        }]);
+
+        std::fs::remove_file(&rejected).ok();
+        std::fs::remove_file(&accepted).ok();
+    }
+
+    #[test]
+    fn test_sender_events() {
+        let test_file = std::env::temp_dir().join("events.txt");
+        std::fs::write(&test_file, b"hi").unwrap();
+
+        let mut block = b"hi".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+
+        let mut script = Script::default();
+        script.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD]);
+        script.sent(&[EOT]).reply(&[BS]);
+        for &c in b"EVENTS  TXT" { script.sent(&[c]).reply(&[c]); }
+        script.sent(&[ENQ]).reply(&[TAB]);
+        script.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(&[BAD]);
+        script.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(&[GOOD]);
+        script.sent(&[ETX, XOFF]);
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = events.clone();
+        let report = Sender::builder(script.mock())
+            .files([&test_file])
+            .observer(move |event: &Event| recorder.lock().unwrap().push(event.clone()))
+            .run()
+            .expect("Transfer failed");
+
+        let file = FileReport {
+            path: test_file.clone(),
+            name: "EVENTS.TXT".into(),
+            bytes: 2,
+            blocks: 1,
+            retries: 1,
+            elapsed: Duration::ZERO,
+            outcome: Outcome::Completed,
+        };
+        let events: Vec<Event> = events.lock().unwrap().drain(..).map(Event::untimed).collect();
+        assert_eq!(report.files[0].retries, 1);
+        assert_eq!(events, vec![
+            Event::HandshakeAttempt { attempt: 1 },
+            Event::HandshakeCompleted,
+            Event::FileStarted { path: test_file.clone(), name: "EVENTS.TXT".into(), size: Some(2) },
+            Event::ChecksumRetry { index: 0, attempt: 1 },
+            Event::BlockSent { index: 0, bytes: 2 },
+            Event::FileCompleted(file),
+            Event::SessionEnded(report.untimed()),
+        ]);
+
+        std::fs::remove_file(&test_file).ok();
+    }
+
+    #[test]
+    fn test_sender_retry_policy() {
+        let test_file = std::env::temp_dir().join("retry.txt");
+        std::fs::write(&test_file, b"test").unwrap();
+
+        // Handshake gives up after the configured number of attempts
+        let options = SenderOptions {
+            retry: RetryPolicy { handshake_attempts: Some(2), ..Default::default() },
+            ..Default::default()
+        };
+        let mock_serial = Box::new(MockSerialPort::new(vec![None, Some(b'?')], vec![SENDER_READY, SENDER_READY]));
+        let fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], options, Observers::default(), true);
+        match run_sender(fsm) {
+            Err(SenderError::Timeout { state, .. }) => assert_eq!(state, "InitialHandshake"),
+            other => panic!("Expected Timeout, got {:?}", other),
+        }
+
+        // A timeout resends EOT instead of aborting
+        let options = SenderOptions {
+            retry: RetryPolicy { timeout_retries: 1, ..Default::default() },
+            ..Default::default()
+        };
+        let responses = vec![Some(RECEIVER_READY), None, Some(BS)];
+        let mock_serial = Box::new(MockSerialPort::new(responses, vec![SENDER_READY, GOOD, EOT, EOT]));
+        let mut fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], options, Observers::default(), true);
+        for _ in 0..3 {
+            fsm = match fsm.step().expect("Should succeed") {
+                Step::Next(next) => next,
+                Step::Done(_) => panic!("Session should continue"),
+            };
+        }
+
+        // A filename character is never sent again, as the receiver would
+        // take it for the next one
+        let options = SenderOptions {
+            retry: RetryPolicy { timeout_retries: 1, ..Default::default() },
+            ..Default::default()
+        };
+        let responses = vec![Some(RECEIVER_READY), Some(BS), None, Some(b'R')];
+        let mock_serial = Box::new(MockSerialPort::new(responses, vec![SENDER_READY, GOOD, EOT, b'R']));
+        let mut fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], options, Observers::default(), true);
+        for _ in 0..4 {
+            fsm = match fsm.step().expect("Should succeed") {
+                Step::Next(next) => next,
+                Step::Done(_) => panic!("Session should continue"),
+            };
+        }
+
+        // Stray bytes while waiting for BS, 'P' or the verdict are noise,
+        // not failed attempts
+        let options = SenderOptions {
+            retry: RetryPolicy { max_retries: Some(0), ..Default::default() },
+            ..Default::default()
+        };
+        let mut block = b"test".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+        let mut script = Script::default();
+        script.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD]);
+        script.sent(&[EOT]).reply(b"?").quiet().sent(&[EOT]).reply(&[BS]);
+        for &c in b"RETRY   TXT" { script.sent(&[c]).reply(&[c]); }
+        script.sent(&[ENQ]).reply(&[TAB]);
+        script.sent(&[STX]).reply(b"?").quiet().sent(&[STX]).reply(&[PROCEED]);
+        script.sent(&block).sent(&[checksum]).reply(&[b'?', GOOD]).quiet().sent(&[NAK]).reply(&[GOOD]);
+        script.sent(&[ETX, XOFF]);
+        let fsm = SenderFsm::new(script.mock(), vec![test_file.clone()], options, Observers::default(), true);
+        run_sender(fsm).expect("Stray bytes should not end the session");
+
+        // A block that keeps failing its checksum ends the session
+        let options = SenderOptions {
+            retry: RetryPolicy { max_retries: Some(1), ..Default::default() },
+            ..Default::default()
+        };
+        let mut responses = vec![Some(RECEIVER_READY), Some(BS)];
+        responses.extend(b"RETRY   TXT".iter().map(|&b| Some(b)));
+        responses.extend([Some(TAB), Some(PROCEED), Some(BAD), Some(PROCEED), Some(BAD)]);
+        let mut block = b"test".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+        let mut writes = vec![SENDER_READY, GOOD, EOT];
+        writes.extend_from_slice(b"RETRY   TXT");
+        writes.push(ENQ);
+        for _ in 0..2 {
+            writes.push(STX);
+            writes.extend_from_slice(&block);
+            writes.push(checksum);
+        }
+        let mock_serial = Box::new(MockSerialPort::new(responses, writes));
+        let fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], options, Observers::default(), true);
+        match run_sender(fsm) {
+            Err(SenderError::RetriesExhausted { state, attempts }) => {
+                assert_eq!((state, attempts), ("SendChecksum", 2));
+            }
+            other => panic!("Expected RetriesExhausted, got {:?}", other),
+        }
+
+        std::fs::remove_file(&test_file).ok();
+    }
+
+    #[test]
+    fn test_sender_handshake_timing() {
+        // Each attempt waits five seconds for 'S', until the connect
+        // timeout has passed
+        let clock = FakeClock::new();
+        let mut script = Script::default();
+        script.clock(&clock);
+        for _ in 0..3 {
+            script.sent(&[SENDER_READY]).quiet();
+        }
+        let mock = script.mock();
+        let reads = mock.reads();
+
+        let result = Sender::builder(mock)
+            .files(["dummy.txt"])
+            .retry(RetryPolicy { connect_timeout: Some(Duration::from_secs(12)), ..Default::default() })
+            .clock(clock.clone())
+            .run();
+        match result {
+            Err(SenderError::Timeout { state, waited }) => {
+                assert_eq!((state, waited), ("InitialHandshake", Duration::from_secs(15)));
+            }
+            other => panic!("Expected Timeout, got {:?}", other),
+        }
+        assert_eq!(*reads.lock().unwrap(), vec![("InitialHandshake", Duration::from_secs(5)); 3]);
+        assert_eq!(clock.elapsed(), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn test_sender_timing() {
+        let test_file = std::env::temp_dir().join("timing.bin");
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        std::fs::write(&test_file, &data).unwrap();
+
+        let clock = FakeClock::new();
+        let mut script = Script::default();
+        script.clock(&clock);
+        script.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD]);
+        script.sent(&[EOT]).reply(&[BS]);
+        for &c in b"TIMING  BIN" { script.sent(&[c]).reply(&[c]); }
+        script.sent(&[ENQ]).reply(&[TAB]);
+        for chunk in data.chunks(128) {
+            let mut block = chunk.to_vec();
+            block.resize(128, 0x1A);
+            let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+            script.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(&[GOOD]);
+        }
+        script.sent(&[ETX, XOFF]);
+        let mock = script.mock();
+        let reads = mock.reads();
+
+        let report = Sender::builder(mock)
+            .files([&test_file])
+            .byte_delay(2)
+            .clock(clock.clone())
+            .run()
+            .expect("Transfer failed");
+
+        // The byte delay is the only time that passes: 2 ms for each byte
+        // of two blocks
+        let paced = Duration::from_millis(2 * 128 * 2);
+        assert_eq!(clock.slept(), paced);
+        assert_eq!(clock.elapsed(), paced);
+        assert_eq!(report.files[0].elapsed, paced);
+        assert_eq!(report.elapsed, paced);
+
+        let timeouts: BTreeMap<&str, Duration> = reads.lock().unwrap().iter().copied().collect();
+        assert_eq!(timeouts, BTreeMap::from([
+            ("InitialHandshake", Duration::from_secs(5)),
+            ("RequestFilename", Duration::from_secs(2)),
+            ("TransmitFilename", Duration::from_secs(2)),
+            ("EndFilename", Duration::from_secs(2)),
+            ("CheckMoreData", Duration::from_secs(2)),
+            ("SendChecksum", Duration::from_secs(3)),
+        ]));
+
+        std::fs::remove_file(&test_file).ok();
+    }
+
+    #[test]
+    fn test_sender_timeouts() {
+        let test_file = std::env::temp_dir().join("timeouts.bin");
+        std::fs::write(&test_file, [0x55u8; 128]).unwrap();
+
+        // The receiver goes silent after the block
+        let clock = FakeClock::new();
+        let mut script = Script::default();
+        script.clock(&clock);
+        script.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD]);
+        script.sent(&[EOT]).reply(&[BS]);
+        for &c in b"TIMEOUTSBIN" { script.sent(&[c]).reply(&[c]); }
+        script.sent(&[ENQ]).reply(&[TAB]);
+        script.sent(&[STX]).reply(&[PROCEED]).sent(&[0x55; 128]).sent(&[0]).quiet();
+        let mock = script.mock();
+        let reads = mock.reads();
+
+        let secs = Duration::from_secs;
+        let timeouts = Timeouts { handshake: secs(7), control: secs(3), data_byte: secs(4), checksum: secs(15) };
+        let result = Sender::builder(mock)
+            .files([&test_file])
+            .timeouts(timeouts)
+            .clock(clock.clone())
+            .run();
+        match result {
+            Err(SenderError::Timeout { state, waited }) => assert_eq!((state, waited), ("SendChecksum", secs(15))),
+            other => panic!("Expected Timeout, got {:?}", other),
+        }
+
+        let timeouts: BTreeMap<&str, Duration> = reads.lock().unwrap().iter().copied().collect();
+        assert_eq!(timeouts, BTreeMap::from([
+            ("InitialHandshake", secs(7)),
+            ("RequestFilename", secs(3)),
+            ("TransmitFilename", secs(3)),
+            ("EndFilename", secs(3)),
+            ("CheckMoreData", secs(3)),
+            ("SendChecksum", secs(15)),
+        ]));
+
+        std::fs::remove_file(&test_file).ok();
+    }
+
+    #[test]
+    fn test_sender_adaptive_pacing() {
+        let test_file = std::env::temp_dir().join("pacing.bin");
+        std::fs::write(&test_file, [0x55u8; 384]).unwrap();
+
+        // The first block only gets through at the fourth attempt
+        let clock = FakeClock::new();
+        let mut script = Script::default();
+        script.clock(&clock);
+        script.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD]);
+        script.sent(&[EOT]).reply(&[BS]);
+        for &c in b"PACING  BIN" { script.sent(&[c]).reply(&[c]); }
+        script.sent(&[ENQ]).reply(&[TAB]);
+        for reply in [BAD, NAK, BAD, GOOD, GOOD, GOOD] {
+            script.sent(&[STX]).reply(&[PROCEED]).sent(&[0x55; 128]).sent(&[0]).reply(&[reply]);
+        }
+        script.sent(&[ETX, XOFF]);
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = events.clone();
+        let report = Sender::builder(script.mock())
+            .files([&test_file])
+            .adaptive_pacing(AdaptivePacing { recover_after: 1, ..Default::default() })
+            .clock(clock.clone())
+            .observer(move |event: &Event| recorder.lock().unwrap().push(event.clone()))
+            .run()
+            .expect("Transfer failed");
+
+        // Backed off to 1, 2 and 4 ms, then one step back down, but not to
+        // the 2 ms that failed
+        let paced = |ms| Pacing { byte_delay: Duration::from_millis(ms), block_delay: Duration::ZERO };
+        let changes: Vec<Pacing> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::PacingChanged(pacing) => Some(*pacing),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(changes, [paced(1), paced(2), paced(4), paced(3)]);
+        assert_eq!(report.pacing, Some(paced(3)));
+        assert_eq!(clock.slept(), Duration::from_millis(128 * (1 + 2 + 4 + 3 + 3)));
+
+        std::fs::remove_file(&test_file).ok();
+    }
+
+    #[test]
+    fn test_sender_resync() {
+        let test_file = std::env::temp_dir().join("resync.txt");
+        std::fs::write(&test_file, b"resync data").unwrap();
+
+        let name = b"RESYNC  TXT";
+        let mut block = b"resync data".to_vec();
+        block.resize(128, 0x1A);
+        let checksum = block.iter().fold(0u8, |acc, &b| acc ^ b);
+
+        let handshake = |s: &mut Script| { s.sent(&[SENDER_READY]).reply(&[RECEIVER_READY]).sent(&[GOOD]); };
+        let name_chars = |s: &mut Script| { for &c in name { s.sent(&[c]).reply(&[c]); } };
+        let filename = |s: &mut Script| {
+            s.sent(&[EOT]).reply(&[BS]);
+            name_chars(s);
+            s.sent(&[ENQ]).reply(&[TAB]);
+        };
+        let block_ok = |s: &mut Script| {
+            s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(&[GOOD]);
+        };
+        let finish = |s: &mut Script| { s.sent(&[ETX, XOFF]); };
+
+        let mut cases: Vec<(&str, Script)> = Vec::new();
+
+        let mut s = Script::default();
+        s.sent(&[SENDER_READY]).reply(b"?");
+        handshake(&mut s); filename(&mut s); block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled 'S'", s));
+
+        // The receiver took the repeated EOT as a filename character
+        let mut s = Script::default();
+        handshake(&mut s);
+        s.sent(&[EOT]).reply(b"?").quiet().sent(&[EOT]).reply(&[ERROR]).sent(&[EOT]).reply(&[BS]);
+        name_chars(&mut s);
+        s.sent(&[ENQ]).reply(&[TAB]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled BS", s));
+
+        let mut s = Script::default();
+        handshake(&mut s);
+        s.sent(&[EOT]).reply(&[BS]).sent(b"R").reply(b"R").sent(b"E").reply(b"?").quiet();
+        s.sent(&[EOT]).reply(&[ERROR]).sent(&[EOT]).reply(&[BS]);
+        name_chars(&mut s);
+        s.sent(&[ENQ]).reply(&[TAB]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled filename echo", s));
+
+        // The receiver already accepted the file and answers the repeated ENQ with 'N'
+        let mut s = Script::default();
+        handshake(&mut s);
+        s.sent(&[EOT]).reply(&[BS]);
+        name_chars(&mut s);
+        s.sent(&[ENQ]).reply(b"?").quiet().sent(&[ENQ]).reply(&[NAK]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled TAB", s));
+
+        // The receiver took the repeated STX as block data, timed out and answered 'N'
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(b"?").quiet().sent(&[STX]).reply(&[NAK]).sent(&[STX]).reply(&[PROCEED]);
+        s.sent(&block).sent(&[checksum]).reply(&[GOOD]);
+        finish(&mut s);
+        cases.push(("garbled 'P'", s));
+
+        // Asked again, the receiver repeats its answer
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(b"?").quiet();
+        s.sent(&[NAK]).reply(&[GOOD]);
+        finish(&mut s);
+        cases.push(("garbled 'G'", s));
+
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(b"?").quiet();
+        s.sent(&[NAK]).reply(&[BAD]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("garbled 'B'", s));
+
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(&[b'?', GOOD]).quiet();
+        s.sent(&[NAK]).reply(&[GOOD]);
+        finish(&mut s);
+        cases.push(("noise before 'G'", s));
+
+        // Asked again while still waiting for the checksum, the receiver
+        // takes the 'N' as one
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(b"?").quiet();
+        s.sent(&[NAK]).reply(&[BAD]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("noise instead of checksum", s));
+
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(&[NAK]);
+        block_ok(&mut s); finish(&mut s);
+        cases.push(("receiver dropped the block", s));
+
+        for (case, mut script) in cases {
+            let fsm = SenderFsm::new(script.mock(), vec![test_file.clone()], SenderOptions::default(), Observers::default(), true);
+            if let Err(e) = run_sender(fsm) {
+                panic!("{}: {}", case, e);
+            }
+        }
+
+        // A lost answer is asked for again once the wait for it times out
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).quiet();
+        s.sent(&[NAK]).reply(&[GOOD]);
+        finish(&mut s);
+        let options = SenderOptions {
+            retry: RetryPolicy { timeout_retries: 1, ..Default::default() },
+            ..Default::default()
+        };
+        let fsm = SenderFsm::new(s.mock(), vec![test_file.clone()], options, Observers::default(), true);
+        run_sender(fsm).expect("lost 'G'");
+
+        // A receiver that doesn't repeat its answer leaves no way of knowing
+        // whether the block was saved
+        let mut s = Script::default();
+        handshake(&mut s); filename(&mut s);
+        s.sent(&[STX]).reply(&[PROCEED]).sent(&block).sent(&[checksum]).reply(b"?").quiet();
+        s.sent(&[NAK]).reply(&[NAK]);
+        let fsm = SenderFsm::new(s.mock(), vec![test_file.clone()], SenderOptions::default(), Observers::default(), true);
+        match run_sender(fsm) {
+            Err(SenderError::UnexpectedByte { state, got, .. }) => assert_eq!((state, got), ("SendChecksum", NAK)),
+            other => panic!("Expected UnexpectedByte, got {:?}", other),
+        }
+
+        std::fs::remove_file(&test_file).ok();
     }
 
     #[test]
@@ -577,6 +1995,7 @@ mod tests {
         for ch in b"MISK" {
             responses.push(Some(*ch));
         }
+        responses.push(None);
 
         responses.push(Some(BS));
 
@@ -616,11 +2035,10 @@ mod tests {
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
         let files = vec![test_file.clone()];
 
-        let fsm = SenderFsm::new(mock_serial, files, 0, true);
+        let fsm = SenderFsm::new(mock_serial, files, SenderOptions::default(), Observers::default(), true);
 
         match run_sender(fsm) {
-            Ok(()) => {},
-            Err(SenderError::TransferComplete) => {},
+            Ok(_) => {},
             Err(e) => panic!("Transfer failed: {:?}", e),
         }
 
@@ -676,11 +2094,10 @@ mod tests {
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
         let files = vec![test_file.clone()];
 
-        let fsm = SenderFsm::new(mock_serial, files, 0, true);
+        let fsm = SenderFsm::new(mock_serial, files, SenderOptions::default(), Observers::default(), true);
 
         match run_sender(fsm) {
-            Ok(()) => {},
-            Err(SenderError::TransferComplete) => {},
+            Ok(_) => {},
             Err(e) => panic!("Transfer failed: {:?}", e),
         }
 
@@ -745,11 +2162,10 @@ mod tests {
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
         let files = vec![test_file.clone()];
 
-        let fsm = SenderFsm::new(mock_serial, files, 0, true);
+        let fsm = SenderFsm::new(mock_serial, files, SenderOptions::default(), Observers::default(), true);
 
         match run_sender(fsm) {
-            Ok(()) => {},
-            Err(SenderError::TransferComplete) => {},
+            Ok(_) => {},
             Err(e) => panic!("Transfer failed: {:?}", e),
         }
 
@@ -817,15 +2233,63 @@ mod tests {
         let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
         let files = vec![test_file1.clone(), test_file2.clone()];
 
-        let fsm = SenderFsm::new(mock_serial, files, 0, true);
+        let fsm = SenderFsm::new(mock_serial, files, SenderOptions::default(), Observers::default(), true);
 
         match run_sender(fsm) {
-            Ok(()) => {},
-            Err(SenderError::TransferComplete) => {},
+            Ok(_) => {},
             Err(e) => panic!("Transfer failed: {:?}", e),
         }
 
         std::fs::remove_file(&test_file1).ok();
         std::fs::remove_file(&test_file2).ok();
     }
+
+    #[test]
+    fn test_sender_text_mode() {
+        let test_file = std::env::temp_dir().join("textmode.txt");
+        std::fs::write(&test_file, b"line one\nline two\n").unwrap();
+
+        let mut responses = vec![
+            Some(RECEIVER_READY),
+            Some(BS),
+        ];
+        for ch in b"TEXTMODETXT" {
+            responses.push(Some(*ch));
+        }
+        responses.push(Some(TAB));
+        responses.push(Some(PROCEED));
+        responses.push(Some(GOOD));
+
+        let mut expected_writes = vec![
+            SENDER_READY,
+            GOOD,
+            EOT,
+        ];
+        expected_writes.extend_from_slice(b"TEXTMODETXT");
+        expected_writes.push(ENQ);
+
+        // The checksum covers the converted bytes that go on the wire
+        expected_writes.push(STX);
+        let mut block = b"line one\r\nline two\r\n\x1A".to_vec();
+        block.resize(128, 0x1A);
+        let checksum: u8 = block.iter().fold(0u8, |acc, &b| acc ^ b);
+        expected_writes.extend_from_slice(&block);
+        expected_writes.push(checksum);
+
+        expected_writes.push(ETX);
+        expected_writes.push(XOFF);
+
+        let options = SenderOptions {
+            modes: ModeRules { default: TransferMode::Text, ..Default::default() },
+            ..Default::default()
+        };
+        let mock_serial = Box::new(MockSerialPort::new(responses, expected_writes));
+        let fsm = SenderFsm::new(mock_serial, vec![test_file.clone()], options, Observers::default(), true);
+
+        // The report counts the bytes of the file, not what went on the wire
+        let report = run_sender(fsm).expect("Transfer failed");
+        assert_eq!(report.files[0].bytes, 18);
+
+        std::fs::remove_file(&test_file).ok();
+    }
 }
@@ -14,7 +14,11 @@
 // with this program; if not, write to the Free Software Foundation, Inc.,
 // 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::Duration;
+#[cfg(test)]
+use crate::clock::FakeClock;
 use serialport::{SerialPort as SerialPortTrait, DataBits, Parity, StopBits};
 
 // ============================================================================
@@ -26,6 +30,24 @@ pub trait SerialPort: Send {
     fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()>;
 
     fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize>;
+
+    /// Called by the state machines as they enter each state, so a wrapper
+    /// such as [`TracePort`](crate::capture::TracePort) can record it
+    fn enter_state(&mut self, _state: &'static str) {}
+}
+
+impl<P: SerialPort + ?Sized> SerialPort for Box<P> {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        (**self).write_all(buf)
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+        (**self).read_timeout(buf, timeout)
+    }
+
+    fn enter_state(&mut self, state: &'static str) {
+        (**self).enter_state(state)
+    }
 }
 
 // ============================================================================
@@ -65,11 +87,96 @@ impl SerialPort for RealSerialPort {
 
     fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
         self.port.set_timeout(timeout)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            .map_err(std::io::Error::other)?;
         self.port.read(buf)
     }
 }
 
+// ============================================================================
+// Loopback Port
+// ============================================================================
+
+/// Bytes travelling in one direction between a pair of loopback ports
+#[derive(Default)]
+struct Pipe {
+    buffer: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    /// The writing end was dropped
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.buffer.lock().unwrap_or_else(|e| e.into_inner()).closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory serial line, created by [`loopback`]
+///
+/// Writes never block. Reads block until data arrives, and fail with
+/// `TimedOut` like a real port if none does in time. Once the other end is
+/// dropped and everything it wrote has been read, reads fail with
+/// `BrokenPipe` instead of waiting.
+pub struct LoopbackPort {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+/// Two serial ports connected to each other, e.g. to run a sender and a
+/// receiver on different threads of the same process
+pub fn loopback() -> (LoopbackPort, LoopbackPort) {
+    let a_to_b = Arc::new(Pipe::default());
+    let b_to_a = Arc::new(Pipe::default());
+    (
+        LoopbackPort { incoming: b_to_a.clone(), outgoing: a_to_b.clone() },
+        LoopbackPort { incoming: a_to_b, outgoing: b_to_a },
+    )
+}
+
+impl SerialPort for LoopbackPort {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let mut state = self.outgoing.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        state.data.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(())
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+        let pipe = &self.incoming;
+        let state = pipe.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut state, _) = pipe
+            .ready
+            .wait_timeout_while(state, timeout, |state| state.data.is_empty() && !state.closed)
+            .unwrap_or_else(|e| e.into_inner());
+
+        if state.data.is_empty() {
+            return Err(if state.closed {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Other end of the loopback closed")
+            } else {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "Loopback timeout")
+            });
+        }
+
+        let n = buf.len().min(state.data.len());
+        for (slot, byte) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for LoopbackPort {
+    fn drop(&mut self) {
+        self.outgoing.close();
+    }
+}
+
 // ============================================================================
 // Mock Serial Port for Testing
 // ============================================================================
@@ -83,6 +190,11 @@ pub struct MockSerialPort {
     write_log: Vec<u8>,
     // Expected writes for verification
     expected_writes: Vec<u8>,
+    // Moved on by each timeout, if set
+    clock: Option<FakeClock>,
+    // State and timeout of every read
+    state: &'static str,
+    reads: Arc<Mutex<Vec<(&'static str, Duration)>>>,
 }
 
 #[cfg(test)]
@@ -93,8 +205,25 @@ impl MockSerialPort {
             read_pos: 0,
             write_log: Vec::new(),
             expected_writes,
+            clock: None,
+            state: "",
+            reads: Arc::default(),
         }
     }
+
+    /// The state and requested timeout of every read, shared so it can be
+    /// inspected after the session consumed the port
+    pub fn reads(&self) -> Arc<Mutex<Vec<(&'static str, Duration)>>> {
+        self.reads.clone()
+    }
+
+    /// Advance `clock` by the requested timeout whenever a read times out
+    fn timed_out(&mut self, timeout: Duration) -> std::io::Error {
+        if let Some(clock) = &self.clock {
+            clock.advance(timeout);
+        }
+        std::io::Error::new(std::io::ErrorKind::TimedOut, "Mock timeout")
+    }
 }
 
 #[cfg(test)]
@@ -104,22 +233,18 @@ impl SerialPort for MockSerialPort {
         Ok(())
     }
 
-    fn read_timeout(&mut self, buf: &mut [u8], _timeout: Duration) -> std::io::Result<usize> {
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+        self.reads.lock().unwrap().push((self.state, timeout));
+
         // Out of responses = timeout
         if self.read_pos >= self.read_buffer.len() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::TimedOut,
-                "Mock timeout"
-            ));
+            return Err(self.timed_out(timeout));
         }
 
         // If current response is None = timeout
         if self.read_buffer[self.read_pos].is_none() {
             self.read_pos += 1;
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::TimedOut,
-                "Mock timeout"
-            ));
+            return Err(self.timed_out(timeout));
         }
 
         let mut bytes_read = 0;
@@ -136,6 +261,54 @@ impl SerialPort for MockSerialPort {
 
         Ok(bytes_read)
     }
+
+    fn enter_state(&mut self, state: &'static str) {
+        self.state = state;
+    }
+}
+
+/// Builds the responses and expected writes of a `MockSerialPort` one
+/// exchange at a time
+#[cfg(test)]
+#[derive(Default)]
+pub struct Script {
+    responses: Vec<Option<u8>>,
+    writes: Vec<u8>,
+    clock: Option<FakeClock>,
+}
+
+#[cfg(test)]
+impl Script {
+    /// Bytes the side under test must write
+    pub fn sent(&mut self, bytes: &[u8]) -> &mut Self {
+        self.writes.extend_from_slice(bytes);
+        self
+    }
+
+    /// Bytes the other side answers with
+    pub fn reply(&mut self, bytes: &[u8]) -> &mut Self {
+        self.responses.extend(bytes.iter().map(|&b| Some(b)));
+        self
+    }
+
+    /// A read that times out
+    pub fn quiet(&mut self) -> &mut Self {
+        self.responses.push(None);
+        self
+    }
+
+    /// Let timeouts advance `clock`
+    pub fn clock(&mut self, clock: &FakeClock) -> &mut Self {
+        self.clock = Some(clock.clone());
+        self
+    }
+
+    pub fn mock(&mut self) -> Box<MockSerialPort> {
+        let script = std::mem::take(self);
+        let mut mock = MockSerialPort::new(script.responses, script.writes);
+        mock.clock = script.clock;
+        Box::new(mock)
+    }
 }
 
 #[cfg(test)]
@@ -162,3 +335,67 @@ impl Drop for MockSerialPort {
     }
 }
 
+/// A line that answers with the given replies and then produces the same
+/// byte for ever, whatever is written to it
+#[cfg(test)]
+pub struct BabblingPort {
+    replies: VecDeque<u8>,
+    noise: u8,
+}
+
+#[cfg(test)]
+impl BabblingPort {
+    pub fn new(replies: &[u8], noise: u8) -> Box<Self> {
+        Box::new(BabblingPort { replies: replies.iter().copied().collect(), noise })
+    }
+}
+
+#[cfg(test)]
+impl SerialPort for BabblingPort {
+    fn write_all(&mut self, _buf: &[u8]) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], _timeout: Duration) -> std::io::Result<usize> {
+        buf[0] = self.replies.pop_front().unwrap_or(self.noise);
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_loopback() {
+        let (mut a, mut b) = loopback();
+        let mut buf = [0u8; 4];
+
+        a.write_all(b"ping").unwrap();
+        assert_eq!(b.read_timeout(&mut buf[..2], Duration::ZERO).unwrap(), 2);
+        assert_eq!(&buf[..2], b"pi");
+        assert_eq!(b.read_timeout(&mut buf, Duration::ZERO).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ng");
+
+        let err = a.read_timeout(&mut buf, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        // A reader blocks until the other thread writes
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            b.write_all(b"pong").unwrap();
+            b
+        });
+        assert_eq!(a.read_timeout(&mut buf, Duration::from_secs(5)).unwrap(), 4);
+        assert_eq!(&buf, b"pong");
+
+        // What was written before the other end closed can still be read
+        let mut b = writer.join().unwrap();
+        b.write_all(b"bye").unwrap();
+        drop(b);
+        assert_eq!(a.read_timeout(&mut buf, Duration::from_secs(5)).unwrap(), 3);
+        let err = a.read_timeout(&mut buf, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Results of a transfer session
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use crate::pacing::Pacing;
+
+/// What happened during a session that ran to completion
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SessionReport {
+    /// Every file offered during the session, in order
+    pub files: Vec<FileReport>,
+    /// Time from the start of the handshake to the end of the session
+    pub elapsed: Duration,
+    /// The delays adaptive pacing settled on, when the sender used it
+    pub pacing: Option<Pacing>,
+}
+
+impl SessionReport {
+    /// Files transferred completely
+    pub fn completed(&self) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(|f| f.outcome == Outcome::Completed)
+    }
+
+    /// Files the receiver refused
+    pub fn rejected(&self) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(|f| f.outcome == Outcome::Rejected)
+    }
+
+    /// File data transferred, over all files
+    pub fn bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.bytes).sum()
+    }
+}
+
+/// How the transfer of one file ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Outcome {
+    /// The whole file was transferred
+    Completed,
+    /// The receiver refused the file with 'X'
+    Rejected,
+    /// The session failed partway through the file
+    Incomplete,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Completed => f.pad("completed"),
+            Outcome::Rejected => f.pad("rejected"),
+            Outcome::Incomplete => f.pad("incomplete"),
+        }
+    }
+}
+
+/// One file offered during a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FileReport {
+    /// Local path the file was read from or saved to. For a file the
+    /// receiver refused, this is the file in the way or, failing that, the
+    /// name offered.
+    pub path: PathBuf,
+    /// The 8.3 name used on the wire, e.g. `NOTES.TXT`
+    pub name: String,
+    /// Bytes read from or written to the local file
+    pub bytes: u64,
+    /// Data blocks acknowledged, not counting retransmissions
+    pub blocks: u32,
+    /// Blocks sent again after a bad checksum
+    pub retries: u32,
+    /// Time from the receiver accepting the file to its end
+    pub elapsed: Duration,
+    /// How the transfer of the file ended
+    pub outcome: Outcome,
+}
+
+impl FileReport {
+    /// A file with the given outcome and nothing transferred yet; the
+    /// `with_*` methods fill in the rest
+    pub fn new(path: PathBuf, name: String, outcome: Outcome) -> Self {
+        FileReport {
+            path,
+            name,
+            bytes: 0,
+            blocks: 0,
+            retries: 0,
+            elapsed: Duration::ZERO,
+            outcome,
+        }
+    }
+
+    /// A file the receiver refused
+    pub fn rejected(path: PathBuf, name: String) -> Self {
+        FileReport::new(path, name, Outcome::Rejected)
+    }
+
+    /// Set the bytes read or written
+    pub fn with_bytes(mut self, bytes: u64) -> Self {
+        self.bytes = bytes;
+        self
+    }
+
+    /// Set the data blocks acknowledged
+    pub fn with_blocks(mut self, blocks: u32) -> Self {
+        self.blocks = blocks;
+        self
+    }
+
+    /// Set the blocks sent again
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the time the transfer took
+    pub fn with_elapsed(mut self, elapsed: Duration) -> Self {
+        self.elapsed = elapsed;
+        self
+    }
+
+    /// Effective throughput in characters per second
+    pub fn cps(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.bytes as f64 / secs } else { 0.0 }
+    }
+}
+
+/// The report of a session in progress. The state machine keeps it up to
+/// date as files start and finish, and whoever runs the session holds on to
+/// a clone, so what was transferred is still known when the session fails.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedReport(Arc<Mutex<SessionReport>>);
+
+impl SharedReport {
+    pub fn lock(&self) -> MutexGuard<'_, SessionReport> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replace the entry of the file at the end of the list, the one being
+    /// transferred
+    pub fn update_last(&self, file: FileReport) {
+        if let Some(last) = self.lock().files.last_mut() {
+            *last = file;
+        }
+    }
+
+    pub fn snapshot(&self) -> SessionReport {
+        self.lock().clone()
+    }
+}
+
+/// Result of running one state of a state machine
+pub(crate) enum Step<S: ?Sized> {
+    /// Continue with this state
+    Next(Box<S>),
+    /// The session is complete
+    Done(SessionReport),
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+impl SessionReport {
+    /// Zero every duration, so a report from a test run can be compared
+    pub(crate) fn untimed(mut self) -> Self {
+        self.elapsed = Duration::ZERO;
+        self.files = self.files.into_iter().map(FileReport::untimed).collect();
+        self
+    }
+}
+
+#[cfg(test)]
+impl FileReport {
+    pub(crate) fn untimed(mut self) -> Self {
+        self.elapsed = Duration::ZERO;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_report_cps() {
+        let file = FileReport::new(PathBuf::from("a.txt"), "A.TXT".into(), Outcome::Completed);
+        assert_eq!(file.cps(), 0.0);
+
+        let file = file.with_bytes(1200).with_elapsed(Duration::from_secs(4));
+        assert_eq!(file.cps(), 300.0);
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Text-mode conversion between host and CP/M conventions
+//!
+//! CP/M text files use CRLF line endings and end at the first ^Z. In text
+//! mode the sender converts LF to CRLF and appends a ^Z, and the receiver
+//! converts CRLF back to LF and drops everything from the first ^Z on.
+//! Binary mode transfers the file byte for byte.
+
+use std::io::Read;
+use std::path::Path;
+use glob::{MatchOptions, Pattern};
+use crate::output::CPM_EOF;
+
+// ============================================================================
+// Mode Selection
+// ============================================================================
+
+/// How a file's contents are converted on the way to or from the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransferMode {
+    /// Byte-exact transfer
+    #[default]
+    Binary,
+    /// Line ending and ^Z conversion
+    Text,
+}
+
+/// Chooses the transfer mode for each file by name
+#[derive(Debug, Clone, Default)]
+pub struct ModeRules {
+    /// Mode for files that no pattern matches
+    pub default: TransferMode,
+    /// Files whose name matches one of these are sent as text
+    pub text: Vec<Pattern>,
+    /// Files whose name matches one of these are sent as binary. These win
+    /// over `text`, since a wrongly converted binary file is ruined.
+    pub binary: Vec<Pattern>,
+}
+
+impl ModeRules {
+    pub fn mode_for(&self, path: &Path) -> TransferMode {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let options = MatchOptions { case_sensitive: false, ..Default::default() };
+        let matches = |patterns: &[Pattern]| patterns.iter().any(|p| p.matches_with(name, options));
+
+        if matches(&self.binary) {
+            TransferMode::Binary
+        } else if matches(&self.text) {
+            TransferMode::Text
+        } else {
+            self.default
+        }
+    }
+}
+
+// ============================================================================
+// Encoder (host to CP/M)
+// ============================================================================
+
+/// Reader adapter that converts LF to CRLF and appends a ^Z at the end,
+/// unless the file already ends with one
+pub struct TextEncoder<R> {
+    inner: R,
+    /// Converted bytes not yet read, each with whether it came from `inner`
+    pending: Vec<(u8, bool)>,
+    last: Option<u8>,
+    done: bool,
+    consumed: u64,
+}
+
+impl<R: Read> TextEncoder<R> {
+    pub fn new(inner: R) -> Self {
+        TextEncoder {
+            inner,
+            pending: Vec::new(),
+            last: None,
+            done: false,
+            consumed: 0,
+        }
+    }
+
+    /// Bytes of the original data passed on so far, not counting the CRs
+    /// and ^Z added
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+}
+
+impl<R: Read> Read for TextEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending.is_empty() && !self.done {
+            let mut chunk = [0u8; 128];
+            let n = self.inner.read(&mut chunk)?;
+
+            if n == 0 {
+                if self.last != Some(CPM_EOF) {
+                    self.pending.push((CPM_EOF, false));
+                }
+                self.done = true;
+            }
+
+            for &b in &chunk[..n] {
+                if b == b'\n' && self.last != Some(b'\r') {
+                    self.pending.push((b'\r', false));
+                }
+                self.pending.push((b, true));
+                self.last = Some(b);
+            }
+        }
+
+        let n = buf.len().min(self.pending.len());
+        for (slot, (b, original)) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *slot = b;
+            self.consumed += original as u64;
+        }
+        Ok(n)
+    }
+}
+
+// ============================================================================
+// Decoder (CP/M to host)
+// ============================================================================
+
+/// Converts CRLF to LF and stops at the first ^Z. Lone CRs are kept.
+#[derive(Default)]
+pub struct TextDecoder {
+    pending_cr: bool,
+    done: bool,
+}
+
+impl TextDecoder {
+    /// Convert the next chunk of received data, appending to `out`
+    pub fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            if self.done {
+                return;
+            }
+
+            if self.pending_cr {
+                self.pending_cr = false;
+                if b != b'\n' {
+                    out.push(b'\r');
+                }
+            }
+
+            match b {
+                CPM_EOF => self.done = true,
+                b'\r' => self.pending_cr = true,
+                _ => out.push(b),
+            }
+        }
+    }
+
+    /// Flush a CR left over at the end of the data
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.pending_cr {
+            self.pending_cr = false;
+            out.push(b'\r');
+        }
+        self.done = true;
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        TextEncoder::new(input).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn decode(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut decoder = TextDecoder::default();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            decoder.decode(chunk, &mut out);
+        }
+        decoder.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(b"one\ntwo\n"), b"one\r\ntwo\r\n\x1A");
+        assert_eq!(encode(b"dos\r\nline"), b"dos\r\nline\x1A");
+        assert_eq!(encode(b"\n\n"), b"\r\n\r\n\x1A");
+        assert_eq!(encode(b"has eof\x1A"), b"has eof\x1A");
+        assert_eq!(encode(b""), b"\x1A");
+    }
+
+    #[test]
+    fn test_encode_consumed() {
+        let mut encoder = TextEncoder::new(&b"ab\ncd\n"[..]);
+        let mut buf = [0u8; 3];
+        assert_eq!(encoder.read(&mut buf).unwrap(), 3);
+        assert_eq!((&buf, encoder.consumed()), (b"ab\r", 2));
+        assert_eq!(encoder.read(&mut buf).unwrap(), 3);
+        assert_eq!((&buf, encoder.consumed()), (b"\ncd", 5));
+        let mut rest = Vec::new();
+        encoder.read_to_end(&mut rest).unwrap();
+        assert_eq!((rest.as_slice(), encoder.consumed()), (&b"\r\n\x1A"[..], 6));
+    }
+
+    #[test]
+    fn test_encode_across_chunks() {
+        // LF right after a chunk boundary must still get its CR
+        let mut input = vec![b'x'; 127];
+        input.push(b'\r');
+        input.push(b'\n');
+        input.extend_from_slice(&[b'y'; 127]);
+        input.push(b'\n');
+
+        let mut expected = vec![b'x'; 127];
+        expected.extend_from_slice(b"\r\n");
+        expected.extend_from_slice(&[b'y'; 127]);
+        expected.extend_from_slice(b"\r\n\x1A");
+
+        assert_eq!(encode(&input), expected);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(&[b"one\r\ntwo\r\n\x1A\x1A\x1A"]), b"one\ntwo\n");
+        assert_eq!(decode(&[b"lone\rcr\r"]), b"lone\rcr\r");
+        assert_eq!(decode(&[b"stop\x1Ahere\r\n"]), b"stop");
+        assert_eq!(decode(&[b"split\r", b"\nline"]), b"split\nline");
+        assert_eq!(decode(&[b"end\r", b"\x1A"]), b"end\r");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let original = b"first line\nsecond line\n\nlast line without newline";
+        assert_eq!(decode(&[&encode(original)]), original);
+    }
+
+    #[test]
+    fn test_mode_rules() {
+        let rules = ModeRules {
+            default: TransferMode::Binary,
+            text: vec![Pattern::new("*.txt").unwrap(), Pattern::new("readme").unwrap()],
+            binary: vec![Pattern::new("keep.*").unwrap()],
+        };
+
+        assert_eq!(rules.mode_for(Path::new("notes.txt")), TransferMode::Text);
+        assert_eq!(rules.mode_for(Path::new("/tmp/NOTES.TXT")), TransferMode::Text);
+        assert_eq!(rules.mode_for(Path::new("README")), TransferMode::Text);
+        assert_eq!(rules.mode_for(Path::new("keep.txt")), TransferMode::Binary);
+        assert_eq!(rules.mode_for(Path::new("prog.com")), TransferMode::Binary);
+
+        let rules = ModeRules { default: TransferMode::Text, ..Default::default() };
+        assert_eq!(rules.mode_for(Path::new("prog.com")), TransferMode::Text);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! How long the sender and receiver wait for each other before treating
+//! the line as silent
+//!
+//! The defaults suit 9600 baud. Slow lines need longer waits: at 110 baud
+//! a 128-byte block takes over eleven seconds to arrive, all of which the
+//! sender spends waiting for the receiver's verdict on it.
+//! [`Timeouts::for_line`] raises each wait to what the line needs.
+
+use std::time::Duration;
+
+/// Bytes the receiver has to read before it can judge a block
+const BLOCK_BYTES: u32 = 129;
+
+/// Per-state timeouts shared by both state machines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Each handshake attempt
+    pub handshake: Duration,
+    /// A control byte or the echo of a filename character
+    pub control: Duration,
+    /// The gap between two bytes of a block, and before its checksum. A
+    /// longer pause means the sender has stopped sending the block,
+    /// usually because it never saw the receiver's 'P'. It is also how
+    /// long the line has to stay quiet before a garbled exchange is
+    /// retried, so a paced block is never mistaken for a quiet line.
+    pub data_byte: Duration,
+    /// The receiver's verdict on a block, counted from when the sender
+    /// finished writing the checksum. Never shorter than
+    /// [`Timeouts::min_checksum`].
+    pub checksum: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: Duration::from_secs(5),
+            control: Duration::from_secs(2),
+            data_byte: Duration::from_secs(1),
+            checksum: Duration::from_secs(3),
+        }
+    }
+}
+
+impl Timeouts {
+    /// The defaults, raised where a line at `baud` with `frame_bits` bits
+    /// per character (start, data, parity and stop) and `byte_delay`
+    /// between block bytes needs longer
+    pub fn for_line(baud: u32, frame_bits: u32, byte_delay: Duration) -> Self {
+        let char_time = Duration::from_secs(frame_bits.into()) / baud.max(1);
+        let defaults = Timeouts::default();
+
+        // A control exchange is one character each way; allow it twice over
+        let control = defaults.control.max(char_time * 4);
+        let mut timeouts = Timeouts {
+            handshake: defaults.handshake.max(control),
+            control,
+            data_byte: defaults.data_byte.max((char_time + byte_delay) * 2),
+            // The block may still be sitting in the output buffer when the
+            // sender starts waiting, so allow for all of it to drain
+            checksum: defaults.checksum.max(control + char_time * BLOCK_BYTES),
+        };
+        timeouts.checksum = timeouts.checksum.max(timeouts.min_checksum());
+        timeouts
+    }
+
+    /// Shortest verdict wait that still works: a receiver that garbles
+    /// the checksum waits `data_byte` for the line to go quiet, then
+    /// answers within `control`
+    pub fn min_checksum(&self) -> Duration {
+        self.data_byte + self.control
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_line() {
+        let ms = Duration::from_millis;
+
+        // Fast lines keep the defaults, bar the time for a block to drain
+        let fast = Timeouts::for_line(9600, 10, ms(255));
+        assert_eq!(fast, Timeouts::default());
+
+        // 110 baud, 8N1: 100 ms per character
+        let slow = Timeouts::for_line(110, 10, Duration::ZERO);
+        assert_eq!(slow.handshake, Duration::from_secs(5));
+        assert_eq!(slow.control, Duration::from_secs(2));
+        assert_eq!(slow.checksum, Duration::from_millis(2000) + Duration::from_secs(10) / 110 * 129);
+        assert_eq!(slow.data_byte, Duration::from_secs(1));
+
+        // 300 baud, 7E2: 11 bits per character and a long byte delay
+        let paced = Timeouts::for_line(300, 11, ms(500));
+        assert_eq!(paced.data_byte, (Duration::from_secs(11) / 300 + ms(500)) * 2);
+        assert_eq!(paced.checksum, Duration::from_secs(2) + Duration::from_secs(11) / 300 * 129);
+
+        // Absurdly slow lines stretch every wait
+        let crawl = Timeouts::for_line(1, 10, Duration::ZERO);
+        assert_eq!(crawl.control, Duration::from_secs(40));
+        assert_eq!(crawl.handshake, Duration::from_secs(40));
+    }
+
+    #[test]
+    fn test_checksum_covers_byte_timeout() {
+        let ms = Duration::from_millis;
+
+        assert!(Timeouts::default().checksum >= Timeouts::default().min_checksum());
+
+        // A long byte delay stretches the byte timeout past the time for
+        // a block to drain, and the verdict wait follows it
+        let paced = Timeouts::for_line(9600, 10, ms(1500));
+        assert_eq!(paced.data_byte, (Duration::from_secs(10) / 9600 + ms(1500)) * 2);
+        assert_eq!(paced.checksum, paced.data_byte + paced.control);
+    }
+}
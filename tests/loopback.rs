@@ -0,0 +1,252 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! End-to-end tests: a `Sender` and a `Receiver` on two threads, talking
+//! over an in-memory loopback line
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use filink::noise::{NoiseConfig, NoisyPort};
+use filink::serial::{loopback, SerialPort};
+use filink::{AdaptivePacing, ModeRules, Receiver, Sender, SenderBuilder, SessionReport, TransferMode, TrimMode, TrimRules};
+
+/// Random but repeatable file contents
+fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        })
+        .collect()
+}
+
+/// What a binary receiver saves: the data padded to whole blocks with ^Z
+fn padded(data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    data.resize(data.len().div_ceil(128) * 128, 0x1A);
+    data
+}
+
+fn fresh_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Send `files` from one thread to a receiver on another. Returns the
+/// output directory and both sides' reports.
+fn transfer(
+    name: &str,
+    files: &[(&str, &[u8])],
+    modes: ModeRules,
+    trim: TrimRules,
+) -> (PathBuf, SessionReport, SessionReport) {
+    transfer_over(loopback(), name, files, modes, trim, |sender| sender)
+}
+
+fn transfer_over(
+    (sender_port, receiver_port): (impl SerialPort + 'static, impl SerialPort + 'static),
+    name: &str,
+    files: &[(&str, &[u8])],
+    modes: ModeRules,
+    trim: TrimRules,
+    tune: impl FnOnce(SenderBuilder) -> SenderBuilder,
+) -> (PathBuf, SessionReport, SessionReport) {
+    let input = fresh_dir(&format!("filink_loopback_{}_in", name));
+    let output = fresh_dir(&format!("filink_loopback_{}_out", name));
+    let paths: Vec<PathBuf> = files
+        .iter()
+        .map(|(file, data)| {
+            let path = input.join(file);
+            std::fs::write(&path, data).unwrap();
+            path
+        })
+        .collect();
+
+    let receiver = {
+        let output = output.clone();
+        let modes = modes.clone();
+        std::thread::spawn(move || {
+            Receiver::builder(receiver_port).output(output).modes(modes).trim(trim).run()
+        })
+    };
+    let sent = tune(Sender::builder(sender_port).files(paths).modes(modes)).run().expect("send failed");
+    let received = receiver.join().unwrap().expect("receive failed");
+
+    std::fs::remove_dir_all(&input).ok();
+    (output, sent, received)
+}
+
+/// Sender port that garbles the `nth` STX it sends
+struct GarbleStx<P> {
+    inner: P,
+    nth: usize,
+    sent: usize,
+    state: &'static str,
+}
+
+impl<P: SerialPort> SerialPort for GarbleStx<P> {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if self.state == "CheckMoreData" && buf == [0x02] {
+            self.sent += 1;
+            if self.sent == self.nth {
+                return self.inner.write_all(b"?");
+            }
+        }
+        self.inner.write_all(buf)
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+        self.inner.read_timeout(buf, timeout)
+    }
+
+    fn enter_state(&mut self, state: &'static str) {
+        self.state = state;
+    }
+}
+
+fn read(dir: &Path, name: &str) -> Vec<u8> {
+    std::fs::read(dir.join(name)).unwrap_or_else(|e| panic!("{}: {}", name, e))
+}
+
+#[test]
+fn test_loopback_binary_sizes() {
+    let large = noise(200_000, 0x2545_F491);
+    let odd = noise(300, 7);
+    let block = noise(128, 11);
+    let two = noise(256, 13);
+    let files: [(&str, &[u8]); 6] = [
+        ("empty.bin", b""),
+        ("one.bin", b"x"),
+        ("block.bin", &block),
+        ("two.bin", &two),
+        ("odd.bin", &odd),
+        ("large.bin", &large),
+    ];
+    let trim = TrimRules { default: TrimMode::Binary, ..Default::default() };
+    let (output, sent, received) = transfer("binary", &files, ModeRules::default(), trim);
+
+    for (name, data) in files {
+        assert!(read(&output, name) == padded(data), "{} differs", name);
+    }
+
+    assert_eq!(sent.completed().count(), files.len());
+    assert_eq!(received.completed().count(), files.len());
+    for ((name, data), (sent, received)) in files.iter().zip(sent.files.iter().zip(&received.files)) {
+        let blocks = data.len().div_ceil(128) as u32;
+        assert_eq!(sent.name, name.to_uppercase());
+        assert_eq!((sent.bytes, sent.blocks, sent.retries), (data.len() as u64, blocks, 0));
+        assert_eq!((received.bytes, received.blocks), (padded(data).len() as u64, blocks));
+    }
+
+    std::fs::remove_dir_all(&output).ok();
+}
+
+#[test]
+fn test_loopback_text_mode() {
+    let long: String = (1..=200).map(|i| format!("line {} of a text file\n", i)).collect();
+    let files: [(&str, &[u8]); 3] = [
+        ("notes.txt", b"first\nsecond\n\nlast without newline"),
+        ("long.txt", long.as_bytes()),
+        ("blank.txt", b""),
+    ];
+    let modes = ModeRules { default: TransferMode::Text, ..Default::default() };
+    let (output, sent, received) = transfer("text", &files, modes, TrimRules::default());
+
+    for ((name, data), (sent, received)) in files.iter().zip(sent.files.iter().zip(&received.files)) {
+        assert_eq!(read(&output, name), *data, "{} differs", name);
+        assert_eq!((sent.bytes, received.bytes), (data.len() as u64, data.len() as u64), "{}", name);
+    }
+    assert_eq!(sent.completed().count(), files.len());
+    assert_eq!(received.completed().count(), files.len());
+
+    std::fs::remove_dir_all(&output).ok();
+}
+
+#[test]
+fn test_loopback_auto_trim() {
+    // Sent byte for byte, the padding of a text file is still trimmed by
+    // its extension
+    let files: [(&str, &[u8]); 2] = [("readme.txt", b"exact text content\r\n"), ("prog.com", &[0xC3, 0x00, 0x01])];
+    let (output, _, received) = transfer("auto", &files, ModeRules::default(), TrimRules::default());
+
+    assert_eq!(read(&output, "readme.txt"), b"exact text content\r\n");
+    assert_eq!(read(&output, "prog.com"), padded(&[0xC3, 0x00, 0x01]));
+    assert_eq!(received.files.len(), 2);
+
+    std::fs::remove_dir_all(&output).ok();
+}
+
+#[test]
+fn test_loopback_noisy_line() {
+    // Flipped bits in both directions cost retries but no data
+    let data = noise(4096, 99);
+    let files: [(&str, &[u8]); 1] = [("noisy.bin", &data)];
+    let line = NoiseConfig { bit_flip: 0.0005, seed: 3, ..Default::default() };
+    let (a, b) = loopback();
+    let ports = (NoisyPort::new(a, line), NoisyPort::new(b, NoiseConfig { seed: 4, ..line }));
+    let trim = TrimRules { default: TrimMode::Binary, ..Default::default() };
+    let (output, sent, _) = transfer_over(ports, "noisy", &files, ModeRules::default(), trim, |sender| sender);
+
+    assert!(read(&output, "noisy.bin") == data, "noisy.bin differs");
+    assert!(sent.files[0].retries > 0, "no block was retried");
+
+    std::fs::remove_dir_all(&output).ok();
+}
+
+#[test]
+fn test_loopback_adaptive_pacing() {
+    // A receiver that takes 10000 characters per second into a 16 byte
+    // buffer keeps up with the control bytes but loses most of a block
+    // sent flat out, so the sender has to slow down to get a block through
+    let data = noise(2048, 5);
+    let files: [(&str, &[u8]); 1] = [("paced.bin", &data)];
+    let slow = NoiseConfig { drain_rate: Some(10_000), buffer: 16, ..Default::default() };
+    let (a, b) = loopback();
+    let ports = (NoisyPort::new(a, slow), b);
+    let trim = TrimRules { default: TrimMode::Binary, ..Default::default() };
+    let (output, sent, _) = transfer_over(ports, "paced", &files, ModeRules::default(), trim, |sender| {
+        sender.adaptive_pacing(AdaptivePacing::default())
+    });
+
+    assert!(read(&output, "paced.bin") == data, "paced.bin differs");
+    assert!(sent.files[0].retries > 0, "no block was retried");
+    let pacing = sent.pacing.expect("no pacing reported");
+    assert!(!pacing.byte_delay.is_zero(), "settled on no delay");
+
+    std::fs::remove_dir_all(&output).ok();
+}
+
+#[test]
+fn test_loopback_garbled_stx_before_repeated_block() {
+    // The receiver answers the garbled STX with 'N'. The block that follows
+    // has the same contents as the one before it, and must still be saved.
+    let block = noise(128, 21);
+    let data = [block.clone(), block, noise(100, 23)].concat();
+    let files: [(&str, &[u8]); 1] = [("repeat.bin", &data)];
+    let (a, b) = loopback();
+    let ports = (GarbleStx { inner: a, nth: 2, sent: 0, state: "" }, b);
+    let trim = TrimRules { default: TrimMode::Binary, ..Default::default() };
+    let (output, sent, received) = transfer_over(ports, "repeat", &files, ModeRules::default(), trim, |sender| sender);
+
+    assert!(read(&output, "repeat.bin") == padded(&data), "repeat.bin differs");
+    assert_eq!((sent.files[0].blocks, received.files[0].blocks), (3, 3));
+
+    std::fs::remove_dir_all(&output).ok();
+}
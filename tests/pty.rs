@@ -0,0 +1,246 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! End-to-end tests of the `filink` binary over pseudo-terminals, through
+//! the real serial port code, argument parsing and exit codes
+
+#![cfg(unix)]
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// ============================================================================
+// Pseudo-terminals
+// ============================================================================
+
+/// A pty pair. filink opens the slave by name; the test talks to the master.
+struct Pty {
+    master: File,
+    /// Held open so the master doesn't see a hangup before filink opens it
+    _slave: File,
+    path: String,
+}
+
+fn pty() -> Pty {
+    let mut master = 0;
+    let mut slave = 0;
+    // SAFETY: openpty fills in the two descriptors; termios is plain data
+    // and initialized by cfmakeraw, so the slave starts without echo
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        libc::cfmakeraw(&mut termios);
+        let result = libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), &termios, std::ptr::null());
+        assert_eq!(result, 0, "openpty failed: {}", std::io::Error::last_os_error());
+        let path = CStr::from_ptr(libc::ttyname(slave)).to_string_lossy().into_owned();
+        Pty { master: File::from_raw_fd(master), _slave: File::from_raw_fd(slave), path }
+    }
+}
+
+/// Wait up to `timeout` for the master to have data
+fn readable(file: &File, timeout: Duration) -> bool {
+    let mut poll = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    // SAFETY: one valid pollfd
+    unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) > 0 }
+}
+
+/// Copy everything between two masters, like a null-modem cable, until
+/// `stop` is set
+fn cable(a: &Pty, b: &Pty, stop: Arc<AtomicBool>) -> std::thread::JoinHandle<()> {
+    let ends = [a.master.try_clone().unwrap(), b.master.try_clone().unwrap()];
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        while !stop.load(Ordering::SeqCst) {
+            for (from, to) in [(0, 1), (1, 0)] {
+                if readable(&ends[from], Duration::from_millis(10))
+                    && let Ok(n) = (&ends[from]).read(&mut buf)
+                {
+                    (&ends[to]).write_all(&buf[..n]).unwrap();
+                }
+            }
+        }
+    })
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+fn filink(port: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_filink"));
+    command.arg("--port").arg(port).stdin(Stdio::null());
+    command
+}
+
+fn fresh_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_file(dir: &Path, name: &str, data: &[u8]) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[test]
+fn test_send_and_receive() {
+    let input = fresh_dir("filink_pty_send_in");
+    let output = fresh_dir("filink_pty_send_out");
+    let text: String = (1..=40).map(|i| format!("line {}\n", i)).collect();
+    let binary: Vec<u8> = (0..=255).collect();
+    let notes = write_file(&input, "notes.txt", text.as_bytes());
+    let data = write_file(&input, "data.bin", &binary);
+
+    let (a, b) = (pty(), pty());
+    let stop = Arc::new(AtomicBool::new(false));
+    let cable = cable(&a, &b, stop.clone());
+
+    let mut receiver = filink(&b.path);
+    receiver.args(["--connect-timeout", "20", "receive", "-o"]).arg(&output);
+    let receiver = std::thread::spawn(move || receiver.output());
+    // Give the receiver a moment to open its end before the sender starts
+    std::thread::sleep(Duration::from_millis(200));
+    let sent = filink(&a.path).args(["--connect-timeout", "20", "send"]).arg(&notes).arg(&data).output().unwrap();
+    let received = receiver.join().unwrap().unwrap();
+    stop.store(true, Ordering::SeqCst);
+    cable.join().unwrap();
+
+    assert_eq!(sent.status.code(), Some(0), "send: {}{}", stdout(&sent), stderr(&sent));
+    assert_eq!(received.status.code(), Some(0), "receive: {}{}", stdout(&received), stderr(&received));
+    assert!(stdout(&sent).contains("Files sent successfully!"));
+    assert_eq!(std::fs::read(output.join("notes.txt")).unwrap(), text.as_bytes());
+    assert_eq!(std::fs::read(output.join("data.bin")).unwrap(), binary);
+
+    std::fs::remove_dir_all(&input).ok();
+    std::fs::remove_dir_all(&output).ok();
+}
+
+#[test]
+fn test_rejected_file_exit_code() {
+    let input = fresh_dir("filink_pty_reject");
+    let file = write_file(&input, "refused.txt", b"no thanks");
+
+    // The test plays a receiver that refuses every file
+    let port = pty();
+    let mut peer = port.master.try_clone().unwrap();
+    let script = std::thread::spawn(move || {
+        let mut byte = [0u8; 1];
+        let mut name_left = 0;
+        while readable(&peer, Duration::from_secs(10)) && peer.read_exact(&mut byte).is_ok() {
+            let reply = match byte[0] {
+                // The 11 characters of the name are echoed
+                name if name_left > 0 => {
+                    name_left -= 1;
+                    Some(name)
+                }
+                b'R' => Some(b'S'),
+                0x04 => {
+                    name_left = 11;
+                    Some(0x08)
+                }
+                0x05 => Some(b'X'),
+                0x13 => return true,
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                peer.write_all(&[reply]).unwrap();
+            }
+        }
+        false
+    });
+
+    let sent = filink(&port.path)
+        .args(["--connect-timeout", "20", "send", "--reject-attempts", "1"])
+        .arg(&file)
+        .output()
+        .unwrap();
+    assert!(script.join().unwrap(), "sender never ended the session");
+    assert_eq!(sent.status.code(), Some(5), "{}{}", stdout(&sent), stderr(&sent));
+    assert!(stdout(&sent).contains("Rejected by the receiver"));
+    assert!(stdout(&sent).contains("refused.txt"));
+
+    std::fs::remove_dir_all(&input).ok();
+}
+
+#[test]
+fn test_handshake_timeout() {
+    let input = fresh_dir("filink_pty_timeout");
+    let file = write_file(&input, "lonely.txt", b"anyone there?");
+
+    // Nobody answers, so the only handshake attempt times out after the
+    // sender's five second wait
+    let port = pty();
+    let started = Instant::now();
+    let sent = filink(&port.path).args(["--handshake-attempts", "1", "send"]).arg(&file).output().unwrap();
+    let elapsed = started.elapsed();
+
+    assert_eq!(sent.status.code(), Some(3), "{}{}", stdout(&sent), stderr(&sent));
+    assert!(stderr(&sent).contains("Send failed"));
+    assert!(elapsed >= Duration::from_millis(4500), "gave up after {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(30), "gave up after {:?}", elapsed);
+
+    let mut sent_bytes = [0u8; 1];
+    assert!(readable(&port.master, Duration::ZERO));
+    (&port.master).read_exact(&mut sent_bytes).unwrap();
+    assert_eq!(&sent_bytes, b"R");
+
+    std::fs::remove_dir_all(&input).ok();
+}
+
+#[test]
+fn test_usage_errors() {
+    let port = pty();
+
+    let bad_parity = filink(&port.path).args(["--parity", "sometimes", "receive"]).output().unwrap();
+    assert_eq!(bad_parity.status.code(), Some(1));
+    assert!(stderr(&bad_parity).contains("Invalid parity"));
+
+    let bad_timeout = filink(&port.path).args(["--timeouts", "control=soon", "receive"]).output().unwrap();
+    assert_eq!(bad_timeout.status.code(), Some(1));
+    assert!(stderr(&bad_timeout).contains("Invalid timeout"));
+
+    let short_checksum = filink(&port.path).args(["--timeouts", "byte=4,checksum=5", "receive"]).output().unwrap();
+    assert_eq!(short_checksum.status.code(), Some(1));
+    assert!(stderr(&short_checksum).contains("shorter than byte plus control"));
+
+    let missing_file = filink(&port.path).args(["send", "/nonexistent/file.txt"]).output().unwrap();
+    assert_eq!(missing_file.status.code(), Some(1));
+
+    let missing_port = filink("/dev/filink-no-such-port").arg("receive").output().unwrap();
+    assert_eq!(missing_port.status.code(), Some(1));
+    assert!(stderr(&missing_port).contains("Failed to open serial port"));
+}
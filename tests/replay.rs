@@ -0,0 +1,99 @@
+// Copyright (C) 2026 Brian Johnson
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Compatibility regression suite: replays recorded sessions
+//!
+//! Each directory under `tests/transcripts` is one recorded session, see
+//! `tests/transcripts/README.md`. `send.trace` is replayed through a
+//! `Sender` sending the files in `sent/`; `receive.trace` through a
+//! `Receiver` whose output must match `received/`. Our side must write
+//! exactly what it wrote in the recording, and play all of it.
+
+use std::path::{Path, PathBuf};
+use filink::capture::ReplayPort;
+use filink::{Receiver, Sender};
+
+fn transcripts() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/transcripts");
+    let mut sessions: Vec<PathBuf> = std::fs::read_dir(root)
+        .expect("transcripts directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    sessions.sort();
+    sessions
+}
+
+fn sorted_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn replay_send_transcripts() {
+    for session in transcripts() {
+        let trace = session.join("send.trace");
+        if !trace.exists() {
+            continue;
+        }
+        let port = ReplayPort::open(&trace).unwrap();
+        let report = Sender::builder(port.clone())
+            .files(sorted_files(&session.join("sent")))
+            .run()
+            .unwrap_or_else(|e| panic!("{}: {}", trace.display(), e));
+        assert_eq!(report.rejected().count(), 0, "{}", trace.display());
+        port.finish().unwrap_or_else(|e| panic!("{}: {}", trace.display(), e));
+    }
+}
+
+#[test]
+fn replay_receive_transcripts() {
+    for session in transcripts() {
+        let trace = session.join("receive.trace");
+        if !trace.exists() {
+            continue;
+        }
+        let name = session.file_name().unwrap().to_string_lossy();
+        let output = std::env::temp_dir().join(format!("filink_replay_{}", name));
+        std::fs::remove_dir_all(&output).ok();
+        std::fs::create_dir_all(&output).unwrap();
+
+        let port = ReplayPort::open(&trace).unwrap();
+        Receiver::builder(port.clone())
+            .output(&output)
+            .run()
+            .unwrap_or_else(|e| panic!("{}: {}", trace.display(), e));
+        port.finish().unwrap_or_else(|e| panic!("{}: {}", trace.display(), e));
+
+        let expected = sorted_files(&session.join("received"));
+        let got = sorted_files(&output);
+        assert_eq!(
+            got.iter().map(|p| p.file_name()).collect::<Vec<_>>(),
+            expected.iter().map(|p| p.file_name()).collect::<Vec<_>>(),
+            "{}: files received",
+            trace.display()
+        );
+        for (got, expected) in got.iter().zip(&expected) {
+            assert!(std::fs::read(got).unwrap() == std::fs::read(expected).unwrap(), "{}: {} differs", trace.display(), got.display());
+        }
+
+        std::fs::remove_dir_all(&output).ok();
+    }
+}